@@ -0,0 +1,529 @@
+use std::{env, fs::File, io::Read, process::exit, sync::Arc};
+
+use rultdb::{
+    comparator,
+    config::PAGE_SIZE,
+    db::{DBInner, MetaFallback, Options, Recovery, DB},
+    dump::DumpFormat,
+    error::{Error, Result},
+    index::Index,
+    inspect::{hexdump, PageContents, PageInfo, PageKind, PageStatus, RawFile},
+    page::{Meta, PgId},
+    salvage, surgery,
+};
+
+const USAGE: &str = "usage: rultdb <command> [arguments]
+
+commands:
+    info PATH                 print meta fields, page size and file size
+    stats PATH                print page and key statistics
+    check PATH                check page consistency
+    keys PATH                 list all keys
+    get PATH KEY              print the value of KEY
+    put PATH KEY VALUE        set KEY to VALUE
+    delete PATH KEY           delete KEY
+    dump PATH [START] [END]   print key/value pairs in [START, END)
+    compact SRC DST           copy all pairs of SRC into a new database DST
+    backup SRC DST            write a consistent snapshot of SRC to DST
+    export PATH FILE [--text] write all plain pairs to FILE in the binary (or JSON lines) dump format;
+                              TTLs, duplicate keys, tables, indexes and the sequence are not exported
+    import PATH FILE          load a dump FILE into the new or empty database PATH
+    pages PATH                list every page with its type and status
+    page PATH PGID [--hex]    decode a single page, or hexdump it
+    salvage SRC DST           recover pairs from leaf pages of an unopenable SRC into DST
+    index verify|rebuild PATH NAME PREFIX EXTRACTOR [--unique]
+                              check or rebuild the index NAME on keys starting with PREFIX,
+                              EXTRACTOR is one of value, field:SEP:N and split:SEP
+
+surgery commands, each copies SRC to a new file DST and only modifies DST:
+    surgery revert-meta SRC DST              replace the active meta page with the other one
+    surgery copy-page SRC DST PGID BACKUP    overwrite page PGID with the same page of BACKUP
+    surgery clear-page SRC DST PGID          clear a leaf page and unlink it from its parent
+    surgery rebuild-freelist SRC DST         free every page not reachable from the meta
+
+keys and values are taken as raw bytes, \\xNN and \\\\ escapes are supported
+and are used when printing non-printable bytes.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("rultdb: {}", e);
+        exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    match args.as_slice() {
+        ["info", path] => info(path),
+        ["stats", path] => stats(path),
+        ["check", path] => check(path),
+        ["keys", path] => keys(path),
+        ["get", path, key] => get(path, key),
+        ["put", path, key, value] => put(path, key, value),
+        ["delete", path, key] => delete(path, key),
+        ["dump", path] => dump(path, None, None),
+        ["dump", path, start] => dump(path, Some(start), None),
+        ["dump", path, start, end] => dump(path, Some(start), Some(end)),
+        ["compact", src, dst] => compact(src, dst),
+        ["backup", src, dst] => backup(src, dst),
+        ["export", path, file] => export(path, file, DumpFormat::Binary),
+        ["export", path, file, "--text"] => export(path, file, DumpFormat::Text),
+        ["import", path, file] => import(path, file),
+        ["pages", path] => pages(path),
+        ["page", path, pgid] => page(path, pgid, false),
+        ["page", path, pgid, "--hex"] => page(path, pgid, true),
+        ["salvage", src, dst] => salvage(src, dst),
+        ["index", cmd @ ("verify" | "rebuild"), path, name, prefix, extractor] => index(cmd, path, name, prefix, extractor, false),
+        ["index", cmd @ ("verify" | "rebuild"), path, name, prefix, extractor, "--unique"] => {
+            index(cmd, path, name, prefix, extractor, true)
+        }
+        ["surgery", "revert-meta", src, dst] => print_changes(surgery::revert_meta(src, dst)),
+        ["surgery", "copy-page", src, dst, pgid, backup] => {
+            print_changes(surgery::copy_page(src, dst, parse_pgid(pgid)?, backup))
+        }
+        ["surgery", "clear-page", src, dst, pgid] => {
+            print_changes(surgery::clear_page(src, dst, parse_pgid(pgid)?))?;
+            println!("run `rultdb surgery rebuild-freelist` to reclaim the unlinked pages");
+            Ok(())
+        }
+        ["surgery", "rebuild-freelist", src, dst] => print_changes(surgery::rebuild_freelist(src, dst)),
+        ["help"] | ["-h"] | ["--help"] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    }
+}
+
+fn open(path: &str, read_only: bool) -> Result<DB> {
+    open_with(path, read_only, file_meta(path), Vec::new())
+}
+
+// 使用内置比较器创建的数据库需要用同样的比较器打开
+fn file_meta(path: &str) -> Option<Meta> {
+    let mut buf = vec![0; 2 * PAGE_SIZE];
+    File::open(path).ok()?.read_exact(&mut buf).ok()?;
+    Some(RawFile::from_buf(buf).ok()?.meta().clone())
+}
+
+// meta 为 None 时使用默认选项；新建的数据库使用 meta 中的比较器和计数模式
+fn open_with(path: &str, read_only: bool, meta: Option<Meta>, indexes: Vec<Index>) -> Result<DB> {
+    if read_only && !std::path::Path::new(path).exists() {
+        return Err(format!("{}: no such file", path).into());
+    }
+    let on_recovery = Arc::new(|f: &MetaFallback| {
+        eprintln!(
+            "rultdb: warning: meta page {} (txid {}) rejected: {}, using txid {}",
+            f.meta_page, f.txid, f.reason, f.fallback_txid
+        )
+    });
+    let comparator = meta.as_ref().and_then(|m| comparator::builtin(&m.comparator_name()));
+    let counted = meta.is_some_and(|m| m.counted());
+    DBInner::open(
+        path,
+        Options { read_only, recovery: Recovery::Verify, on_recovery: Some(on_recovery), comparator, counted, indexes, ..Default::default() },
+    )
+}
+
+fn info(path: &str) -> Result<()> {
+    let db = open(path, true)?;
+    let tx = db.begin_tx();
+    let meta = tx.meta();
+    tx.close()?;
+    let file_size = std::fs::metadata(path).map_err(|e| ("can't stat file", e))?.len();
+    println!("page size: {}", PAGE_SIZE);
+    println!("file size: {}", file_size);
+    println!("magic:     {:#x}", meta.magic);
+    println!("version:   {}", meta.version);
+    println!("flags:     {:#x}", meta.flags);
+    println!("root:      {}", meta.root);
+    println!("freelist:  {}", meta.freelist);
+    println!("pgid:      {}", meta.pgid);
+    println!("txid:      {}", meta.txid);
+    println!("sequence:  {}", meta.sequence);
+    println!("comparator: {}", meta.comparator_name());
+    println!("counted:   {}", meta.counted());
+    println!("checksum:  {:#010x}", meta.checksum);
+    Ok(())
+}
+
+fn stats(path: &str) -> Result<()> {
+    let db = open(path, true)?;
+    let tx = db.begin_tx();
+    let s = tx.stats();
+    let meta = tx.meta();
+    tx.close()?;
+    let freelist = db.0.freelist.read();
+    println!("pages:               {}", meta.pgid);
+    println!("free pages:          {}", freelist.free_count());
+    println!("pending pages:       {}", freelist.pending_count());
+    println!("keys:                {}", s.key_n);
+    println!("depth:               {}", s.depth);
+    println!("branch pages:        {} (overflow {})", s.branch_page_n, s.branch_overflow_n);
+    println!("branch bytes:        {} / {} ({})", s.branch_inuse, s.branch_alloc, percent(s.branch_inuse, s.branch_alloc));
+    println!("leaf pages:          {} (overflow {})", s.leaf_page_n, s.leaf_overflow_n);
+    println!("leaf bytes:          {} / {} ({})", s.leaf_inuse, s.leaf_alloc, percent(s.leaf_inuse, s.leaf_alloc));
+    Ok(())
+}
+
+fn percent(a: usize, b: usize) -> String {
+    if b == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", a as f64 * 100.0 / b as f64)
+}
+
+fn check(path: &str) -> Result<()> {
+    let db = open(path, true)?;
+    let tx = db.begin_tx();
+    let errors = tx.check();
+    tx.close()?;
+    if errors.is_empty() {
+        println!("OK");
+        return Ok(());
+    }
+    for e in errors.iter() {
+        println!("{}", e);
+    }
+    Err(format!("{} errors found", errors.len()).into())
+}
+
+fn keys(path: &str) -> Result<()> {
+    let db = open(path, true)?;
+    let tx = db.begin_tx();
+    let mut c = tx.cursor();
+    let mut item = c.first()?;
+    while let Some(k) = item.key() {
+        println!("{}", escape(k));
+        item = c.next()?;
+    }
+    tx.close()
+}
+
+fn get(path: &str, key: &str) -> Result<()> {
+    let db = open(path, true)?;
+    let mut tx = db.begin_tx();
+    let value = tx.get(&unescape(key)?).map(|v| v.map(escape));
+    tx.close()?;
+    match value? {
+        Some(v) => {
+            println!("{}", v);
+            Ok(())
+        }
+        None => Err(format!("key not found: {}", key).into()),
+    }
+}
+
+fn put(path: &str, key: &str, value: &str) -> Result<()> {
+    let db = open(path, false)?;
+    let mut tx = db.begin_rwtx()?;
+    tx.put(&unescape(key)?, &unescape(value)?)?;
+    tx.commit()
+}
+
+fn delete(path: &str, key: &str) -> Result<()> {
+    let db = open(path, false)?;
+    let mut tx = db.begin_rwtx()?;
+    tx.delete(&unescape(key)?)?;
+    tx.commit()
+}
+
+fn dump(path: &str, start: Option<&str>, end: Option<&str>) -> Result<()> {
+    let start = start.map(unescape).transpose()?;
+    let end = end.map(unescape).transpose()?;
+    let db = open(path, true)?;
+    let tx = db.begin_tx();
+    let mut c = tx.cursor();
+    let mut item = match &start {
+        Some(s) => c.seek(s)?,
+        None => c.first()?,
+    };
+    while let (Some(k), Some(v)) = (item.key(), item.value()) {
+        if end.as_ref().is_some_and(|e| tx.compare_keys(k, e).is_ge()) {
+            break;
+        }
+        println!("{} => {}", escape(k), escape(v));
+        item = c.next()?;
+    }
+    tx.close()
+}
+
+fn compact(src: &str, dst: &str) -> Result<()> {
+    if std::path::Path::new(dst).exists() {
+        return Err(format!("{}: already exists", dst).into());
+    }
+    let src_db = open(src, true)?;
+    let dst_db = open_with(dst, false, file_meta(src), Vec::new())?;
+
+    src_db.compact_into(&dst_db)?;
+    let before = std::fs::metadata(src).map_err(|e| ("can't stat file", e))?.len();
+    let after = std::fs::metadata(dst).map_err(|e| ("can't stat file", e))?.len();
+    println!("{} -> {} bytes", before, after);
+    Ok(())
+}
+
+fn export(path: &str, file: &str, format: DumpFormat) -> Result<()> {
+    if std::path::Path::new(file).exists() {
+        return Err(format!("{}: already exists", file).into());
+    }
+    let db = open(path, true)?;
+    let tx = db.begin_tx();
+    let f = File::create(file).map_err(|e| ("can't create dump file", e))?;
+    let n = tx.export_as(&mut std::io::BufWriter::new(&f), format);
+    tx.close()?;
+    f.sync_all().map_err(|e| ("can't sync dump file", e))?;
+    println!("exported {} keys", n?);
+    Ok(())
+}
+
+fn import(path: &str, file: &str) -> Result<()> {
+    let f = File::open(file).map_err(|e| ("can't open dump file", e))?;
+    let db = open(path, false)?;
+    let n = db.import(&mut std::io::BufReader::new(f))?;
+    println!("imported {} keys", n);
+    Ok(())
+}
+
+fn salvage(src: &str, dst: &str) -> Result<()> {
+    let report = salvage::salvage(src, dst)?;
+    for (pgid, reason) in report.skipped.iter() {
+        println!("page {}: skipped: {}", pgid, reason);
+    }
+    println!("scanned {} pages, decoded {} leaf pages", report.pages, report.leaf_pages);
+    println!("recovered {} keys into {}", report.keys, dst);
+    if report.unreachable_keys > 0 {
+        println!("{} keys were only found in unreachable pages and may have been deleted", report.unreachable_keys);
+    }
+    if report.conflicts > 0 {
+        println!("{} keys had conflicting values of unknown age", report.conflicts);
+    }
+    Ok(())
+}
+
+fn index(cmd: &str, path: &str, name: &str, prefix: &str, extractor: &str, unique: bool) -> Result<()> {
+    let index = Index::builtin(name, &unescape(prefix)?, extractor).ok_or_else(|| format!("invalid extractor: {}", extractor))?;
+    let index = if unique { index.unique() } else { index };
+    if cmd == "rebuild" {
+        let db = open_with(path, false, file_meta(path), vec![index])?;
+        let mut tx = db.begin_rwtx()?;
+        let n = tx.rebuild_index(name)?;
+        tx.commit()?;
+        println!("rebuilt {} entries", n);
+        return Ok(());
+    }
+    let db = open_with(path, true, file_meta(path), vec![index])?;
+    let tx = db.begin_tx();
+    let problems = tx.verify_index(name);
+    tx.close()?;
+    let problems = problems?;
+    if problems.is_empty() {
+        println!("OK");
+        return Ok(());
+    }
+    for p in problems.iter() {
+        println!("{}", p);
+    }
+    Err(format!("{} problems found", problems.len()).into())
+}
+
+fn backup(src: &str, dst: &str) -> Result<()> {
+    if std::path::Path::new(dst).exists() {
+        return Err(format!("{}: already exists", dst).into());
+    }
+    let db = open(src, true)?;
+    let tx = db.begin_tx();
+    let mut f = File::create(dst).map_err(|e| ("can't create backup file", e))?;
+    let n = tx.write_to(&mut f);
+    tx.close()?;
+    f.sync_all().map_err(|e| ("can't sync backup file", e))?;
+    println!("{} bytes written", n?);
+    Ok(())
+}
+
+// 页面检查优先通过打开的数据库进行，数据库无法打开时直接读取文件
+enum PageSource {
+    DB(DB),
+    Raw(RawFile),
+}
+
+impl PageSource {
+    fn open(path: &str) -> Result<PageSource> {
+        match open(path, true) {
+            Ok(db) => Ok(PageSource::DB(db)),
+            Err(e) => {
+                eprintln!("rultdb: can't open database ({}), reading raw file", e);
+                Ok(PageSource::Raw(RawFile::open(path)?))
+            }
+        }
+    }
+
+    fn pages(&self) -> Vec<PageInfo> {
+        match self {
+            PageSource::DB(db) => {
+                let tx = db.begin_tx();
+                let pages = tx.pages();
+                let _ = tx.close();
+                pages
+            }
+            PageSource::Raw(raw) => raw.pages(),
+        }
+    }
+
+    fn page(&self, pgid: PgId) -> Result<(PageInfo, PageContents)> {
+        match self {
+            PageSource::DB(db) => {
+                let tx = db.begin_tx();
+                let page = tx.page_info(pgid).and_then(|info| Ok((info, tx.page_contents(pgid)?)));
+                tx.close()?;
+                page
+            }
+            PageSource::Raw(raw) => Ok((raw.page_info(pgid)?, raw.page_contents(pgid)?)),
+        }
+    }
+}
+
+fn kind_name(kind: PageKind) -> String {
+    match kind {
+        PageKind::Meta => "meta".to_string(),
+        PageKind::FreeList => "freelist".to_string(),
+        PageKind::Branch => "branch".to_string(),
+        PageKind::Leaf => "leaf".to_string(),
+        PageKind::Overflow(id) => format!("overflow({})", id),
+        PageKind::Unknown(flags) => format!("unknown({:#x})", flags),
+    }
+}
+
+fn status_name(status: PageStatus) -> &'static str {
+    match status {
+        PageStatus::InUse => "in-use",
+        PageStatus::Free => "free",
+        PageStatus::Pending => "pending",
+        PageStatus::Unreachable => "unreachable",
+    }
+}
+
+fn pages(path: &str) -> Result<()> {
+    let source = PageSource::open(path)?;
+    println!("{:>10}  {:<16}  {:>6}  {:>8}  {:>10}  STATUS", "ID", "TYPE", "COUNT", "OVERFLOW", "PARENT");
+    for p in source.pages() {
+        let (kind, count, overflow) = match p.status {
+            PageStatus::InUse => (kind_name(p.kind), p.count.to_string(), p.overflow.to_string()),
+            _ => (String::new(), String::new(), String::new()),
+        };
+        let parent = p.parent.map(|id| id.to_string()).unwrap_or_default();
+        println!(
+            "{:>10}  {:<16}  {:>6}  {:>8}  {:>10}  {}",
+            p.id, kind, count, overflow, parent, status_name(p.status)
+        );
+    }
+    Ok(())
+}
+
+fn parse_pgid(pgid: &str) -> Result<PgId> {
+    pgid.parse().map_err(|_| format!("invalid page id: {}", pgid).into())
+}
+
+fn print_changes(changes: Result<Vec<String>>) -> Result<()> {
+    for c in changes? {
+        println!("{}", c);
+    }
+    Ok(())
+}
+
+fn page(path: &str, pgid: &str, hex: bool) -> Result<()> {
+    let pgid = parse_pgid(pgid)?;
+    let source = PageSource::open(path)?;
+    let (info, contents) = source.page(pgid)?;
+    println!("page:     {}", info.id);
+    println!("type:     {}", kind_name(info.kind));
+    println!("status:   {}", status_name(info.status));
+    println!("count:    {}", info.count);
+    println!("overflow: {}", info.overflow);
+    if let Some(parent) = info.parent {
+        println!("parent:   {}", parent);
+    }
+    println!();
+    match contents {
+        PageContents::Raw(data) => print!("{}", hexdump(&data)),
+        _ if hex => {
+            let data = std::fs::read(path).map_err(|e| ("can't read file", e))?;
+            let offset = pgid as usize * PAGE_SIZE;
+            let end = (offset + (info.overflow as usize + 1) * PAGE_SIZE).min(data.len());
+            print!("{}", hexdump(&data[offset..end]));
+        }
+        PageContents::Meta(m) => {
+            println!("magic:    {:#x}", m.magic);
+            println!("version:  {}", m.version);
+            println!("flags:    {:#x}", m.flags);
+            println!("root:     {}", m.root);
+            println!("freelist: {}", m.freelist);
+            println!("pgid:     {}", m.pgid);
+            println!("txid:     {}", m.txid);
+            println!("sequence: {}", m.sequence);
+            println!("comparator: {}", m.comparator_name());
+            println!("checksum: {:#010x}", m.checksum);
+        }
+        PageContents::FreeList(ids) => {
+            for id in ids {
+                println!("{}", id);
+            }
+        }
+        PageContents::Branch(elems) => {
+            for (i, (k, child)) in elems.iter().enumerate() {
+                println!("{:>5}  {} -> page {}", i, escape(k), child);
+            }
+        }
+        PageContents::Leaf(elems) => {
+            for (i, (k, v)) in elems.iter().enumerate() {
+                println!("{:>5}  {} => {}", i, escape(k), escape(v));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn escape(b: &[u8]) -> String {
+    let mut s = String::with_capacity(b.len());
+    for &c in b {
+        match c {
+            b'\\' => s.push_str("\\\\"),
+            0x20..=0x7e => s.push(c as char),
+            _ => s.push_str(&format!("\\x{:02x}", c)),
+        }
+    }
+    s
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>> {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] != b'\\' {
+            out.push(b[i]);
+            i += 1;
+            continue;
+        }
+        match b.get(i + 1) {
+            Some(b'\\') => {
+                out.push(b'\\');
+                i += 2;
+            }
+            Some(b'x') if i + 4 <= b.len() => {
+                let hex = std::str::from_utf8(&b[i + 2..i + 4]).map_err(|_| invalid_escape(s))?;
+                out.push(u8::from_str_radix(hex, 16).map_err(|_| invalid_escape(s))?);
+                i += 4;
+            }
+            _ => return Err(invalid_escape(s)),
+        }
+    }
+    Ok(out)
+}
+
+fn invalid_escape(s: &str) -> Error {
+    format!("invalid escape sequence in {:?}", s).into()
+}
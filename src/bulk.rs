@@ -0,0 +1,271 @@
+use crate::{
+    config::PAGE_SIZE,
+    error::{Error, Result},
+    node::{INode, NodeInner},
+    page::{PageFlag, PgId, BRANCH_COUNT_SIZE, BRANCH_ELEMENT_SIZE, LEAF_ELEMENT_SIZE, MIN_KEY_PERPAGE, PAGE_HEADER_SIZE},
+    db::DB,
+    is_system_key,
+    tx::Tx,
+    MAX_FILL_PERCENT, MAX_KEY_SIZE, MAX_VALUE_SIZE, MIN_FILL_PERCENT,
+};
+
+/// 把按 key 升序排列的 key/value 直接写成叶子页面，再自底向上构建分支页面，
+/// 不经过 Cursor 和 Node 的 put/split。只能用于空的 B+ 树，新的根节点在 finish 时
+/// 写入事务的 meta，随事务一起提交
+pub struct BulkLoader<'a> {
+    tx: &'a mut Tx,
+    fill_percent: f64,
+    // 正在填充的叶子页面
+    inodes: Vec<INode>,
+    size: usize,
+    // 已经写入的叶子页面的第一个 key、页面 id 和 key 数量
+    leaves: Vec<(Vec<u8>, PgId, u64)>,
+    count: u64,
+    // 是否允许内部使用的系统 key，只用于复制整个数据库
+    raw: bool,
+}
+
+impl<'a> BulkLoader<'a> {
+    pub fn new(tx: &'a mut Tx) -> Result<Self> {
+        if !tx.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        // 事务中已经修改过的节点会在提交时覆盖新的根节点
+        let root = unsafe { &*tx.db().unwrap().0.page(tx.root_id()) };
+        if !tx.0.nodes.borrow().is_empty() || root.flags != PageFlag::LeafPage || root.count != 0 {
+            return Err("bulk load: tree is not empty".into());
+        }
+        let fill_percent = tx.fill_percent();
+        Ok(BulkLoader { tx, fill_percent, inodes: Vec::new(), size: PAGE_HEADER_SIZE, leaves: Vec::new(), count: 0, raw: false })
+    }
+
+    // 可以写入系统 key 的 BulkLoader，用于 compact 和 salvage
+    pub(crate) fn new_raw(tx: &'a mut Tx) -> Result<Self> {
+        Ok(BulkLoader { raw: true, ..Self::new(tx)? })
+    }
+
+    /// 页面的填充比例，默认使用事务的 fill_percent
+    pub fn fill_percent(mut self, fill_percent: f64) -> Self {
+        self.fill_percent = fill_percent.clamp(MIN_FILL_PERCENT, MAX_FILL_PERCENT);
+        self
+    }
+
+    /// 追加一个 key/value，key 必须严格大于之前追加的 key。内部使用的系统 key 返回 ErrReservedKey
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            return Err(Error::ErrKeyRequired);
+        } else if key.len() > MAX_KEY_SIZE {
+            return Err(Error::ErrKeyTooLarge);
+        } else if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::ErrValueTooLarge);
+        } else if !self.raw && is_system_key(key) {
+            return Err(Error::ErrReservedKey);
+        }
+        let last = self.inodes.last().map(|i| &i.key).or(self.leaves.last().map(|l| &l.0));
+        if last.is_some_and(|l| self.tx.0.order.cmp(l, key).is_ge()) {
+            return Err(Error::ErrUnsortedKeys);
+        }
+
+        let elsize = LEAF_ELEMENT_SIZE + key.len() + value.len();
+        if self.inodes.len() >= MIN_KEY_PERPAGE && self.size + elsize > self.threshold() {
+            self.flush_leaf()?;
+        }
+        self.inodes.push(INode { pgid: 0, key: key.to_vec(), value: value.to_vec(), count: 0 });
+        self.size += elsize;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn load<K: AsRef<[u8]>, V: AsRef<[u8]>>(mut self, iter: impl IntoIterator<Item = (K, V)>) -> Result<u64> {
+        for (k, v) in iter {
+            self.add(k.as_ref(), v.as_ref())?;
+        }
+        self.finish()
+    }
+
+    /// 写入剩余的叶子页面和所有分支页面，并把新的根节点写入事务的 meta，返回写入的 key 数量。
+    /// 写入的 key 不经过 update_indexes，最后重新生成 Options::indexes 声明的所有索引
+    pub fn finish(mut self) -> Result<u64> {
+        if self.count == 0 {
+            return Ok(0);
+        }
+        self.flush_leaf()?;
+        let mut level = std::mem::take(&mut self.leaves);
+        while level.len() > 1 {
+            level = self.write_branches(level)?;
+        }
+
+        let db = self.tx.db().unwrap();
+        let old_root = self.tx.root_id();
+        db.0.freelist.write().free(self.tx.id(), unsafe { &*db.0.page(old_root) });
+        self.tx.0.meta.borrow_mut().root = level[0].1;
+        self.tx.0.last_leaf.borrow_mut().clear();
+        let names: Vec<String> = db.0.indexes.iter().map(|i| i.name().to_string()).collect();
+        for name in names {
+            self.tx.rebuild_index(&name)?;
+        }
+        Ok(self.count)
+    }
+
+    fn threshold(&self) -> usize {
+        (PAGE_SIZE as f64 * self.fill_percent) as usize
+    }
+
+    fn flush_leaf(&mut self) -> Result<()> {
+        let inodes = std::mem::take(&mut self.inodes);
+        let key = inodes[0].key.clone();
+        let (pgid, count) = self.write_node(inodes, true)?;
+        self.leaves.push((key, pgid, count));
+        self.size = PAGE_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn write_branches(&mut self, children: Vec<(Vec<u8>, PgId, u64)>) -> Result<Vec<(Vec<u8>, PgId, u64)>> {
+        let mut parents = Vec::new();
+        let mut inodes: Vec<INode> = Vec::new();
+        let mut size = PAGE_HEADER_SIZE;
+        let count_size = if self.tx.counted() { BRANCH_COUNT_SIZE } else { 0 };
+        for (key, pgid, count) in children {
+            let elsize = BRANCH_ELEMENT_SIZE + count_size + key.len();
+            if inodes.len() >= MIN_KEY_PERPAGE && size + elsize > self.threshold() {
+                let first = inodes[0].key.clone();
+                let (pgid, count) = self.write_node(std::mem::take(&mut inodes), false)?;
+                parents.push((first, pgid, count));
+                size = PAGE_HEADER_SIZE;
+            }
+            inodes.push(INode { pgid, key, value: Vec::new(), count });
+            size += elsize;
+        }
+        let first = inodes[0].key.clone();
+        let (pgid, count) = self.write_node(inodes, false)?;
+        parents.push((first, pgid, count));
+        Ok(parents)
+    }
+
+    // 页面直接写入文件而不是留在事务中，提交前 sync。返回页面 id 和其中 key 的数量
+    fn write_node(&mut self, inodes: Vec<INode>, is_leaf: bool) -> Result<(PgId, u64)> {
+        let node = NodeInner::new().leaf(is_leaf).counted(self.tx.counted()).build();
+        node.node_mut().inodes = inodes;
+        let db = self.tx.db().unwrap();
+        let mut p = db.0.allocate(node.size() / PAGE_SIZE + 1)?;
+        let page = p.to_page_mut();
+        let pgid = page.id;
+        node.write(page);
+        db.0.write_at(&p.value, pgid * PAGE_SIZE as u64)?;
+        Ok((pgid, node.key_count()))
+    }
+}
+
+impl DB {
+    /// 把所有 key（包括 TTL 等内部使用的系统 key）和序列号复制到空的数据库 dst，
+    /// 页面填满写入，返回复制的 key 数量。dst 应该使用相同的比较器
+    pub fn compact_into(&self, dst: &DB) -> Result<u64> {
+        let tx = self.begin_tx();
+        let mut wtx = dst.begin_rwtx()?;
+        let loaded = (|| {
+            wtx.set_sequence(tx.sequence())?;
+            let mut loader = BulkLoader::new_raw(&mut wtx)?.fill_percent(MAX_FILL_PERCENT);
+            let mut c = tx.raw_cursor();
+            let mut item = c.first()?;
+            while let (Some(k), Some(v)) = (item.key(), item.value()) {
+                loader.add(k, v)?;
+                item = c.next()?;
+            }
+            loader.finish()
+        })();
+        tx.close()?;
+        match loaded {
+            Ok(n) => {
+                wtx.commit()?;
+                Ok(n)
+            }
+            Err(e) => {
+                wtx.rollback()?;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::temp_db;
+
+    #[test]
+    fn test_bulk_load() {
+        let db = temp_db("rultdb_test_bulk_load.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        let n = BulkLoader::new(&mut tx)
+            .unwrap()
+            .fill_percent(1.0)
+            .load((0..20000).map(|i| (format!("{:08}", i), vec![i as u8; i % 200])))
+            .unwrap();
+        assert_eq!(n, 20000);
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert!(tx.stats().depth > 1);
+        assert_eq!(tx.stats().key_n, 20000);
+        assert_eq!(tx.get(b"00012345").unwrap(), Some(&[12345u32 as u8; 12345 % 200][..]));
+        // 加载之后可以正常修改
+        tx.put(b"00012345", b"new").unwrap();
+        tx.delete(b"00000000").unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.get(b"00012345").unwrap(), Some(&b"new"[..]));
+        assert_eq!(tx.stats().key_n, 19999);
+        tx.close().unwrap();
+
+        // 只能加载到空的 B+ 树
+        let mut tx = db.begin_rwtx().unwrap();
+        assert!(BulkLoader::new(&mut tx).is_err());
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load_unsorted() {
+        let db = temp_db("rultdb_test_bulk_load_unsorted.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        let r = BulkLoader::new(&mut tx).unwrap().load([(b"b", b"1"), (b"a", b"2")]);
+        assert!(matches!(r, Err(Error::ErrUnsortedKeys)));
+        tx.rollback().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        let mut loader = BulkLoader::new(&mut tx).unwrap();
+        loader.add(b"a", b"1").unwrap();
+        assert!(matches!(loader.add(b"a", b"2"), Err(Error::ErrUnsortedKeys)));
+        tx.rollback().unwrap();
+
+        let tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.stats().key_n, 0);
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load_system_keys() {
+        let db = temp_db("rultdb_test_bulk_load_system.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        let mut loader = BulkLoader::new(&mut tx).unwrap();
+        assert!(matches!(loader.add(b"\xffrultdb\x00exp\x00k", b""), Err(Error::ErrReservedKey)));
+        tx.rollback().unwrap();
+
+        // compact_into 复制包括 TTL 在内的所有 key
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"a", b"1").unwrap();
+        tx.put_with_ttl(b"b", b"2", std::time::Duration::from_secs(3600)).unwrap();
+        tx.set_sequence(7).unwrap();
+        tx.commit().unwrap();
+        let dst = temp_db("rultdb_test_bulk_load_system_dst.db");
+        assert!(db.compact_into(&dst).unwrap() > 2);
+        let mut tx = dst.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.get(b"a").unwrap(), Some(&b"1"[..]));
+        assert!(tx.ttl(b"b").unwrap().is_some());
+        assert_eq!(tx.sequence(), 7);
+        tx.close().unwrap();
+    }
+}
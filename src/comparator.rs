@@ -0,0 +1,260 @@
+use std::{
+    cmp::Ordering,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use crate::{is_system_key, SYSTEM_KEY_END, SYSTEM_KEY_PREFIX};
+
+/// 没有通过 Options::comparator 注册比较器时使用的名字，key 按字节比较
+pub const BYTEWISE: &str = "bytewise";
+
+/// 通过 Options::comparator 注册，决定 key 在 B+ 树中的顺序。
+/// 名字记录在 meta 页面中，之后必须使用同名的比较器打开数据库
+pub trait Comparator: Send + Sync {
+    /// 最长 COMPARATOR_NAME_SIZE 字节
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// 按字节逆序
+pub struct ReverseBytewise;
+
+impl Comparator for ReverseBytewise {
+    fn name(&self) -> &str {
+        "reverse_bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// 把 key 的前 8 个字节（不足 8 个字节时为整个 key）作为大端序的 u64 比较，
+/// 相等时再按字节比较剩余的部分
+pub struct U64BigEndian;
+
+impl Comparator for U64BigEndian {
+    fn name(&self) -> &str {
+        "u64_be"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        fn split(k: &[u8]) -> (u64, usize, &[u8]) {
+            let (n, rest) = k.split_at(k.len().min(8));
+            (n.iter().fold(0u64, |acc, &b| acc << 8 | b as u64), n.len(), rest)
+        }
+        let (a, b) = (split(a), split(b));
+        a.0.cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(b.2))
+    }
+}
+
+/// 名字对应的内置比较器，rultdb 命令行工具用它打开使用了内置比较器的数据库
+pub fn builtin(name: &str) -> Option<Arc<dyn Comparator>> {
+    match name {
+        "reverse_bytewise" => Some(Arc::new(ReverseBytewise)),
+        "u64_be" => Some(Arc::new(U64BigEndian)),
+        _ => None,
+    }
+}
+
+pub(crate) type KeyRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+// 事务和节点中比较 key 使用的顺序。没有注册比较器时按字节比较；
+// 否则用户 key 使用比较器，内部使用的系统 key 按字节比较并排在所有用户 key 之后
+#[derive(Clone, Default)]
+pub(crate) struct KeyOrder(Option<Arc<dyn Comparator>>);
+
+impl KeyOrder {
+    pub(crate) fn new(comparator: Option<Arc<dyn Comparator>>) -> Self {
+        Self(comparator)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.0.as_ref().map_or(BYTEWISE, |c| c.name())
+    }
+
+    pub(crate) fn is_bytewise(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub(crate) fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let Some(c) = &self.0 else {
+            return a.cmp(b);
+        };
+        match (is_system_key(a), is_system_key(b)) {
+            (false, false) => c.compare(a, b),
+            (true, true) => a.cmp(b),
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+        }
+    }
+
+    pub(crate) fn contains(&self, range: &(Bound<&[u8]>, Bound<&[u8]>), key: &[u8]) -> bool {
+        (match range.0 {
+            Bound::Included(s) => self.cmp(s, key).is_le(),
+            Bound::Excluded(s) => self.cmp(s, key).is_lt(),
+            Bound::Unbounded => true,
+        }) && match range.1 {
+            Bound::Included(e) => self.cmp(key, e).is_le(),
+            Bound::Excluded(e) => self.cmp(key, e).is_lt(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    // range 中不包括系统 key 的部分
+    pub(crate) fn user_ranges<'a, R: RangeBounds<&'a [u8]>>(&self, range: &R) -> Vec<KeyRange<'a>> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut ranges = vec![(start, self.min_end(end, SYSTEM_KEY_PREFIX))];
+        // 按字节比较时系统 key 之后还可能有用户 key
+        if self.is_bytewise() {
+            ranges.push((self.max_start(start, SYSTEM_KEY_END), end));
+        }
+        ranges.retain(|r| !self.is_empty_range(r));
+        ranges
+    }
+
+    // min(end, Excluded(key))
+    fn min_end<'a>(&self, end: Bound<&'a [u8]>, key: &'a [u8]) -> Bound<&'a [u8]> {
+        match end {
+            Bound::Included(e) if self.cmp(e, key).is_lt() => Bound::Included(e),
+            Bound::Excluded(e) if self.cmp(e, key).is_le() => Bound::Excluded(e),
+            _ => Bound::Excluded(key),
+        }
+    }
+
+    // max(start, Included(key))
+    fn max_start<'a>(&self, start: Bound<&'a [u8]>, key: &'a [u8]) -> Bound<&'a [u8]> {
+        match start {
+            Bound::Included(s) | Bound::Excluded(s) if self.cmp(s, key).is_ge() => start,
+            _ => Bound::Included(key),
+        }
+    }
+
+    fn is_empty_range(&self, range: &(Bound<&[u8]>, Bound<&[u8]>)) -> bool {
+        match *range {
+            (Bound::Included(s), Bound::Included(e)) => self.cmp(s, e).is_gt(),
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e)) | (Bound::Excluded(s), Bound::Included(e)) => {
+                self.cmp(s, e).is_ge()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{DBInner, Options},
+        error::Error,
+        page::Page,
+        tx::Tx,
+    };
+
+    fn keys(tx: &Tx) -> Vec<Vec<u8>> {
+        let mut c = tx.cursor();
+        let mut item = c.first().unwrap();
+        let mut keys = Vec::new();
+        while let Some(k) = item.key() {
+            keys.push(k.to_vec());
+            item = c.next().unwrap();
+        }
+        keys
+    }
+
+    #[test]
+    fn test_u64_be() {
+        let c = U64BigEndian;
+        assert_eq!(c.compare(&[1], &[0, 2]), Ordering::Less);
+        assert_eq!(c.compare(&[0, 1], &[1]), Ordering::Greater);
+        assert_eq!(c.compare(&5u64.to_be_bytes(), &[5]), Ordering::Greater);
+        assert_eq!(c.compare(&[0, 0, 0, 0, 0, 0, 0, 1, b'a'], &[0, 0, 0, 0, 0, 0, 0, 1, b'b']), Ordering::Less);
+    }
+
+    #[test]
+    fn test_reverse_comparator() {
+        let path = std::env::temp_dir().join("rultdb_test_reverse_comparator.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let opt = || Options { comparator: Some(Arc::new(ReverseBytewise)), ..Default::default() };
+        let db = DBInner::open(path, opt()).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..3000 {
+            tx.put(format!("{:05}", i).as_bytes(), b"v").unwrap();
+        }
+        tx.put_with_ttl(b"00001", b"v", std::time::Duration::from_secs(100)).unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.delete_range(&b"02999"[..]..&b"01000"[..]).unwrap(), 1999);
+        assert_eq!(tx.cursor().seek(b"00500").unwrap().key(), Some(&b"00500"[..]));
+        assert_eq!(tx.cursor().seek(b"005").unwrap().key(), Some(&b"00499"[..]));
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        let expected: Vec<Vec<u8>> = (0..1001).rev().map(|i| format!("{:05}", i).into_bytes()).collect();
+        assert_eq!(keys(&tx), expected);
+        assert!(tx.ttl(b"00001").unwrap().is_some());
+        tx.close().unwrap();
+        drop(db);
+
+        assert!(matches!(
+            DBInner::open(path, Default::default()),
+            Err(Error::ErrComparatorMismatch { expected, found }) if expected == BYTEWISE && found == "reverse_bytewise"
+        ));
+        let db = DBInner::open(path, opt()).unwrap();
+        let tx = db.begin_tx();
+        assert_eq!(keys(&tx).len(), 1001);
+        tx.close().unwrap();
+
+        // append 模式下按比较器递增的 key 使用缓存的最右侧路径，其他 key 回到正常查找
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.set_append_mode(true);
+        for i in (0..1000).rev() {
+            tx.put(format!("0000{:04}", i).as_bytes(), b"v").unwrap();
+        }
+        tx.put(b"99999", b"v").unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        let keys = keys(&tx);
+        assert_eq!(keys.len(), 2002);
+        assert_eq!(keys[0], b"99999");
+        assert!(keys.windows(2).all(|w| w[0] > w[1]));
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_empty_comparator_name() {
+        let path = std::env::temp_dir().join("rultdb_test_empty_comparator.db");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let db = DBInner::open(path, Default::default()).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"k", b"v").unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        // meta 中没有记录比较器名字的文件按字节比较打开
+        let mut buf = std::fs::read(path).unwrap();
+        for id in 0..2 {
+            let m = Page::page_in_buffer_mut(&mut buf, id).meta_mut();
+            m.comparator.fill(0);
+            m.checksum = m.compute_checksum();
+        }
+        std::fs::write(path, &buf).unwrap();
+        let db = DBInner::open(path, Default::default()).unwrap();
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"k").unwrap(), Some(&b"v"[..]));
+        tx.close().unwrap();
+        drop(db);
+        assert!(matches!(
+            DBInner::open(path, Options { comparator: Some(Arc::new(ReverseBytewise)), ..Default::default() }),
+            Err(Error::ErrComparatorMismatch { expected, found }) if expected == "reverse_bytewise" && found == BYTEWISE
+        ));
+    }
+
+}
@@ -8,10 +8,12 @@ use crate::error::Result;
 
 
 
-pub(crate) struct Cursor{
+pub struct Cursor{
     //pub(crate) bucket: &'a mut Bucket,
     pub(crate) tx: Tx,
     stack: Vec<ElemRef>,
+    // 为 true 时不跳过系统 key 和已经过期的 key
+    raw: bool,
 }
 
 
@@ -22,7 +24,7 @@ pub(crate) struct ElemRef {
 }
 
 
-pub(crate) struct Item<'a>(
+pub struct Item<'a>(
     pub(crate) Option<&'a [u8]>,
     pub(crate) Option<&'a [u8]>,
 );
@@ -37,11 +39,11 @@ impl<'a> Item<'a> {
         Self(None, None)
     }
 
-    pub(crate) fn key(&self) -> Option<&'a [u8]> {
+    pub fn key(&self) -> Option<&'a [u8]> {
         self.0
     }
 
-    pub(crate) fn value(&self) -> Option<&'a [u8]> {
+    pub fn value(&self) -> Option<&'a [u8]> {
         self.1
     }
 
@@ -56,6 +58,29 @@ impl ElemRef {
         }
     }
 
+    fn pgid(&self) -> PgId {
+        match &self.page_node {
+            PageNode::Node(n) => n.node().pgid,
+            PageNode::Page(p) => self.get_page(p).id,
+        }
+    }
+
+    // 分支节点 index 位置的子节点
+    fn child_pgid(&self) -> PgId {
+        match &self.page_node {
+            PageNode::Node(n) => n.node().inodes[self.index].pgid,
+            PageNode::Page(p) => self.get_page(p).branch_page_element(self.index).value,
+        }
+    }
+
+    // 叶子节点 index 位置的 key
+    fn key_at(&self, index: usize) -> &[u8] {
+        match &self.page_node {
+            PageNode::Node(n) => unsafe { &*(n.node().inodes[index].key.as_slice() as *const [u8]) },
+            PageNode::Page(p) => self.get_page(p).leaf_page_element(index).key(),
+        }
+    }
+
     fn count(&self) -> usize {
         match &self.page_node {
             PageNode::Node(n) => n.node().inodes.len(),
@@ -87,10 +112,59 @@ impl Cursor {
         Self {
             tx: tx,
             stack: Vec::new(),
+            raw: false,
         }
     }
 
-    fn first(&mut self) -> Result<()> {
+    pub(crate) fn new_raw(tx: Tx) -> Cursor {
+        Self { tx, stack: Vec::new(), raw: true }
+    }
+
+    /// 将游标移动到第一个 key
+    pub fn first<'a>(&mut self) -> Result<Item<'a>> {
+        let item = self.first_item()?;
+        self.skip_hidden(item)
+    }
+
+    /// 将游标移动到下一个 key，没有更多数据时返回空的 Item
+    pub fn next<'a>(&mut self) -> Result<Item<'a>> {
+        let item = self.next_item()?;
+        self.skip_hidden(item)
+    }
+
+    /// 将游标移动到第一个大于等于 key 的位置
+    pub fn seek<'a>(&mut self, key: &[u8]) -> Result<Item<'a>> {
+        let item = self.seek_raw(key)?;
+        self.skip_hidden(item)
+    }
+
+    // 跳过系统 key 和已经过期的 key
+    fn skip_hidden<'a>(&mut self, mut item: Item<'a>) -> Result<Item<'a>> {
+        while let Some(k) = item.key() {
+            if self.raw || !self.tx.is_hidden(k)? {
+                break;
+            }
+            item = self.next_item()?;
+        }
+        Ok(item)
+    }
+
+    fn first_item<'a>(&mut self) -> Result<Item<'a>> {
+        self.stack.clear();
+        let page_node = self.tx.page_node(self.tx.root_id())?;
+        self.stack.push(ElemRef {
+            page_node,
+            index: 0,
+        });
+        self.first_leaf()?;
+        if self.stack.last().unwrap().count() == 0 {
+            return self.next_item();
+        }
+        self.key_value()
+    }
+
+    //从栈顶开始一直向下走到最左边的叶子节点
+    fn first_leaf(&mut self) -> Result<()> {
         loop {
             let ref_elem = self.stack.last().ok_or("stack empty")?;
             if ref_elem.is_leaf() {
@@ -122,11 +196,10 @@ impl Cursor {
     }
 
 
-    fn next<'a>(&mut self) -> Result<Item<'a>> {
+    fn next_item<'a>(&mut self) -> Result<Item<'a>> {
         loop {
-            let mut index: usize = 0;
             let mut i: i32 = -1;
-            for _i in (0..self.stack.len() - 1).rev() {
+            for _i in (0..self.stack.len()).rev() {
                 //取上一页数据
                 let elem = self.stack.get_mut(_i).ok_or("get elem fail")?;
                 if elem.index + 1 < elem.count() {
@@ -139,7 +212,7 @@ impl Cursor {
                 return Ok(Item::null());
             }
             self.stack.truncate((i + 1) as usize);
-            self.first()?;
+            self.first_leaf()?;
             if self.stack.last().unwrap().count() == 0 {
                 continue;
             }
@@ -147,12 +220,43 @@ impl Cursor {
         }
     }
 
-    pub(crate) fn seek<'a>(&mut self, key: &[u8]) -> Result<Item<'a>> {
+    // 将游标移动到上一个 key，已经在第一个 key 时返回空的 Item。不跳过系统 key
+    pub(crate) fn prev_item<'a>(&mut self) -> Result<Item<'a>> {
+        loop {
+            let Some(i) = (0..self.stack.len()).rev().find(|&i| self.stack[i].index > 0) else {
+                return Ok(Item::null());
+            };
+            self.stack[i].index -= 1;
+            self.stack.truncate(i + 1);
+            self.last_leaf()?;
+            if self.stack.last().unwrap().count() == 0 {
+                continue;
+            }
+            return self.key_value();
+        }
+    }
+
+    //从栈顶开始一直向下走到最右边的叶子节点
+    fn last_leaf(&mut self) -> Result<()> {
+        loop {
+            let ref_elem = self.stack.last().ok_or("stack empty")?;
+            if ref_elem.is_leaf() {
+                break;
+            }
+            let page_node = self.tx.page_node(ref_elem.child_pgid())?;
+            let mut elem = ElemRef { page_node, index: 0 };
+            elem.index = elem.count().saturating_sub(1);
+            self.stack.push(elem);
+        }
+        Ok(())
+    }
+
+    fn seek_raw<'a>(&mut self, key: &[u8]) -> Result<Item<'a>> {
         let mut item = self.seek_item(key)?;
         let ref_elem = self.stack.last().ok_or("stack empty")?;
         let idx = ref_elem.index;
         if ref_elem.index >= ref_elem.count() {
-            item = self.next()?;
+            item = self.next_item()?;
         }
 
         //dbg!(idx);
@@ -166,7 +270,12 @@ impl Cursor {
 
     pub(crate) fn seek_item<'a>(&mut self, key: &[u8]) -> Result<Item<'a>> {
         self.stack.clear();
-        self.search(key, self.tx.root_id())?;
+        if !self.tx.append_mode() || !self.seek_last_leaf(key)? {
+            self.search(key, self.tx.root_id())?;
+            if self.tx.append_mode() {
+                self.save_last_leaf();
+            }
+        }
         let ref_elem = self.stack.last().ok_or("stack empty")?;
         if ref_elem.index >= ref_elem.count() {
             return Ok(Item::null());
@@ -174,6 +283,40 @@ impl Cursor {
         self.key_value()
     }
 
+    // append 模式下 key 不小于最后一个叶子节点的第一个 key 时，直接使用缓存的最右侧路径
+    fn seek_last_leaf(&mut self, key: &[u8]) -> Result<bool> {
+        let path = self.tx.0.last_leaf.borrow().clone();
+        for (i, &id) in path.iter().enumerate() {
+            let mut elem = ElemRef { page_node: self.tx.page_node(id)?, index: 0 };
+            let count = elem.count();
+            let valid = match path.get(i + 1) {
+                Some(&child) => {
+                    elem.index = count.saturating_sub(1);
+                    !elem.is_leaf() && count > 0 && elem.child_pgid() == child
+                }
+                None => elem.is_leaf() && (path.len() == 1 || (count > 0 && self.tx.0.order.cmp(elem.key_at(0), key).is_le())),
+            };
+            if !valid {
+                self.stack.clear();
+                self.tx.0.last_leaf.borrow_mut().clear();
+                return Ok(false);
+            }
+            self.stack.push(elem);
+        }
+        if self.stack.is_empty() {
+            return Ok(false);
+        }
+        self.nsearch(key)?;
+        Ok(true)
+    }
+
+    fn save_last_leaf(&self) {
+        let rightmost = self.stack.iter().all(|e| e.is_leaf() || e.index + 1 == e.count());
+        if rightmost {
+            *self.tx.0.last_leaf.borrow_mut() = self.stack.iter().map(|e| e.pgid()).collect();
+        }
+    }
+
     fn key_value<'a>(&self) -> Result<Item<'a>> {
         let ref_elem = self.stack.last().ok_or("stack empty")?;
         unsafe {
@@ -233,7 +376,7 @@ impl Cursor {
                 let index = match n
                     .node()
                     .inodes
-                    .binary_search_by(|inode| inode.key.as_slice().cmp(key))
+                    .binary_search_by(|inode| self.tx.0.order.cmp(&inode.key, key))
                 {
                     Ok(v) => (v),
                     Err(e) => (e),
@@ -246,7 +389,7 @@ impl Cursor {
                     //dbg!(&inodes[0..20]);
 
                 //}
-                let index = match inodes.binary_search_by(|inode| inode.key().cmp(key)) {
+                let index = match inodes.binary_search_by(|inode| self.tx.0.order.cmp(inode.key(), key)) {
                     Ok(v) => (v),
                     Err(e) => (e),
                 };
@@ -258,7 +401,7 @@ impl Cursor {
 
     fn search_page(&mut self, key: &[u8], p: &Page) -> Result<()> {
         let inodes = p.branch_page_elements();
-        let (exact, mut index) = match inodes.binary_search_by(|inode| inode.key().cmp(key)) {
+        let (exact, mut index) = match inodes.binary_search_by(|inode| self.tx.0.order.cmp(inode.key(), key)) {
             Ok(v) => (true, v),
             Err(e) => (false, e),
         };
@@ -282,7 +425,7 @@ impl Cursor {
         let (exact, mut index) = match n
             .node()
             .inodes
-            .binary_search_by(|inode| inode.key.as_slice().cmp(key))
+            .binary_search_by(|inode| self.tx.0.order.cmp(&inode.key, key))
         {
             Ok(v) => (true, v),
             Err(e) => (false, e),
@@ -300,6 +443,12 @@ impl Cursor {
         if ref_elem.is_node() && ref_elem.is_leaf() {
             return Ok(ref_elem.node().expect("get node fail"));
         }
+        // 页面被修改前先校验 checksum，避免把损坏的数据写入新的页面
+        for e in self.stack.iter() {
+            if let PageNode::Page(p) = &e.page_node {
+                e.get_page(p).verify_checksum()?;
+            }
+        }
         let mut elem = self.stack.first().unwrap();
         let mut n = match &elem.page_node {
             PageNode::Node(n) => n.clone(),
@@ -1,19 +1,27 @@
-use std::{borrow::{Borrow, BorrowMut}, default, fs::{File, OpenOptions}, io::Write, ptr::null, sync::{atomic::AtomicU64, Arc, Weak}};
+use std::{borrow::{Borrow, BorrowMut}, default, fs::{File, OpenOptions}, io::Write, ptr::null, sync::{atomic::{AtomicU64, Ordering}, Arc, Weak}};
 
 use parking_lot::Mutex;
 use parking_lot::RwLock;
-use crate::{config::{INITIAL_DB_SIZE, MAX_MMAP_SIZE, MAX_MMAP_STEP, PAGE_SIZE}, db, freelist::FreeList, page::{Meta, OwnedPage, Page, PageFlag, PgId, MAGIC, VERSION}, tx::{Tx, TxId, TxInner}};
+use crate::{config::{INITIAL_DB_SIZE, MAX_MMAP_SIZE, MAX_MMAP_STEP, PAGE_SIZE}, db, freelist::FreeList, inspect::verify_tree, feed::{self, Subscriber, Watch}, index::Index, comparator::{Comparator, KeyOrder}, merge::MergeOperator, ttl::{Clock, SystemClock, DEFAULT_TTL_SWEEP_BATCH}, page::{Meta, OwnedPage, Page, PageFlag, PgId, COMPARATOR_NAME_SIZE, MAGIC, META_COUNTED, VERSION}, tx::{Tx, TxId, TxInner}};
 
 use crate::error::{Error,Result};
 use std::os::unix::fs::FileExt;
 use lock_api::{RawMutex, RawRwLock};
+pub(crate) const NO_IGNORED_META: PgId = PgId::MAX;
+
 pub struct DBInnerState {
     pub db_size: u64,
     pub meta0: *const Meta, 
     pub meta1: *const Meta,
     pub mmap: Option<memmap::Mmap>,
+    // 打开时校验失败的 meta 页面，在被新的事务覆盖之前不再使用
+    pub(crate) ignored_meta: AtomicU64,
 }
 
+// meta0、meta1 指向 mmap，只在持有 state 锁时访问
+unsafe impl Send for DBInnerState {}
+unsafe impl Sync for DBInnerState {}
+
 impl Default for DBInnerState {
     fn default() -> Self {
         Self{
@@ -21,6 +29,7 @@ impl Default for DBInnerState {
             mmap: None,
             meta0: null(),
             meta1: null(),
+            ignored_meta: AtomicU64::new(NO_IGNORED_META),
         }
     }
 }
@@ -40,8 +49,10 @@ impl DBInnerState {
         let meta0 = Page::page_in_buffer(&nmmap, 0).meta();
         let meta1 = Page::page_in_buffer(&nmmap, 1).meta();
 
-        meta0.validate()?;
-        meta1.validate()?;
+        // 只有两个 meta 页面都无效时才返回错误，其中一个在写入时中断的话可以使用另一个
+        if meta0.validate().is_err() {
+            meta1.validate()?;
+        }
         self.meta0 = meta0;
         self.meta1 = meta1;
         self.mmap.replace(nmmap);
@@ -50,21 +61,74 @@ impl DBInnerState {
     }
 
     pub(crate) fn meta(&self) -> Meta {
-        unsafe {
-            let mut meta_a = self.meta0;
-            let mut meta_b = self.meta1;
-            if (*self.meta1).txid > (*self.meta0).txid {
-                meta_a = self.meta1;
-                meta_b = self.meta0;
+        let ignored = self.ignored_meta.load(Ordering::Acquire);
+        for id in self.meta_order() {
+            let m = self.meta_page(id);
+            if id != ignored && m.validate().is_ok() {
+                return m.clone();
             }
-            if (*meta_a).validate().is_ok() {
-                return (*meta_a).clone();
+        }
+        panic!(" invalid meta pages")
+    }
+
+    fn meta_page(&self, id: PgId) -> &Meta {
+        unsafe { if id == 0 { &*self.meta0 } else { &*self.meta1 } }
+    }
+
+    // txid 较大的 meta 页面在前
+    fn meta_order(&self) -> [PgId; 2] {
+        if self.meta_page(1).txid > self.meta_page(0).txid {
+            [1, 0]
+        } else {
+            [0, 1]
+        }
+    }
+
+    /// 选择打开时使用的 meta 页面。最新的 meta 校验失败（以及 Verify/Strict 模式下它指向的
+    /// 页面校验失败）时，Strict 模式返回错误，其他模式回退到另一个 meta 并通过 on_recovery 通知
+    pub(crate) fn recover(&self, opt: &Options, file_size: u64) -> Result<()> {
+        let [newest, older] = self.meta_order();
+        // 比较器不同时 key 的顺序也不同，不能用来校验 B+ 树
+        let order = KeyOrder::new(opt.comparator.clone());
+        if let Some(m) = [newest, older].map(|id| self.meta_page(id)).into_iter().find(|m| m.validate().is_ok()) {
+            if m.comparator_name() != order.name() {
+                return Err(Error::ErrComparatorMismatch { expected: order.name().to_string(), found: m.comparator_name() });
             }
-            if (*meta_b).validate().is_ok() {
-                return (*meta_b).clone();
+        }
+        let verify = |id: PgId| -> Result<()> {
+            let m = self.meta_page(id);
+            m.validate()?;
+            if opt.recovery == Recovery::Off {
+                return Ok(());
             }
-            panic!(" invalid meta pages")
+            let len = file_size.min(self.db_size) as usize;
+            verify_tree(&self.mmap.as_ref().unwrap()[..len], m, &order)
+        };
+
+        let reason = match verify(newest) {
+            Ok(()) => return Ok(()),
+            Err(e) if opt.recovery == Recovery::Strict => {
+                return Err(match e {
+                    Error::ErrCorrupted { .. } => e,
+                    _ => Error::ErrCorrupted { pgid: newest },
+                });
+            }
+            Err(e) => e,
+        };
+        if verify(older).is_err() {
+            return Err(reason);
+        }
+
+        self.ignored_meta.store(newest, Ordering::Release);
+        if let Some(f) = &opt.on_recovery {
+            f(&MetaFallback {
+                meta_page: newest,
+                txid: self.meta_page(newest).txid,
+                fallback_txid: self.meta_page(older).txid,
+                reason,
+            });
         }
+        Ok(())
     }
     fn mmap_size(&self, mut size: u64) -> Result<u64> {
         for i in 3..=30 {
@@ -92,6 +156,18 @@ impl DBInnerState {
 }
 pub struct DBInner {
     pub file: RwLock<File>,
+    pub read_only: bool,
+    pub verify_checksums: bool,
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    pub(crate) order: KeyOrder,
+    pub clock: Arc<dyn Clock>,
+    pub ttl_sweep_batch: usize,
+    pub on_commit: Option<CommitCallback>,
+    pub(crate) indexes: Vec<Index>,
+    // 创建数据库时是否使用计数 B+ 树
+    pub(crate) counted: bool,
+    pub(crate) subscribers: Mutex<Vec<Weak<Subscriber>>>,
+    pub(crate) watch: Watch,
     pub freelist: RwLock<FreeList>,
     pub rw_tx: RwLock<Option<Tx>>,
     pub txs: RwLock<Vec<Tx>>,
@@ -106,15 +182,79 @@ pub struct WeakDB(pub Weak<DBInner>);
 
 
 pub struct Options{
-    pub initial_mmap_size: usize
+    pub initial_mmap_size: usize,
+    /// 以只读方式打开文件，此时不能开启写事务
+    pub read_only: bool,
+    /// 每次读取页面时都校验页面 checksum。关闭时只在修改页面前校验
+    pub verify_checksums: bool,
+    pub recovery: Recovery,
+    /// 打开时放弃最新的 meta 页面、回退到另一个 meta 页面时调用
+    pub on_recovery: Option<RecoveryCallback>,
+    /// Tx::merge 使用的 merge operator
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// key 的比较器，默认按字节比较。名字记录在 meta 页面中，打开时名字不同返回 ErrComparatorMismatch
+    pub comparator: Option<Arc<dyn Comparator>>,
+    /// 判断 key 是否过期使用的时钟，默认使用系统时间
+    pub clock: Option<Arc<dyn Clock>>,
+    /// 每个写事务提交时最多删除的过期 key 数量，0 表示只通过 Tx::sweep_expired 或 DB::start_sweeper 删除
+    pub ttl_sweep_batch: usize,
+    /// 每个写事务提交之后、释放写锁之前按 txid 的顺序调用，参数包括事务 id 和修改过的 key。
+    /// 回调中不能开始写事务
+    pub on_commit: Option<CommitCallback>,
+    /// 在写事务中自动维护的二级索引
+    pub indexes: Vec<Index>,
+    /// 创建数据库时使用计数 B+ 树，支持 Tx::count、Tx::rank 和 Tx::nth。
+    /// 记录在 meta 页面中，打开已经存在的数据库时忽略
+    pub counted: bool,
+}
+
+pub type CommitCallback = Arc<dyn Fn(&CommitInfo) + Send + Sync>;
+
+/// 已经提交的写事务以及它修改过的 key（按顺序排列，不包括内部使用的系统 key）
+#[derive(Debug, Clone)]
+pub struct CommitInfo {
+    pub txid: TxId,
+    pub keys: Vec<Vec<u8>>,
+}
 
+pub type RecoveryCallback = Arc<dyn Fn(&MetaFallback) + Send + Sync>;
+
+/// 打开数据库时对 meta 页面的校验方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// 只校验 meta 页面的 checksum
+    Off,
+    /// 额外遍历最新 meta 指向的 B+ 树和 freelist 页面，校验失败时回退到另一个 meta
+    Verify,
+    /// 和 Verify 一样校验，但校验失败时返回 Error::ErrCorrupted 而不回退
+    Strict,
+}
+
+/// 打开时放弃的 meta 页面以及放弃的原因
+#[derive(Debug)]
+pub struct MetaFallback {
+    pub meta_page: PgId,
+    pub txid: TxId,
+    pub fallback_txid: TxId,
+    pub reason: Error,
 }
+
+impl Default for Options {
+    fn default() -> Self {
+        DEFAULT_OPTIONS
+    }
+}
+
 impl DB {
-    fn begin_rwtx(&self) -> Tx {
+    /// 写事务需要扩大 mmap 时会等待所有读事务结束，所以不要在持有读事务的线程中开启写事务
+    pub fn begin_rwtx(&self) -> Result<Tx> {
+        if self.0.read_only {
+            return Err(Error::ErrDatabaseReadOnly);
+        }
         unsafe {
             self.0.rw_lock.raw().lock();
         }
-        let mut meta = self.0.state.try_read().unwrap().borrow().meta();
+        let mut meta = self.0.state().meta();
         meta.txid+=1;
         let mut tx = Tx::new(true, WeakDB(Arc::downgrade(&self.0)), meta);
         *(self.0.rw_tx.try_write().unwrap()) = Some(tx.clone());
@@ -126,20 +266,20 @@ impl DB {
             .min()
             .unwrap_or(0xFFFF_FFFF_FFFF_FFFF);
         if minid > 0 {
-            self.0.freelist.try_write().unwrap().release(minid - 1);
+            self.0.freelist.write().release(minid - 1);
         }
         drop(txs);
-        tx
+        Ok(tx)
 
     }
 
-    fn begin_tx(&self) -> Tx {
+    pub fn begin_tx(&self) -> Tx {
         unsafe {
             self.0.state.raw().lock_shared();
         }
-        let mut meta = self.0.state.try_read().unwrap().borrow().meta();
+        let meta = self.0.state().meta();
         let mut tx = Tx::new(false, WeakDB(Arc::downgrade(&self.0)), meta);
-        self.0.txs.try_write().unwrap().push(tx.clone());
+        self.0.txs.write().push(tx.clone());
         tx
     }
 
@@ -149,10 +289,27 @@ impl DB {
 
 }
 
+impl Drop for DBInner {
+    fn drop(&mut self) {
+        feed::close(&self.subscribers);
+    }
+}
+
 impl DBInner {
     pub fn new(file: File) -> Self {
         Self{
             file: RwLock::new(file),
+            read_only: false,
+            verify_checksums: false,
+            merge_operator: None,
+            order: Default::default(),
+            clock: Arc::new(SystemClock),
+            ttl_sweep_batch: DEFAULT_TTL_SWEEP_BATCH,
+            on_commit: None,
+            indexes: Vec::new(),
+            counted: false,
+            subscribers: Default::default(),
+            watch: Default::default(),
             freelist: RwLock::new(FreeList::default()),
             rw_tx:RwLock::new(None), 
             txs: RwLock::new(Vec::default()),
@@ -165,27 +322,50 @@ impl DBInner {
 
         let f = OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true)
+            .write(!opt.read_only)
+            .create(!opt.read_only)
             .open(path)
             .map_err(|e| Error::DBOpenFail(e))?; 
-        f.set_len(0);
 
         let size = f.metadata().map_err(|e| Error::DBOpenFail(e))?.len();
         let mut db = Self::new(f);
+        db.read_only = opt.read_only;
+        db.verify_checksums = opt.verify_checksums;
+        db.merge_operator = opt.merge_operator.clone();
+        db.order = KeyOrder::new(opt.comparator.clone());
+        if db.order.name().len() > COMPARATOR_NAME_SIZE {
+            return Err(Error::Unexpected(format!("comparator name too long: {}", db.order.name())));
+        }
+        if let Some(clock) = &opt.clock {
+            db.clock = clock.clone();
+        }
+        db.ttl_sweep_batch = opt.ttl_sweep_batch;
+        db.on_commit = opt.on_commit.clone();
+        for (i, index) in opt.indexes.iter().enumerate() {
+            if opt.indexes[..i].iter().any(|other| other.name() == index.name()) {
+                return Err(Error::Unexpected(format!("duplicate index name: {}", index.name())));
+            }
+        }
+        db.indexes = opt.indexes.clone();
+        db.counted = opt.counted;
         if size == 0 {
+            if opt.read_only {
+                return Err(Error::ErrInvalid);
+            }
             db.init()?;
         }
-        db.state.try_write().unwrap().set_mmap(&db.file.try_read().unwrap(),0)?;
+        db.state.try_write().unwrap().set_mmap(&db.file.try_read().unwrap(),opt.initial_mmap_size)?;
+        let file_size = db.file.try_read().unwrap().metadata().map_err(|e| Error::DBOpenFail(e))?.len();
+        db.state.try_read().unwrap().recover(&opt, file_size)?;
         let meta = db.state.try_read().unwrap().meta();
         {
             let t = db.state.try_read().unwrap();
             let tt = t.mmap.as_ref().unwrap().as_ptr();
             unsafe {
                 let buf = std::slice::from_raw_parts(tt, t.db_size as usize);
-                db.freelist.try_write().unwrap().read(
-                    Page::page_in_buffer(buf, meta.freelist)
-                );
+                let p = Page::page_in_buffer(buf, meta.freelist);
+                p.verify_checksum()?;
+                db.freelist.write().read(p);
             }
         } 
         Ok(DB(Arc::new(db)))
@@ -205,6 +385,10 @@ impl DBInner {
             m.root = 3;
             m.pgid = 4;
             m.txid = i as TxId;
+            m.comparator[..self.order.name().len()].copy_from_slice(self.order.name().as_bytes());
+            if self.counted {
+                m.flags |= META_COUNTED;
+            }
             m.checksum = m.compute_checksum();
         }
 
@@ -212,11 +396,13 @@ impl DBInner {
         p.id = 2;
         p.flags = PageFlag::FreeListPage;
         p.count = 0;
+        p.set_checksum();
 
         p = Page::page_in_buffer_mut(&mut buf, 3);
         p.id = 3;
         p.flags = PageFlag::LeafPage;
         p.count = 0;
+        p.set_checksum();
 
         self.write_at(&buf, 0)?;
         self.sync()?;
@@ -243,9 +429,14 @@ impl DBInner {
         Ok(())
     }
 
+    // 读事务在整个生命周期内持有 state 的共享锁，只有写事务在 allocate 中修改 state，
+    // 所以事务中可以直接读取。不能使用 try_read：allocate 等待读事务结束时 try_read 会失败
+    pub(crate) fn state(&self) -> &DBInnerState {
+        unsafe { &*self.state.data_ptr() }
+    }
+
     pub fn page(&self, id: PgId) -> *const Page {
-        let r = self.state.try_read().unwrap();
-        let s = r.mmap.as_deref().unwrap();
+        let s = self.state().mmap.as_deref().unwrap();
         let ptr = unsafe { s.as_ptr().add(id as usize * PAGE_SIZE as usize)} as*const Page;
         ptr
     }
@@ -263,7 +454,7 @@ impl DBInner {
         let p = page.to_page_mut();
         p.overflow = (count - 1) as u32;
 
-        p.id = self.freelist.try_write().unwrap().allocate(count);
+        p.id = self.freelist.write().allocate(count);
         if p.id != 0 {
             return Ok(page);
         }
@@ -274,11 +465,9 @@ impl DBInner {
             .pgid;
 
         let minsz = (((p.id + count as PgId + 1) as usize) * PAGE_SIZE) as u64;
-        if minsz >= self.state.try_read().unwrap().db_size {
-            self.state
-                .try_write()
-                .unwrap()
-                .set_mmap(&self.file.try_read().unwrap(), minsz as usize)?;
+        if minsz >= self.state().db_size {
+            // 和 bolt 的 mmaplock 一样，等待所有读事务结束之后再重新 mmap
+            self.state.write().set_mmap(&self.file.try_read().unwrap(), minsz as usize)?;
         }
 
         
@@ -291,13 +480,25 @@ impl DBInner {
 
 
 const DEFAULT_OPTIONS: Options = Options {
-    initial_mmap_size: INITIAL_DB_SIZE
+    initial_mmap_size: INITIAL_DB_SIZE,
+    read_only: false,
+    verify_checksums: false,
+    recovery: Recovery::Off,
+    on_recovery: None,
+    merge_operator: None,
+    comparator: None,
+    clock: None,
+    ttl_sweep_batch: DEFAULT_TTL_SWEEP_BATCH,
+    on_commit: None,
+    indexes: Vec::new(),
+    counted: false,
 };
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::config::INITIAL_DB_SIZE;
 
     use super::*;
+    use crate::inspect::PageContents;
     use core::time;
     use std::str;
     use std::thread;
@@ -306,15 +507,22 @@ mod tests {
     use std::thread::Thread;
     use std::time::Duration;
 
+    /// 在临时目录下创建一个全新的数据库
+    pub(crate) fn temp_db(name: &str) -> DB {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        DBInner::open(path.to_str().unwrap(), DEFAULT_OPTIONS).unwrap()
+    }
+
     #[test]
     fn test_multi_thread() {
-        let db = DB::open("./test1.db", Options { initial_mmap_size: INITIAL_DB_SIZE });
+        let db = temp_db("rultdb_test_multi_thread.db");
         let mut v = vec![];
         let s = std::time::Instant::now(); 
         for i in 0..3000{
-            let mut tx = db.begin_rwtx();
+            let mut tx = db.begin_rwtx().unwrap();
             tx.put(i.to_string().as_bytes(), i.to_string().as_bytes());
-            assert_eq!(tx.get(i.to_string().as_bytes()).unwrap(),i.to_string().as_bytes());
+            assert_eq!(tx.get(i.to_string().as_bytes()).unwrap().unwrap(),i.to_string().as_bytes());
             tx.commit();
         }
         println!("{:?}", s.elapsed());
@@ -323,7 +531,7 @@ mod tests {
             let mut tx = db.begin_tx();
             v.push(thread::spawn(move || {
                 //dbg!(k.to_string().as_bytes());
-                assert_eq!(tx.get(k.to_string().as_bytes()),Some(k.to_string().as_bytes()));
+                assert_eq!(tx.get(k.to_string().as_bytes()).unwrap(),Some(k.to_string().as_bytes()));
 //                assert_eq!(tx.get(k.to_string().as_bytes()),Some(k.to_string().as_bytes()));
                 //assert_eq!(tx.get(k.to_string().as_bytes()),Some(k.to_string().as_bytes()));
                 //assert_eq!(tx.get(k.to_string().as_bytes()),Some(k.to_string().as_bytes()));
@@ -340,7 +548,7 @@ mod tests {
     }
     #[test]
     fn test_db_mmap() {
-        let db = DB::open("./test1.db", Options { initial_mmap_size: INITIAL_DB_SIZE });
+        let db = temp_db("rultdb_test_db_mmap.db");
         let mut tx = unsafe { (&*(db.0.state.try_read().unwrap().meta0)).txid };
         let mut buf = vec![0; 4096];
         let page =
@@ -349,7 +557,7 @@ mod tests {
         meta.txid = 2;
         db.0.write_at(&buf, 0).unwrap();
         db.0.sync().unwrap();
-        let mut tx = db.begin_rwtx();
+        let mut tx = db.begin_rwtx().unwrap();
         tx.commit();
         assert_eq!(tx.id(),2);
     }
@@ -357,34 +565,34 @@ mod tests {
 
     #[test]
     fn test_tx_delete() {
-        let mut db = DBInner::open("./test2.db", DEFAULT_OPTIONS).unwrap();
-        let mut tx1 = db.begin_rwtx();
+        let mut db = temp_db("rultdb_test_tx_delete.db");
+        let mut tx1 = db.begin_rwtx().unwrap();
         tx1.put(b"001", b"123");
         tx1.put(b"005", b"ccc");
         tx1.commit();
-        let mut tx2 = db.begin_rwtx();
+        let mut tx2 = db.begin_rwtx().unwrap();
         tx2.put(b"002", b"bbb");
         tx2.put(b"003", b"ccc");
         tx2.put(b"004", b"ddd");
         tx2.commit();
 
-        let mut tx3 = db.begin_rwtx();
+        let mut tx3 = db.begin_rwtx().unwrap();
         tx3.delete(b"001");
-        assert_eq!(tx3.get(b"001"),None);
+        assert_eq!(tx3.get(b"001").unwrap(),None);
         tx3.commit();
 
 
         let mut tx4 = db.begin_tx();
-        assert_eq!(tx4.get(b"002").unwrap(),b"bbb");
-        assert_eq!(tx4.get(b"004").unwrap(),b"ddd");
-        assert_eq!(tx4.get(b"001"),None);
+        assert_eq!(tx4.get(b"002").unwrap().unwrap(),b"bbb");
+        assert_eq!(tx4.get(b"004").unwrap().unwrap(),b"ddd");
+        assert_eq!(tx4.get(b"001").unwrap(),None);
         tx4.commit();
     }
     #[test]
     fn test_tx_put_get() {
-        let mut db = DBInner::open("./test2.db", DEFAULT_OPTIONS).unwrap();
+        let mut db = temp_db("rultdb_test_tx_put_get.db");
         dbg!(db.0.state.try_read().unwrap().meta().root);
-        let mut tx1 = db.begin_rwtx();
+        let mut tx1 = db.begin_rwtx().unwrap();
         tx1.put(b"001", b"aaa");
         tx1.put(b"005", b"ccc");
         tx1.commit().unwrap();
@@ -392,10 +600,490 @@ mod tests {
         dbg!(db.0.state.try_read().unwrap().meta().root);
         dbg!(db.0.state.try_read().unwrap().meta().txid);
         let mut tx4 = db.begin_tx();
-        assert_eq!(tx4.get(b"001").unwrap(),b"aaa");
-        assert_eq!(tx4.get(b"008"),None);
+        assert_eq!(tx4.get(b"001").unwrap().unwrap(),b"aaa");
+        assert_eq!(tx4.get(b"008").unwrap(),None);
         tx4.commit();
     }
+
+    #[test]
+    fn test_conditional_writes() {
+        let db = temp_db("rultdb_test_conditional_writes.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..2000 {
+            tx.put(format!("{:05}", i).as_bytes(), b"v0").unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.compare_and_swap(b"00010", Some(b"v0"), Some(b"v1")).unwrap();
+        let r = tx.compare_and_swap(b"00010", Some(b"v0"), Some(b"v2"));
+        assert!(matches!(r, Err(Error::ErrCompareAndSwap { current: Some(ref v) }) if v == b"v1"));
+        let r = tx.compare_and_swap(b"00010", None, Some(b"v2"));
+        assert!(matches!(r, Err(Error::ErrCompareAndSwap { current: Some(_) })));
+        tx.compare_and_swap(b"00020", Some(b"v0"), None).unwrap();
+        let r = tx.compare_and_swap(b"00020", Some(b"v0"), None);
+        assert!(matches!(r, Err(Error::ErrCompareAndSwap { current: None })));
+        tx.compare_and_swap(b"10000", None, Some(b"new")).unwrap();
+
+        assert!(!tx.put_if_absent(b"00030", b"v1").unwrap());
+        assert!(tx.put_if_absent(b"00020", b"v1").unwrap());
+        assert_eq!(tx.replace(b"00040", b"v1").unwrap(), Some(b"v0".to_vec()));
+        assert_eq!(tx.replace(b"20000", b"v1").unwrap(), None);
+        // 大于叶子节点最后一个 key 的 key 写入当前叶子节点
+        assert!(tx.put_if_absent(b"00100a", b"v1").unwrap());
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.get(b"00010").unwrap(), Some(&b"v1"[..]));
+        assert_eq!(tx.get(b"00020").unwrap(), Some(&b"v1"[..]));
+        assert_eq!(tx.get(b"00030").unwrap(), Some(&b"v0"[..]));
+        assert_eq!(tx.get(b"00040").unwrap(), Some(&b"v1"[..]));
+        assert_eq!(tx.get(b"10000").unwrap(), Some(&b"new"[..]));
+        assert_eq!(tx.get(b"20000").unwrap(), None);
+        assert_eq!(tx.stats().key_n, 2002);
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_sequence() {
+        let path = std::env::temp_dir().join("rultdb_test_sequence.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let db = DBInner::open(path, Default::default()).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.sequence(), 0);
+        assert_eq!(tx.next_sequence().unwrap(), 1);
+        assert_eq!(tx.next_sequence().unwrap(), 2);
+        tx.put(b"k", b"v").unwrap();
+        tx.commit().unwrap();
+
+        // 回滚的事务中的序列号不会保留
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.next_sequence().unwrap(), 3);
+        tx.rollback().unwrap();
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.sequence(), 2);
+        assert!(matches!(tx.next_sequence(), Err(Error::ErrTxNotWritable)));
+        tx.close().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.set_sequence(100).unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let db = DBInner::open(path, Default::default()).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.next_sequence().unwrap(), 101);
+        assert_eq!(tx.get(b"k").unwrap(), Some(&b"v"[..]));
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_commit_hooks() {
+        let path = std::env::temp_dir().join("rultdb_test_commit_hooks.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let infos = Arc::new(Mutex::new(Vec::new()));
+        let cloned = infos.clone();
+        let opt = Options {
+            on_commit: Some(Arc::new(move |info: &CommitInfo| cloned.lock().push(info.clone()))),
+            ..Default::default()
+        };
+        let db = DBInner::open(path, opt).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..1000 {
+            tx.put(format!("{:04}", i).as_bytes(), b"v").unwrap();
+        }
+        let e = events.clone();
+        tx.on_commit(move || e.lock().push("commit"));
+        let e = events.clone();
+        tx.on_rollback(move || e.lock().push("rollback"));
+        let txid = tx.id();
+        tx.commit().unwrap();
+        assert_eq!(*events.lock(), vec!["commit"]);
+        {
+            let infos = infos.lock();
+            assert_eq!(infos.len(), 1);
+            assert_eq!(infos[0].txid, txid);
+            assert_eq!(infos[0].keys.len(), 1000);
+        }
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"zzz", b"v").unwrap();
+        let e = events.clone();
+        tx.on_commit(move || e.lock().push("commit"));
+        let e = events.clone();
+        tx.on_rollback(move || e.lock().push("rollback"));
+        tx.rollback().unwrap();
+        assert_eq!(*events.lock(), vec!["commit", "rollback"]);
+        assert_eq!(infos.lock().len(), 1);
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.delete(b"0005").unwrap();
+        tx.delete_range(&b"0100"[..]..&b"0200"[..]).unwrap();
+        tx.commit().unwrap();
+        let infos = infos.lock();
+        assert_eq!(infos.len(), 2);
+        assert_eq!(infos[1].keys.len(), 101);
+        assert_eq!(infos[1].keys[0], b"0005");
+        assert_eq!(infos[1].keys[1], b"0100");
+    }
+
+    fn rand(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    #[test]
+    fn test_reopen_random_ops() {
+        let path = std::env::temp_dir().join("rultdb_test_reopen_random_ops.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut model = std::collections::BTreeMap::new();
+        let mut seed = 88172645463325252u64;
+        {
+            let db = DBInner::open(path, DEFAULT_OPTIONS).unwrap();
+            for round in 0..40 {
+                let mut tx = db.begin_rwtx().unwrap();
+                for _ in 0..500 {
+                    let k = format!("k{:06}", rand(&mut seed) % 10000);
+                    if rand(&mut seed) % 3 == 0 {
+                        tx.delete(k.as_bytes()).unwrap();
+                        model.remove(&k);
+                    } else {
+                        let v = vec![round as u8; (rand(&mut seed) % 300) as usize];
+                        tx.put(k.as_bytes(), &v).unwrap();
+                        model.insert(k, v);
+                    }
+                }
+                tx.commit().unwrap();
+            }
+        }
+
+        let db = DBInner::open(path, DEFAULT_OPTIONS).unwrap();
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        for i in 0..10000 {
+            let k = format!("k{:06}", i);
+            assert_eq!(tx.get(k.as_bytes()).unwrap(), model.get(&k).map(|v| v.as_slice()));
+        }
+        assert_eq!(tx.stats().key_n, model.len());
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let db = temp_db("rultdb_test_delete_range.db");
+        let mut model = std::collections::BTreeMap::new();
+        let mut seed = 88172645463325252u64;
+        for round in 0..30 {
+            let mut tx = db.begin_rwtx().unwrap();
+            for _ in 0..1000 {
+                let k = format!("k{:06}", rand(&mut seed) % 20000);
+                tx.put(k.as_bytes(), &[round as u8; 40]).unwrap();
+                model.insert(k, vec![round as u8; 40]);
+            }
+            let a = format!("k{:06}", rand(&mut seed) % 20000);
+            let b = format!("k{:06}", rand(&mut seed) % 20000);
+            let (start, end) = if a < b { (a, b) } else { (b, a) };
+            let n = match round % 3 {
+                0 => tx.delete_range(start.as_bytes()..end.as_bytes()).unwrap(),
+                1 => tx.delete_range(start.as_bytes()..=end.as_bytes()).unwrap(),
+                _ => tx.delete_range(..end.as_bytes()).unwrap(),
+            };
+            let before = model.len();
+            model.retain(|k, _| match round % 3 {
+                0 => !(start <= *k && *k < end),
+                1 => !(start <= *k && *k <= end),
+                _ => *k >= end,
+            });
+            assert_eq!(n as usize, before - model.len());
+            tx.commit().unwrap();
+
+            let mut tx = db.begin_tx();
+            assert!(tx.check().is_empty(), "{:?}", tx.check());
+            assert_eq!(tx.stats().key_n, model.len());
+            for k in [&start, &end] {
+                assert_eq!(tx.get(k.as_bytes()).unwrap(), model.get(k).map(|v| v.as_slice()));
+            }
+            tx.close().unwrap();
+        }
+
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.clear().unwrap() as usize, model.len());
+        tx.put(b"after", b"clear").unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.stats().key_n, 1);
+        assert_eq!(tx.get(b"after").unwrap(), Some(&b"clear"[..]));
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_cursor_iterate() {
+        let db = temp_db("rultdb_test_cursor_iterate.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..2000 {
+            tx.put(format!("{:05}", i).as_bytes(), b"value").unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_tx();
+        let mut c = tx.cursor();
+        let mut item = c.first().unwrap();
+        let mut i = 0;
+        while let Some(k) = item.key() {
+            assert_eq!(k, format!("{:05}", i).as_bytes());
+            i += 1;
+            item = c.next().unwrap();
+        }
+        assert_eq!(i, 2000);
+        assert_eq!(c.seek(b"01500").unwrap().key(), Some(&b"01500"[..]));
+        assert_eq!(c.next().unwrap().key(), Some(&b"01501"[..]));
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_remap_waits_for_readers() {
+        let db = temp_db("rultdb_test_remap_waits_for_readers.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"key", b"value").unwrap();
+        tx.commit().unwrap();
+
+        // 写事务扩大 mmap 时等待读事务结束，读事务中的页面不会失效
+        let mut reader = db.begin_tx();
+        let db2 = db.clone();
+        let writer = thread::spawn(move || {
+            let mut tx = db2.begin_rwtx().unwrap();
+            for i in 0..5000 {
+                tx.put(format!("{:05}", i).as_bytes(), &[0u8; 100]).unwrap();
+            }
+            tx.commit().unwrap();
+        });
+        for _ in 0..20 {
+            assert_eq!(reader.get(b"key").unwrap(), Some(&b"value"[..]));
+            sleep(Duration::from_millis(5));
+        }
+        reader.close().unwrap();
+        writer.join().unwrap();
+
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"04999").unwrap(), Some(&[0u8; 100][..]));
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = std::env::temp_dir().join("rultdb_test_read_only.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        {
+            let db = DBInner::open(path, DEFAULT_OPTIONS).unwrap();
+            let mut tx = db.begin_rwtx().unwrap();
+            tx.put(b"foo", b"bar").unwrap();
+            tx.commit().unwrap();
+        }
+        let db = DBInner::open(path, Options { read_only: true, ..Default::default() }).unwrap();
+        assert!(matches!(db.begin_rwtx(), Err(Error::ErrDatabaseReadOnly)));
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"foo").unwrap(), Some(&b"bar"[..]));
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_write_to() {
+        let db = temp_db("rultdb_test_write_to.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..1000 {
+            tx.put(format!("{:04}", i).as_bytes(), &[1u8; 64]).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let path = std::env::temp_dir().join("rultdb_test_write_to_backup.db");
+        let tx = db.begin_tx();
+        let mut f = File::create(&path).unwrap();
+        let n = tx.write_to(&mut f).unwrap();
+        assert_eq!(n, f.metadata().unwrap().len());
+        tx.close().unwrap();
+
+        let backup = DBInner::open(path.to_str().unwrap(), DEFAULT_OPTIONS).unwrap();
+        let mut tx = backup.begin_tx();
+        assert!(tx.check().is_empty());
+        assert_eq!(tx.get(b"0999").unwrap(), Some(&[1u8; 64][..]));
+        tx.rollback().unwrap();
+    }
+
+    // 提交两个事务后破坏最新的事务写入的根页面，返回文件路径和根页面
+    fn corrupt_newest_root(name: &str) -> (String, PgId) {
+        let path = std::env::temp_dir().join(name).to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let db = DBInner::open(&path, DEFAULT_OPTIONS).unwrap();
+        for k in [b"old", b"new"] {
+            let mut tx = db.begin_rwtx().unwrap();
+            tx.put(k, b"value").unwrap();
+            tx.commit().unwrap();
+        }
+        let root = db.begin_tx().meta().root;
+        db.0.write_at(&vec![0xFF; PAGE_SIZE], root * PAGE_SIZE as u64).unwrap();
+        (path, root)
+    }
+
+    #[test]
+    fn test_recovery_checksum_fallback() {
+        let path = std::env::temp_dir().join("rultdb_test_recovery_checksum.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let txid = {
+            let db = DBInner::open(path, DEFAULT_OPTIONS).unwrap();
+            let mut tx = db.begin_rwtx().unwrap();
+            tx.put(b"foo", b"bar").unwrap();
+            tx.commit().unwrap();
+            let txid = tx.id();
+            // 破坏最新 meta 的 checksum
+            let mut buf = vec![0u8; PAGE_SIZE];
+            db.0.file.read().read_exact_at(&mut buf, txid % 2 * PAGE_SIZE as u64).unwrap();
+            Page::page_in_buffer_mut(&mut buf, 0).meta_mut().checksum ^= 1;
+            db.0.write_at(&buf, txid % 2 * PAGE_SIZE as u64).unwrap();
+            txid
+        };
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let e = events.clone();
+        let opt = Options {
+            on_recovery: Some(Arc::new(move |f: &MetaFallback| e.lock().push((f.meta_page, f.txid, f.fallback_txid)))),
+            ..Default::default()
+        };
+        let db = DBInner::open(path, opt).unwrap();
+        assert_eq!(events.lock().as_slice(), &[(txid % 2, txid, txid - 1)]);
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.id(), txid - 1);
+        assert_eq!(tx.get(b"foo").unwrap(), None);
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_recovery_verify() {
+        let (path, _) = corrupt_newest_root("rultdb_test_recovery_verify.db");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let e = events.clone();
+        let opt = Options {
+            recovery: Recovery::Verify,
+            on_recovery: Some(Arc::new(move |f: &MetaFallback| e.lock().push(f.reason.to_string()))),
+            ..Default::default()
+        };
+        {
+            let db = DBInner::open(&path, opt).unwrap();
+            assert_eq!(events.lock().len(), 1);
+            let mut tx = db.begin_rwtx().unwrap();
+            assert_eq!(tx.get(b"old").unwrap(), Some(&b"value"[..]));
+            assert_eq!(tx.get(b"new").unwrap(), None);
+            tx.put(b"after", b"recovery").unwrap();
+            tx.commit().unwrap();
+        }
+
+        // 被放弃的 meta 已经被新的事务覆盖
+        let opt = Options { recovery: Recovery::Strict, ..Default::default() };
+        let db = DBInner::open(&path, opt).unwrap();
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"after").unwrap(), Some(&b"recovery"[..]));
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_recovery_strict() {
+        let (path, root) = corrupt_newest_root("rultdb_test_recovery_strict.db");
+        let opt = Options { recovery: Recovery::Strict, ..Default::default() };
+        match DBInner::open(&path, opt) {
+            Err(Error::ErrCorrupted { pgid }) => assert_eq!(pgid, root),
+            _ => panic!("expected corrupted error"),
+        }
+    }
+
+    #[test]
+    fn test_page_checksum() {
+        let path = std::env::temp_dir().join("rultdb_test_page_checksum.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let leaf = {
+            let db = DBInner::open(path, DEFAULT_OPTIONS).unwrap();
+            let mut tx = db.begin_rwtx().unwrap();
+            for i in 0..100 {
+                tx.put(format!("{:04}", i).as_bytes(), &[7u8; 100]).unwrap();
+            }
+            tx.commit().unwrap();
+            let mut tx = db.begin_tx();
+            assert!(tx.check().is_empty(), "{:?}", tx.check());
+            let leaf = tx
+                .pages()
+                .into_iter()
+                .map(|p| p.id)
+                .find(|&id| matches!(tx.page_contents(id), Ok(PageContents::Leaf(e)) if e.first().is_some_and(|(k, _)| k == b"0000")))
+                .unwrap();
+            tx.close().unwrap();
+            // 修改叶子页面最后一个字节，页面结构不变但 checksum 不再匹配
+            let offset = (leaf + 1) * PAGE_SIZE as u64 - 1;
+            let mut b = [0u8];
+            db.0.file.read().read_exact_at(&mut b, offset).unwrap();
+            db.0.write_at(&[b[0] ^ 1], offset).unwrap();
+            leaf
+        };
+
+        let db = DBInner::open(path, DEFAULT_OPTIONS).unwrap();
+        let tx = db.begin_tx();
+        assert_eq!(tx.check(), vec![format!("page {}: checksum mismatch", leaf)]);
+        tx.close().unwrap();
+        // 默认只在修改页面时校验
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.get(b"0000").unwrap(), Some(&[7u8; 100][..]));
+        assert!(matches!(tx.put(b"0000", b"new"), Err(Error::ErrCorrupted { pgid }) if pgid == leaf));
+        tx.rollback().unwrap();
+        drop(db);
+
+        let db = DBInner::open(path, Options { verify_checksums: true, ..Default::default() }).unwrap();
+        let mut tx = db.begin_tx();
+        assert!(matches!(tx.get(b"0000"), Err(Error::ErrCorrupted { pgid }) if pgid == leaf));
+        assert_eq!(tx.get(b"0099").unwrap(), Some(&[7u8; 100][..]));
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_append_mode() {
+        let leaf_usage = |name: &str, append: bool| {
+            let db = temp_db(name);
+            for batch in 0..20 {
+                let mut tx = db.begin_rwtx().unwrap();
+                tx.set_append_mode(append);
+                for i in 0..500 {
+                    let k = format!("{:08}", batch * 500 + i);
+                    tx.put(k.as_bytes(), &[1u8; 32]).unwrap();
+                    assert_eq!(tx.get(k.as_bytes()).unwrap(), Some(&[1u8; 32][..]));
+                }
+                // 不是递增的 key 同样可以写入
+                tx.put(b"0", b"first").unwrap();
+                tx.commit().unwrap();
+            }
+            let mut tx = db.begin_tx();
+            assert!(tx.check().is_empty(), "{:?}", tx.check());
+            let stats = tx.stats();
+            assert_eq!(stats.key_n, 10001);
+            assert_eq!(tx.get(b"00009999").unwrap(), Some(&[1u8; 32][..]));
+            assert_eq!(tx.get(b"0").unwrap(), Some(&b"first"[..]));
+            tx.close().unwrap();
+            stats.leaf_inuse as f64 / stats.leaf_alloc as f64
+        };
+        let normal = leaf_usage("rultdb_test_append_mode_off.db", false);
+        let append = leaf_usage("rultdb_test_append_mode_on.db", true);
+        assert!(normal < 0.7, "{}", normal);
+        assert!(append > 0.9, "{}", append);
+    }
+
     //#[test]
     //fn test_db_print() {
         //let mut db = DBImpl::open("./test.db", DEFAULT_OPTIONS).unwrap();
@@ -0,0 +1,421 @@
+//! 与页面格式无关的逻辑导出格式，用于在不同环境和不同文件格式版本之间迁移数据。
+//!
+//! 二进制格式，所有整数都是小端序：
+//!
+//! ```text
+//! header:  magic "RULTDUMP" (8 字节) | version: u32 | txid: u64
+//! record:  key_len: u32 (> 0) | key | value_len: u32 | value
+//! trailer: 0: u32 | count: u64 | checksum: u32
+//! ```
+//!
+//! record 按 key 升序排列且 key 不重复，txid 是导出时事务看到的 meta txid，
+//! checksum 是它之前所有字节的 crc32。
+//!
+//! 文本格式每行一个 JSON 对象，key 和 value 使用十六进制编码：
+//!
+//! ```text
+//! {"rultdb_dump":1,"txid":5}
+//! {"key":"6b6579","value":"76616c7565"}
+//! {"count":1,"checksum":"1a2b3c4d"}
+//! ```
+//!
+//! 文本格式的 checksum 按照相同内容的二进制格式计算，两种格式可以互相转换而不改变 checksum。
+//!
+//! 只导出普通的用户 key。保存在系统 key 中的 TTL、一个 key 的多个值（put_dup）、表和二级索引的项，
+//! 以及 meta 中的序列号都不会导出：导入之后的 key 不会过期，需要重新写入多值 key 和表并调用 Tx::rebuild_index。
+//! 需要完整复制数据库时使用 rultdb compact 或者 Tx::write_to。
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::{
+    bulk::BulkLoader,
+    db::DB,
+    error::{Error, Result},
+    tx::{Tx, TxId},
+    MAX_KEY_SIZE, MAX_VALUE_SIZE,
+};
+
+pub const DUMP_MAGIC: &[u8; 8] = b"RULTDUMP";
+pub const DUMP_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    Binary,
+    Text,
+}
+
+impl Tx {
+    /// 以二进制格式导出当前事务看到的所有用户 key/value，返回导出的 key 数量。
+    /// 不包括 TTL、多值 key、表、索引和序列号，见模块说明
+    pub fn export(&self, w: &mut impl Write) -> Result<u64> {
+        self.export_as(w, DumpFormat::Binary)
+    }
+
+    pub fn export_as(&self, w: &mut impl Write, format: DumpFormat) -> Result<u64> {
+        let mut dw = DumpWriter { w, format, hasher: crc32fast::Hasher::new(), count: 0 };
+        dw.header(self.meta().txid)?;
+        let mut c = self.cursor();
+        let mut item = c.first()?;
+        while let (Some(k), Some(v)) = (item.key(), item.value()) {
+            dw.record(k, v)?;
+            item = c.next()?;
+        }
+        dw.finish()
+    }
+}
+
+impl DB {
+    /// 导入 export 导出的数据（自动识别二进制和文本格式）到一个空的数据库。
+    /// 通过 BulkLoader 在同一个写事务中写入，checksum 校验失败时不会写入任何数据
+    pub fn import(&self, r: &mut impl Read) -> Result<u64> {
+        let mut tx = self.begin_rwtx()?;
+        match import_tx(&mut tx, r) {
+            Ok(count) => {
+                tx.commit()?;
+                Ok(count)
+            }
+            Err(e) => {
+                tx.rollback()?;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn import_tx(tx: &mut Tx, r: &mut impl Read) -> Result<u64> {
+    let mut loader = BulkLoader::new(tx)?;
+    let mut dr = DumpReader::new(r)?;
+    while let Some((k, v)) = dr.next()? {
+        loader.add(&k, &v)?;
+    }
+    loader.finish()?;
+    Ok(dr.count)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::ErrInvalidDump(msg.to_string())
+}
+
+struct DumpWriter<'a, W: Write> {
+    w: &'a mut W,
+    format: DumpFormat,
+    hasher: crc32fast::Hasher,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<'_, W> {
+    fn header(&mut self, txid: TxId) -> Result<()> {
+        let mut buf = DUMP_MAGIC.to_vec();
+        buf.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+        buf.extend_from_slice(&txid.to_le_bytes());
+        self.hasher.update(&buf);
+        match self.format {
+            DumpFormat::Binary => self.write(&buf),
+            DumpFormat::Text => self.write(format!("{{\"rultdb_dump\":{},\"txid\":{}}}\n", DUMP_VERSION, txid).as_bytes()),
+        }
+    }
+
+    fn record(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(key.len() + value.len() + 8);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value);
+        self.hasher.update(&buf);
+        self.count += 1;
+        match self.format {
+            DumpFormat::Binary => self.write(&buf),
+            DumpFormat::Text => self.write(format!("{{\"key\":\"{}\",\"value\":\"{}\"}}\n", hex(key), hex(value)).as_bytes()),
+        }
+    }
+
+    fn finish(mut self) -> Result<u64> {
+        let mut buf = 0u32.to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        self.hasher.update(&buf);
+        let checksum = self.hasher.clone().finalize();
+        match self.format {
+            DumpFormat::Binary => {
+                buf.extend_from_slice(&checksum.to_le_bytes());
+                self.write(&buf)?;
+            }
+            DumpFormat::Text => {
+                self.write(format!("{{\"count\":{},\"checksum\":\"{:08x}\"}}\n", self.count, checksum).as_bytes())?
+            }
+        }
+        self.w.flush().map_err(|e| ("can't write dump", e))?;
+        Ok(self.count)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.w.write_all(buf).map_err(|e| ("can't write dump", e))?;
+        Ok(())
+    }
+}
+
+struct DumpReader<'a> {
+    r: Box<dyn BufRead + 'a>,
+    format: DumpFormat,
+    hasher: crc32fast::Hasher,
+    count: u64,
+    done: bool,
+}
+
+impl<'a> DumpReader<'a> {
+    fn new(r: &'a mut impl Read) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic).map_err(|_| invalid("missing header"))?;
+        let (format, prefix) = if &magic == DUMP_MAGIC {
+            (DumpFormat::Binary, Vec::new())
+        } else if magic[0] == b'{' {
+            // 文本格式需要重新读取已经读出的内容
+            (DumpFormat::Text, magic.to_vec())
+        } else {
+            return Err(invalid("unknown format"));
+        };
+        let mut dr = DumpReader {
+            r: Box::new(BufReader::new(std::io::Cursor::new(prefix).chain(r))),
+            format,
+            hasher: crc32fast::Hasher::new(),
+            count: 0,
+            done: false,
+        };
+        dr.header()?;
+        Ok(dr)
+    }
+
+    fn header(&mut self) -> Result<()> {
+        let (version, txid) = match self.format {
+            DumpFormat::Binary => {
+                let mut buf = [0u8; 12];
+                self.read(&mut buf)?;
+                (u32::from_le_bytes(buf[..4].try_into().unwrap()), u64::from_le_bytes(buf[4..].try_into().unwrap()))
+            }
+            DumpFormat::Text => {
+                let line = self.line()?;
+                (json_number(&line, "rultdb_dump")? as u32, json_number(&line, "txid")?)
+            }
+        };
+        if version != DUMP_VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        self.hasher.update(DUMP_MAGIC);
+        self.hasher.update(&version.to_le_bytes());
+        self.hasher.update(&txid.to_le_bytes());
+        Ok(())
+    }
+
+    /// 读取下一个 key/value，读到结尾时校验 count 和 checksum 并返回 None
+    fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let (key, value) = match self.format {
+            DumpFormat::Binary => {
+                let key = self.read_bytes(MAX_KEY_SIZE)?;
+                if key.is_empty() {
+                    let mut buf = [0u8; 12];
+                    self.read(&mut buf)?;
+                    let count = u64::from_le_bytes(buf[..8].try_into().unwrap());
+                    return self.finish(count, u32::from_le_bytes(buf[8..].try_into().unwrap())).map(|_| None);
+                }
+                (key, self.read_bytes(MAX_VALUE_SIZE)?)
+            }
+            DumpFormat::Text => {
+                let line = self.line()?;
+                if line.contains("\"checksum\"") {
+                    let checksum = u32::from_str_radix(json_string(&line, "checksum")?, 16)
+                        .map_err(|_| invalid("invalid checksum"))?;
+                    self.hasher.update(&0u32.to_le_bytes());
+                    return self.finish(json_number(&line, "count")?, checksum).map(|_| None);
+                }
+                let key = unhex(json_string(&line, "key")?)?;
+                let value = unhex(json_string(&line, "value")?)?;
+                if key.is_empty() {
+                    return Err(invalid("empty key"));
+                }
+                for b in [&key, &value] {
+                    self.hasher.update(&(b.len() as u32).to_le_bytes());
+                    self.hasher.update(b);
+                }
+                (key, value)
+            }
+        };
+        self.count += 1;
+        Ok(Some((key, value)))
+    }
+
+    // 结尾的 0 已经计入 hasher
+    fn finish(&mut self, count: u64, checksum: u32) -> Result<()> {
+        self.hasher.update(&count.to_le_bytes());
+        if count != self.count {
+            return Err(invalid(&format!("record count {} does not match trailer count {}", self.count, count)));
+        }
+        if checksum != self.hasher.clone().finalize() {
+            return Err(Error::ErrChecksum);
+        }
+        self.done = true;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.r.read_exact(buf).map_err(|_| invalid("unexpected end of dump"))
+    }
+
+    // 长度在校验 checksum 之前读出，先检查上限，并且按实际读到的数据分配内存
+    fn read_bytes(&mut self, max: usize) -> Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        self.read(&mut len)?;
+        self.hasher.update(&len);
+        let len = u32::from_le_bytes(len) as usize;
+        if len > max {
+            return Err(invalid(&format!("record length {} exceeds {}", len, max)));
+        }
+        let mut buf = Vec::new();
+        match (&mut self.r).take(len as u64).read_to_end(&mut buf) {
+            Ok(n) if n == len => {}
+            _ => return Err(invalid("unexpected end of dump")),
+        }
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+
+    fn line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        match self.r.read_line(&mut line) {
+            Ok(0) | Err(_) => Err(invalid("unexpected end of dump")),
+            Ok(_) => Ok(line),
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| {
+            std::str::from_utf8(c)
+                .ok()
+                .filter(|c| c.len() == 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or_else(|| invalid("invalid hex string"))
+        })
+        .collect()
+}
+
+// 只解析导出时写出的 JSON：字段值是不含转义的字符串或者非负整数
+fn json_value<'a>(line: &'a str, name: &str) -> Result<&'a str> {
+    let field = format!("\"{}\"", name);
+    let start = line.find(&field).ok_or_else(|| invalid(&format!("missing field {}", name)))?;
+    let rest = line[start + field.len()..].trim_start();
+    let rest = rest.strip_prefix(':').ok_or_else(|| invalid("invalid json"))?.trim_start();
+    let end = rest.find([',', '}']).ok_or_else(|| invalid("invalid json"))?;
+    Ok(rest[..end].trim_end())
+}
+
+fn json_string<'a>(line: &'a str, name: &str) -> Result<&'a str> {
+    let v = json_value(line, name)?;
+    v.strip_prefix('"').and_then(|v| v.strip_suffix('"')).ok_or_else(|| invalid(&format!("field {} is not a string", name)))
+}
+
+fn json_number(line: &str, name: &str) -> Result<u64> {
+    json_value(line, name)?.parse().map_err(|_| invalid(&format!("field {} is not a number", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::temp_db;
+
+    fn fill(db: &DB, n: usize) {
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..n {
+            tx.put(format!("key{:05}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        tx.put(b"bin\x00\xff", b"").unwrap();
+        tx.commit().unwrap();
+    }
+
+    fn export(db: &DB, format: DumpFormat) -> Vec<u8> {
+        let tx = db.begin_tx();
+        let mut buf = Vec::new();
+        assert_eq!(tx.export_as(&mut buf, format).unwrap(), 1001);
+        tx.close().unwrap();
+        buf
+    }
+
+    fn keys(db: &DB) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let tx = db.begin_tx();
+        let mut c = tx.cursor();
+        let mut item = c.first().unwrap();
+        let mut pairs = Vec::new();
+        while let (Some(k), Some(v)) = (item.key(), item.value()) {
+            pairs.push((k.to_vec(), v.to_vec()));
+            item = c.next().unwrap();
+        }
+        tx.close().unwrap();
+        pairs
+    }
+
+    #[test]
+    fn test_export_import() {
+        let src = temp_db("rultdb_test_dump_src.db");
+        fill(&src, 1000);
+        for (i, format) in [DumpFormat::Binary, DumpFormat::Text].into_iter().enumerate() {
+            let buf = export(&src, format);
+            let dst = temp_db(&format!("rultdb_test_dump_dst{}.db", i));
+            assert_eq!(dst.import(&mut buf.as_slice()).unwrap(), 1001);
+            assert_eq!(keys(&dst), keys(&src));
+            // 只能导入到空的数据库
+            assert!(dst.import(&mut buf.as_slice()).is_err());
+        }
+
+        // 两种格式的 checksum 相同
+        let bin = export(&src, DumpFormat::Binary);
+        let text = String::from_utf8(export(&src, DumpFormat::Text)).unwrap();
+        let checksum = u32::from_le_bytes(bin[bin.len() - 4..].try_into().unwrap());
+        assert!(text.ends_with(&format!("\"checksum\":\"{:08x}\"}}\n", checksum)));
+    }
+
+    #[test]
+    fn test_import_corrupted() {
+        let src = temp_db("rultdb_test_dump_corrupted.db");
+        fill(&src, 1000);
+        let mut bin = export(&src, DumpFormat::Binary);
+        let pos = bin.windows(8).position(|w| w == b"value500").unwrap();
+        bin[pos + 7] ^= 1;
+        let dst = temp_db("rultdb_test_dump_corrupted_dst.db");
+        assert!(matches!(dst.import(&mut bin.as_slice()), Err(Error::ErrChecksum)));
+        let text = String::from_utf8(export(&src, DumpFormat::Text)).unwrap();
+        let truncated = &text[..text.len() / 2];
+        assert!(matches!(dst.import(&mut truncated.as_bytes()), Err(Error::ErrInvalidDump(_))));
+
+        // 超过上限的长度在分配内存之前被拒绝，截断的长度不会分配整个长度
+        let header = &export(&src, DumpFormat::Binary)[..20];
+        let mut huge = header.to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(dst.import(&mut huge.as_slice()), Err(Error::ErrInvalidDump(m)) if m.contains("exceeds")));
+        let mut cut = header.to_vec();
+        cut.extend_from_slice(&1u32.to_le_bytes());
+        cut.push(b'k');
+        cut.extend_from_slice(&(1u32 << 30).to_le_bytes());
+        cut.extend_from_slice(b"short");
+        assert!(matches!(dst.import(&mut cut.as_slice()), Err(Error::ErrInvalidDump(m)) if m.contains("end of dump")));
+
+        // 导入的数据不能写入内部使用的系统 key
+        let empty = temp_db("rultdb_test_dump_reserved.db");
+        let tx = empty.begin_tx();
+        let mut buf = Vec::new();
+        tx.export_as(&mut buf, DumpFormat::Text).unwrap();
+        tx.close().unwrap();
+        let mut text = String::from_utf8(buf).unwrap();
+        let trailer = text.split_inclusive('\n').last().unwrap().to_string();
+        text.truncate(text.len() - trailer.len());
+        text.push_str(&format!("{{\"key\":\"{}\",\"value\":\"\"}}\n{}", hex(b"\xffrultdb\x00exp\x00k"), trailer));
+        assert!(matches!(dst.import(&mut text.as_bytes()), Err(Error::ErrReservedKey)));
+        assert!(keys(&dst).is_empty());
+    }
+}
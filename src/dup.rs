@@ -0,0 +1,443 @@
+use crate::{
+    config::PAGE_SIZE,
+    cursor::Cursor,
+    error::{Error, Result},
+    keys::{self, PrefixRange},
+    table::get_raw,
+    tx::Tx,
+    MAX_KEY_SIZE,
+};
+
+// 一个 key 对应多个按字节顺序排列的值（类似 LMDB 的 DUPSORT），与普通的 key 互不影响。
+// 系统 key "duh" + key 保存头部：值较少时所有值直接保存在头部中，
+// 超过 MAX_INLINE_DUP 字节之后提升为子树，每个值保存为一个系统 key "duv" + 编码后的 key + 值，
+// 头部只记录值的数量。子树中的值全部删除之前不会再回到头部中
+const HEAD_PREFIX: &[u8] = b"\xffrultdb\x00duh\x00";
+const VALUE_PREFIX: &[u8] = b"\xffrultdb\x00duv\x00";
+
+const MAX_INLINE_DUP: usize = PAGE_SIZE / 4;
+
+// 头部的第一个字节
+const INLINE: u8 = 0;
+const PROMOTED: u8 = 1;
+
+enum Head<'a> {
+    // 按顺序排列的值
+    Inline(Vec<&'a [u8]>),
+    Promoted(u64),
+}
+
+fn head_key(key: &[u8]) -> Vec<u8> {
+    [HEAD_PREFIX, key].concat()
+}
+
+fn value_prefix(key: &[u8]) -> Vec<u8> {
+    let mut k = VALUE_PREFIX.to_vec();
+    keys::Encode::encode_to(key, &mut k);
+    k
+}
+
+fn value_key(key: &[u8], value: &[u8]) -> Vec<u8> {
+    [value_prefix(key), value.to_vec()].concat()
+}
+
+fn corrupted() -> Error {
+    Error::Unexpected("invalid duplicate head".to_string())
+}
+
+fn decode_head(v: &[u8]) -> Result<Head<'_>> {
+    match v.split_first() {
+        Some((&INLINE, mut rest)) => {
+            let mut values = Vec::new();
+            while !rest.is_empty() {
+                let len = u32::from_be_bytes(rest.get(..4).ok_or_else(corrupted)?.try_into().unwrap()) as usize;
+                values.push(rest.get(4..4 + len).ok_or_else(corrupted)?);
+                rest = &rest[4 + len..];
+            }
+            Ok(Head::Inline(values))
+        }
+        Some((&PROMOTED, n)) => Ok(Head::Promoted(u64::from_be_bytes(n.try_into().map_err(|_| corrupted())?))),
+        _ => Err(corrupted()),
+    }
+}
+
+fn encode_inline(values: &[&[u8]]) -> Vec<u8> {
+    let mut v = vec![INLINE];
+    for value in values {
+        v.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        v.extend_from_slice(value);
+    }
+    v
+}
+
+fn encode_promoted(count: u64) -> Vec<u8> {
+    [&[PROMOTED][..], &count.to_be_bytes()].concat()
+}
+
+impl Tx {
+    /// 给 key 增加一个值，返回是否增加。key 已经有这个值时不修改
+    pub fn put_dup(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        } else if key.is_empty() {
+            return Err(Error::ErrKeyRequired);
+        } else if head_key(key).len() > MAX_KEY_SIZE || value_key(key, value).len() > MAX_KEY_SIZE {
+            // 提升为子树之后值是 key 的一部分
+            return Err(Error::ErrKeyTooLarge);
+        }
+        let hk = head_key(key);
+        match get_raw(self, &hk)?.map(decode_head).transpose()? {
+            None => self.put_raw(&hk, &encode_inline(&[value]))?,
+            Some(Head::Inline(mut values)) => {
+                let Err(i) = values.binary_search(&value) else {
+                    return Ok(false);
+                };
+                values.insert(i, value);
+                let head = encode_inline(&values);
+                if head.len() <= MAX_INLINE_DUP {
+                    self.put_raw(&hk, &head)?;
+                } else {
+                    let keys: Vec<Vec<u8>> = values.iter().map(|v| value_key(key, v)).collect();
+                    for k in keys.iter() {
+                        self.put_raw(k, &[])?;
+                    }
+                    self.put_raw(&hk, &encode_promoted(keys.len() as u64))?;
+                }
+            }
+            Some(Head::Promoted(n)) => {
+                let vk = value_key(key, value);
+                if get_raw(self, &vk)?.is_some() {
+                    return Ok(false);
+                }
+                self.put_raw(&vk, &[])?;
+                self.put_raw(&hk, &encode_promoted(n + 1))?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// 删除 key 的一个值，返回值是否存在。删除最后一个值之后 key 不再存在
+    pub fn delete_dup(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        let hk = head_key(key);
+        match get_raw(self, &hk)?.map(decode_head).transpose()? {
+            None => return Ok(false),
+            Some(Head::Inline(mut values)) => {
+                let Ok(i) = values.binary_search(&value) else {
+                    return Ok(false);
+                };
+                values.remove(i);
+                match values.is_empty() {
+                    true => self.delete_raw(&hk)?,
+                    false => self.put_raw(&hk, &encode_inline(&values))?,
+                }
+            }
+            Some(Head::Promoted(n)) => {
+                let vk = value_key(key, value);
+                if get_raw(self, &vk)?.is_none() {
+                    return Ok(false);
+                }
+                self.delete_raw(&vk)?;
+                match n <= 1 {
+                    true => self.delete_raw(&hk)?,
+                    false => self.put_raw(&hk, &encode_promoted(n - 1))?,
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// 删除 key 的所有值，返回删除的数量
+    pub fn delete_dups(&mut self, key: &[u8]) -> Result<u64> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        let hk = head_key(key);
+        let count = match get_raw(self, &hk)?.map(decode_head).transpose()? {
+            None => return Ok(0),
+            Some(Head::Inline(values)) => values.len() as u64,
+            Some(Head::Promoted(n)) => {
+                let range = PrefixRange::new(&value_prefix(key));
+                let mut keys = Vec::new();
+                let mut c = self.raw_cursor();
+                let mut item = c.seek(&range.start)?;
+                while let Some(k) = item.key().filter(|k| range.contains(k)) {
+                    keys.push(k.to_vec());
+                    item = c.next()?;
+                }
+                for k in keys.iter() {
+                    self.delete_raw(k)?;
+                }
+                n
+            }
+        };
+        self.delete_raw(&hk)?;
+        Ok(count)
+    }
+
+    /// key 的值的数量，不需要遍历子树
+    pub fn count_dup(&self, key: &[u8]) -> Result<u64> {
+        Ok(match get_raw(self, &head_key(key))?.map(decode_head).transpose()? {
+            None => 0,
+            Some(Head::Inline(values)) => values.len() as u64,
+            Some(Head::Promoted(n)) => n,
+        })
+    }
+
+    /// 遍历多值 key 的游标，按 key 和值的字节顺序排列
+    pub fn dup_cursor(&self) -> DupCursor {
+        DupCursor { tx: self.clone(), heads: self.raw_cursor(), key: None, pos: None }
+    }
+}
+
+// 当前 key 中的位置
+enum DupPos {
+    Inline { values: Vec<Vec<u8>>, index: usize },
+    Tree { cursor: Cursor, prefix: Vec<u8>, value: Vec<u8> },
+}
+
+/// Tx::dup_cursor 返回的游标。返回 None 时 first_dup、next_dup 等方法不移动游标
+pub struct DupCursor {
+    tx: Tx,
+    heads: Cursor,
+    key: Option<Vec<u8>>,
+    pos: Option<DupPos>,
+}
+
+impl DupCursor {
+    /// 移动到第一个 key 的第一个值
+    pub fn first(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.seek(&[])
+    }
+
+    /// 移动到第一个大于等于 key 的 key 的第一个值
+    pub fn seek(&mut self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let item = self.heads.seek(&head_key(key))?;
+        self.enter(item.key(), item.value())
+    }
+
+    /// 移动到 key 中第一个大于等于 value 的值，key 不存在或者没有这样的值时返回 None
+    pub fn seek_dup(&mut self, key: &[u8], value: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.seek(key)?.is_none() || self.key.as_deref() != Some(key) {
+            return Ok(None);
+        }
+        match self.pos.as_mut().unwrap() {
+            DupPos::Inline { values, index } => match values.iter().position(|v| v.as_slice() >= value) {
+                Some(i) => *index = i,
+                None => return Ok(None),
+            },
+            DupPos::Tree { cursor, prefix, value: current } => {
+                let item = cursor.seek(&[prefix.as_slice(), value].concat())?;
+                match item.key().and_then(|k| k.strip_prefix(prefix.as_slice())) {
+                    Some(v) => *current = v.to_vec(),
+                    None => return Ok(None),
+                }
+            }
+        }
+        Ok(self.current())
+    }
+
+    /// 移动到下一个值，当前 key 没有更多值时移动到下一个 key 的第一个值
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.next_dup()? {
+            Some(kv) => Ok(Some(kv)),
+            None => self.next_key(),
+        }
+    }
+
+    /// 移动到下一个 key 的第一个值
+    pub fn next_key(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.key.is_none() {
+            return Ok(None);
+        }
+        let item = self.heads.next()?;
+        self.enter(item.key(), item.value())
+    }
+
+    pub fn first_dup(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.pos.as_mut() {
+            None => return Ok(None),
+            Some(DupPos::Inline { index, .. }) => *index = 0,
+            Some(DupPos::Tree { cursor, prefix, value }) => {
+                let item = cursor.seek(prefix)?;
+                *value = item.key().and_then(|k| k.strip_prefix(prefix.as_slice())).ok_or_else(corrupted)?.to_vec();
+            }
+        }
+        Ok(self.current())
+    }
+
+    pub fn last_dup(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.pos.as_mut() {
+            None => return Ok(None),
+            Some(DupPos::Inline { values, index }) => *index = values.len() - 1,
+            Some(DupPos::Tree { cursor, prefix, value }) => {
+                // 前缀之后的第一个 key 的前一个 key
+                cursor.seek(&keys::prefix_end(prefix).ok_or_else(corrupted)?)?;
+                let item = cursor.prev_item()?;
+                *value = item.key().and_then(|k| k.strip_prefix(prefix.as_slice())).ok_or_else(corrupted)?.to_vec();
+            }
+        }
+        Ok(self.current())
+    }
+
+    /// 移动到当前 key 的下一个值，已经是最后一个值时返回 None
+    pub fn next_dup(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.step(true)
+    }
+
+    /// 移动到当前 key 的上一个值，已经是第一个值时返回 None
+    pub fn prev_dup(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.step(false)
+    }
+
+    /// 当前 key 的值的数量
+    pub fn count(&self) -> Result<u64> {
+        match &self.key {
+            Some(key) => self.tx.count_dup(key),
+            None => Ok(0),
+        }
+    }
+
+    pub fn current(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let key = self.key.clone()?;
+        match self.pos.as_ref()? {
+            DupPos::Inline { values, index } => Some((key, values[*index].clone())),
+            DupPos::Tree { value, .. } => Some((key, value.clone())),
+        }
+    }
+
+    fn step(&mut self, forward: bool) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.pos.as_mut() {
+            None => return Ok(None),
+            Some(DupPos::Inline { values, index }) => {
+                let next = if forward { *index + 1 } else { index.wrapping_sub(1) };
+                if next >= values.len() {
+                    return Ok(None);
+                }
+                *index = next;
+            }
+            Some(DupPos::Tree { cursor, prefix, value }) => {
+                let item = if forward { cursor.next()? } else { cursor.prev_item()? };
+                match item.key().and_then(|k| k.strip_prefix(prefix.as_slice())) {
+                    Some(v) => *value = v.to_vec(),
+                    None => {
+                        // 回到原来的位置
+                        cursor.seek(&[prefix.as_slice(), value].concat())?;
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+        Ok(self.current())
+    }
+
+    // 进入 heads 游标所在的 key，位于第一个值
+    fn enter(&mut self, head_key: Option<&[u8]>, head: Option<&[u8]>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let (Some(key), Some(head)) = (head_key.and_then(|k| k.strip_prefix(HEAD_PREFIX)), head) else {
+            self.key = None;
+            self.pos = None;
+            return Ok(None);
+        };
+        self.key = Some(key.to_vec());
+        self.pos = Some(match decode_head(head)? {
+            Head::Inline(values) => DupPos::Inline { values: values.iter().map(|v| v.to_vec()).collect(), index: 0 },
+            Head::Promoted(_) => DupPos::Tree { cursor: self.tx.raw_cursor(), prefix: value_prefix(key), value: Vec::new() },
+        });
+        self.first_dup()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::temp_db;
+
+    fn dups(tx: &Tx, key: &[u8]) -> Vec<Vec<u8>> {
+        let mut c = tx.dup_cursor();
+        let mut values = Vec::new();
+        let mut kv = c.seek(key).unwrap().filter(|(k, _)| k == key);
+        while let Some((_, v)) = kv {
+            values.push(v);
+            kv = c.next_dup().unwrap();
+        }
+        values
+    }
+
+    #[test]
+    fn test_dupsort() {
+        let db = temp_db("rultdb_test_dupsort.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        assert!(tx.put_dup(b"a", b"2").unwrap());
+        assert!(tx.put_dup(b"a", b"1").unwrap());
+        assert!(!tx.put_dup(b"a", b"2").unwrap());
+        // 超过 MAX_INLINE_DUP 之后提升为子树
+        for i in 0..2000 {
+            assert!(tx.put_dup(b"b", format!("{:05}", i).as_bytes()).unwrap());
+        }
+        tx.put_dup(b"c", b"x").unwrap();
+        tx.put(b"a", b"plain").unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.get(b"a").unwrap(), Some(&b"plain"[..]));
+        assert_eq!(tx.count_dup(b"a").unwrap(), 2);
+        assert_eq!(tx.count_dup(b"b").unwrap(), 2000);
+        assert_eq!(dups(&tx, b"a"), vec![b"1".to_vec(), b"2".to_vec()]);
+        assert_eq!(dups(&tx, b"b").len(), 2000);
+
+        let mut c = tx.dup_cursor();
+        assert_eq!(c.first().unwrap(), Some((b"a".to_vec(), b"1".to_vec())));
+        assert_eq!(c.prev_dup().unwrap(), None);
+        assert_eq!(c.last_dup().unwrap(), Some((b"a".to_vec(), b"2".to_vec())));
+        assert_eq!(c.next().unwrap(), Some((b"b".to_vec(), b"00000".to_vec())));
+        assert_eq!(c.count().unwrap(), 2000);
+        assert_eq!(c.last_dup().unwrap(), Some((b"b".to_vec(), b"01999".to_vec())));
+        assert_eq!(c.next_dup().unwrap(), None);
+        assert_eq!(c.prev_dup().unwrap(), Some((b"b".to_vec(), b"01998".to_vec())));
+        assert_eq!(c.seek_dup(b"b", b"01000x").unwrap(), Some((b"b".to_vec(), b"01001".to_vec())));
+        assert_eq!(c.prev_dup().unwrap(), Some((b"b".to_vec(), b"01000".to_vec())));
+        assert_eq!(c.first_dup().unwrap(), Some((b"b".to_vec(), b"00000".to_vec())));
+        assert_eq!(c.prev_dup().unwrap(), None);
+        assert_eq!(c.next_dup().unwrap(), Some((b"b".to_vec(), b"00001".to_vec())));
+        assert_eq!(c.next_key().unwrap(), Some((b"c".to_vec(), b"x".to_vec())));
+        assert_eq!(c.next().unwrap(), None);
+
+        assert!(tx.delete_dup(b"a", b"1").unwrap());
+        assert!(!tx.delete_dup(b"a", b"1").unwrap());
+        assert!(tx.delete_dup(b"b", b"00007").unwrap());
+        assert_eq!(tx.count_dup(b"b").unwrap(), 1999);
+        assert!(tx.delete_dup(b"c", b"x").unwrap());
+        assert_eq!(tx.count_dup(b"c").unwrap(), 0);
+        assert_eq!(tx.delete_dups(b"b").unwrap(), 1999);
+        assert_eq!(tx.dup_cursor().first().unwrap(), Some((b"a".to_vec(), b"2".to_vec())));
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.count_dup(b"b").unwrap(), 0);
+        assert_eq!(tx.raw_cursor().seek(VALUE_PREFIX).unwrap().key(), None);
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_dup_key_with_zero() {
+        let db = temp_db("rultdb_test_dup_zero.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..200 {
+            tx.put_dup(b"a", format!("v{:05}", i).as_bytes()).unwrap();
+            tx.put_dup(b"a\0b", format!("w{:05}", i).as_bytes()).unwrap();
+        }
+        // "a" 的子树不能包含 "a\0b" 的值
+        assert_eq!(tx.delete_dups(b"a").unwrap(), 200);
+        assert_eq!(tx.count_dup(b"a\0b").unwrap(), 200);
+        assert_eq!(dups(&tx, b"a\0b").len(), 200);
+        let mut c = tx.dup_cursor();
+        assert_eq!(c.seek_dup(b"a\0b", b"w00100").unwrap(), Some((b"a\0b".to_vec(), b"w00100".to_vec())));
+        assert!(tx.delete_dup(b"a\0b", b"w00000").unwrap());
+        assert_eq!(tx.count_dup(b"a\0b").unwrap(), 199);
+        tx.commit().unwrap();
+    }
+}
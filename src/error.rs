@@ -1,6 +1,8 @@
 use std::{io, ops::Deref};
 
 use thiserror::Error;
+
+use crate::page::PgId;
 #[derive(Error,Debug)]
 pub enum Error {
     #[error("Unexpected: {0}, {1}")]
@@ -25,6 +27,38 @@ pub enum Error {
     ErrValueTooLarge,
     #[error("IncompatibleValue")]
     IncompatibleValue,
+    #[error("database is in read-only mode")]
+    ErrDatabaseReadOnly,
+    #[error("corrupted page {pgid}")]
+    ErrCorrupted { pgid: PgId },
+    #[error("invalid dump: {0}")]
+    ErrInvalidDump(String),
+    #[error("tx not writable")]
+    ErrTxNotWritable,
+    #[error("keys are not sorted")]
+    ErrUnsortedKeys,
+    #[error("compare and swap mismatch")]
+    ErrCompareAndSwap { current: Option<Vec<u8>> },
+    #[error("no merge operator")]
+    ErrNoMergeOperator,
+    #[error("key is reserved for internal use")]
+    ErrReservedKey,
+    #[error("savepoint released or rolled back")]
+    ErrSavepointNotFound,
+    #[error("comparator mismatch: opened with {expected}, database uses {found}")]
+    ErrComparatorMismatch { expected: String, found: String },
+    #[error("invalid key encoding")]
+    ErrInvalidKeyEncoding,
+    #[error("table {0} not found")]
+    ErrTableNotFound(String),
+    #[error("table {name} type mismatch: opened as ({expected}), defined as ({found})")]
+    ErrTableTypeMismatch { name: String, expected: String, found: String },
+    #[error("index {0} not found")]
+    ErrIndexNotFound(String),
+    #[error("unique index {index} already contains {value:?}")]
+    ErrUniqueViolation { index: String, value: Vec<u8> },
+    #[error("database is not counted")]
+    ErrNotCounted,
 }
 
 
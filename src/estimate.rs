@@ -0,0 +1,303 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::{
+    comparator::KeyRange,
+    config::PAGE_SIZE,
+    error::{Error, Result},
+    page::{PageFlag, PgId, LEAF_ELEMENT_SIZE},
+    tx::{PageNode, Tx},
+};
+
+// 按 fill_percent（f）写入的叶子页面按 (1 + f) / 2 个页面估计，每个页面的相对误差不超过 (1 - f) / (1 + f)，
+// 默认 f = 0.5 时为 1/3；range 两端只覆盖一部分的叶子页面按半个计算，最多再差一个页面
+
+// range 覆盖的叶子页面
+#[derive(Default)]
+struct Span {
+    // 没有读取的叶子页面数量
+    leaves: f64,
+    // 计数 B+ 树分支页面中记录的这些叶子页面的 key 数量
+    keys: f64,
+    // 已经读取为 Node 的叶子节点中 range 内的 key 数量和大小
+    node_keys: u64,
+    node_bytes: u64,
+}
+
+impl Tx {
+    /// 估计 range 内的 key 和值占用的字节数。只读取分支页面，按 range 覆盖的叶子页面数量和
+    /// 平均填充率计算，overflow 页面保存的大值不计算在内。
+    ///
+    /// 每个叶子页面 (1 - f) / (1 + f) 的误差只在页面按当前事务的 fill_percent（f）或者更大的比例
+    /// 写入时成立，比如默认设置的事务和 append 模式。BulkLoader 或者较小的 fill_percent 写入的页面
+    /// 低于这个比例，估计值偏大
+    pub fn approximate_size<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> Result<u64> {
+        let span = self.span(&range)?;
+        Ok(span.node_bytes + (span.leaves * self.leaf_fill()) as u64)
+    }
+
+    /// 估计 range 内 key 的数量。计数 B+ 树使用分支页面中记录的数量，
+    /// 否则按 approximate_size 估计大小，并假设 key 和值的平均大小与 range 起点所在的叶子页面相同，
+    /// 这是唯一读取的叶子页面
+    pub fn approximate_count<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> Result<u64> {
+        let span = self.span(&range)?;
+        let keys = if self.counted() {
+            span.keys
+        } else if span.leaves > 0.0 {
+            let start = self.0.order.user_ranges(&range)[0].0;
+            let entry = self.sample_entry(start)?;
+            if entry > 0.0 { span.leaves * self.leaf_fill() / entry } else { 0.0 }
+        } else {
+            0.0
+        };
+        Ok(span.node_keys + keys.round() as u64)
+    }
+
+    // 一个叶子页面中数据的平均大小
+    fn leaf_fill(&self) -> f64 {
+        (1.0 + self.fill_percent()) / 2.0 * PAGE_SIZE as f64
+    }
+
+    // range 覆盖的叶子页面
+    fn span<'a, R: RangeBounds<&'a [u8]>>(&self, range: &R) -> Result<Span> {
+        let pieces = self.0.order.user_ranges(range);
+        let mut span = Span::default();
+        // 空的根节点
+        let Some(height) = self.height()? else {
+            return Ok(span);
+        };
+        for piece in pieces.iter() {
+            self.visit(self.root_id(), 0, height, (None, None), false, 0, piece, &mut span)?;
+        }
+        Ok(span)
+    }
+
+    // 根节点到叶子节点的分支页面层数，树为空时返回 None。树是平衡的，沿最左侧的路径计算即可
+    fn height(&self) -> Result<Option<usize>> {
+        let mut pgid = self.root_id();
+        let mut height = 0;
+        loop {
+            let (leaf, count) = self.page_header(pgid)?;
+            if leaf {
+                return Ok((height > 0 || count > 0).then_some(height));
+            }
+            pgid = match self.page_node(pgid)? {
+                PageNode::Node(n) => n.node().inodes[0].pgid,
+                PageNode::Page(p) => unsafe { &*p }.branch_page_elements()[0].value,
+            };
+            height += 1;
+        }
+    }
+
+    // 页面是否为叶子页面和元素数量，只读取页头
+    fn page_header(&self, pgid: PgId) -> Result<(bool, usize)> {
+        if let Some(n) = self.0.nodes.borrow().get(&pgid) {
+            let node = n.node();
+            return Ok((node.is_leaf, node.inodes.len()));
+        }
+        if pgid >= self.0.meta.borrow().pgid {
+            return Err(Error::ErrCorrupted { pgid });
+        }
+        let p = unsafe { &*self.db().unwrap().0.page(pgid) };
+        Ok((p.flags.contains(PageFlag::LeafPage), p.count as usize))
+    }
+
+    // 从根节点沿着 start 所在的路径找到叶子页面，返回叶子页面中一个元素的平均大小
+    fn sample_entry(&self, start: Bound<&[u8]>) -> Result<f64> {
+        let order = &self.0.order;
+        let child = |key: Option<&[u8]>| match start {
+            Bound::Included(s) | Bound::Excluded(s) => key.is_some_and(|k| order.cmp(k, s).is_le()),
+            Bound::Unbounded => false,
+        };
+        let mut pgid = self.root_id();
+        loop {
+            match self.page_node(pgid)? {
+                PageNode::Node(n) => {
+                    let node = n.node();
+                    if node.is_leaf {
+                        let bytes: usize = node.inodes.iter().map(|i| LEAF_ELEMENT_SIZE + i.key.len() + i.value.len()).sum();
+                        return Ok(bytes as f64 / node.inodes.len().max(1) as f64);
+                    }
+                    let index = node.inodes.partition_point(|i| child(Some(&i.key))).saturating_sub(1);
+                    pgid = node.inodes[index].pgid;
+                }
+                PageNode::Page(p) => {
+                    let p = unsafe { &*p };
+                    if p.flags.contains(PageFlag::LeafPage) {
+                        let elems = p.leaf_page_elements();
+                        let bytes: usize = elems.iter().map(|e| LEAF_ELEMENT_SIZE + (e.ksize + e.vsize) as usize).sum();
+                        return Ok(bytes as f64 / elems.len().max(1) as f64);
+                    }
+                    let elems = p.branch_page_elements();
+                    let index = elems.partition_point(|e| child(Some(e.key()))).saturating_sub(1);
+                    pgid = elems[index].value;
+                }
+            }
+        }
+    }
+
+    // bounds 是 pgid 中 key 的范围 [lower, upper)，full 表示整个子树都在 range 内，
+    // count 是父节点中记录的子树 key 数量
+    #[allow(clippy::too_many_arguments)]
+    fn visit(
+        &self,
+        pgid: PgId,
+        depth: usize,
+        height: usize,
+        bounds: (Option<&[u8]>, Option<&[u8]>),
+        full: bool,
+        count: u64,
+        range: &KeyRange,
+        span: &mut Span,
+    ) -> Result<()> {
+        if depth == height {
+            // 没有读取为 Node 的叶子页面只按父节点中的信息计算
+            let materialized = self.0.nodes.borrow().get(&pgid).cloned();
+            match materialized {
+                Some(n) => {
+                    for inode in n.node().inodes.iter().filter(|i| full || self.0.order.contains(range, &i.key)) {
+                        span.node_keys += 1;
+                        span.node_bytes += (LEAF_ELEMENT_SIZE + inode.key.len() + inode.value.len()) as u64;
+                    }
+                }
+                None => {
+                    let weight = if full { 1.0 } else { 0.5 };
+                    span.leaves += weight;
+                    span.keys += weight * count as f64;
+                }
+            }
+            return Ok(());
+        }
+
+        let page_node = self.page_node(pgid)?;
+        let guard;
+        // 分支节点中每个子节点的第一个 key、pgid 和 key 数量
+        let mut children: Vec<(&[u8], PgId, u64)> = Vec::new();
+        match &page_node {
+            PageNode::Node(n) => {
+                guard = n.node();
+                children.extend(guard.inodes.iter().map(|i| (i.key.as_slice(), i.pgid, i.count)));
+            }
+            PageNode::Page(p) => {
+                let p = unsafe { &**p };
+                for (i, e) in p.branch_page_elements().iter().enumerate() {
+                    let count = if self.counted() { p.branch_count(i) } else { 0 };
+                    children.push((e.key(), e.value, count));
+                }
+            }
+        }
+        for (i, &(key, child, count)) in children.iter().enumerate() {
+            let lower = if i == 0 { bounds.0 } else { Some(key) };
+            let upper = children.get(i + 1).map(|c| c.0).or(bounds.1);
+            let inside = full || self.inside(range, lower, upper);
+            if inside || !self.outside(range, lower, upper) {
+                self.visit(child, depth + 1, height, (lower, upper), inside, count, range, span)?;
+            }
+        }
+        Ok(())
+    }
+
+    // [lower, upper) 完全在 range 内
+    fn inside(&self, range: &KeyRange, lower: Option<&[u8]>, upper: Option<&[u8]>) -> bool {
+        let order = &self.0.order;
+        (match range.0 {
+            Bound::Unbounded => true,
+            Bound::Included(s) => lower.is_some_and(|l| order.cmp(s, l).is_le()),
+            Bound::Excluded(s) => lower.is_some_and(|l| order.cmp(s, l).is_lt()),
+        }) && match range.1 {
+            Bound::Unbounded => true,
+            Bound::Included(e) | Bound::Excluded(e) => upper.is_some_and(|u| order.cmp(u, e).is_le()),
+        }
+    }
+
+    // [lower, upper) 和 range 没有交集
+    fn outside(&self, range: &KeyRange, lower: Option<&[u8]>, upper: Option<&[u8]>) -> bool {
+        let order = &self.0.order;
+        (match range.0 {
+            Bound::Unbounded => false,
+            Bound::Included(s) | Bound::Excluded(s) => upper.is_some_and(|u| order.cmp(u, s).is_le()),
+        }) || match range.1 {
+            Bound::Unbounded => false,
+            Bound::Included(e) => lower.is_some_and(|l| order.cmp(l, e).is_gt()),
+            Bound::Excluded(e) => lower.is_some_and(|l| order.cmp(l, e).is_ge()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::PAGE_SIZE,
+        db::{DBInner, Options},
+        page::LEAF_ELEMENT_SIZE,
+        tx::Tx,
+    };
+
+    // range 内 key 的实际数量和大小
+    fn exact(tx: &Tx, start: &[u8], end: &[u8]) -> (u64, u64) {
+        let (mut count, mut size) = (0, 0);
+        let mut c = tx.cursor();
+        let mut item = c.seek(start).unwrap();
+        while let (Some(k), Some(v)) = (item.key(), item.value()) {
+            if k >= end {
+                break;
+            }
+            count += 1;
+            size += (LEAF_ELEMENT_SIZE + k.len() + v.len()) as u64;
+            item = c.next().unwrap();
+        }
+        (count, size)
+    }
+
+    fn within(estimate: u64, exact: u64, slack: u64) -> bool {
+        estimate.abs_diff(exact) <= exact / 3 + slack
+    }
+
+    #[test]
+    fn test_approximate_size_and_count() {
+        let entry = LEAF_ELEMENT_SIZE + 8 + 20;
+        for counted in [false, true] {
+            let path = std::env::temp_dir().join(format!("rultdb_test_approximate_{}.db", counted));
+            let _ = std::fs::remove_file(&path);
+            let db = DBInner::open(path.to_str().unwrap(), Options { counted, ..Default::default() }).unwrap();
+            let tx = db.begin_tx();
+            assert_eq!((tx.approximate_size(..).unwrap(), tx.approximate_count(..).unwrap()), (0, 0));
+            tx.close().unwrap();
+
+            let mut x: u64 = 0x9E3779B97F4A7C15;
+            let mut rand = move || {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x
+            };
+            for _ in 0..10 {
+                let mut tx = db.begin_rwtx().unwrap();
+                for _ in 0..2000 {
+                    let k = format!("{:08}", rand() % 100_000_000);
+                    tx.put(k.as_bytes(), &[b'v'; 20]).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+
+            let mut tx = db.begin_rwtx().unwrap();
+            let ranges: [(&[u8], &[u8]); 4] =
+                [(b"", b"99999999"), (b"20000000", b"70000000"), (b"50000000", b"51000000"), (b"42000000", b"42000100")];
+            for &(s, e) in ranges.iter() {
+                let (count, size) = exact(&tx, s, e);
+                let (c, z) = (tx.approximate_count(s..e).unwrap(), tx.approximate_size(s..e).unwrap());
+                assert!(within(z, size, PAGE_SIZE as u64), "{:?} size {} {}", (s, e), z, size);
+                assert!(within(c, count, (PAGE_SIZE / entry) as u64), "{:?} count {} {}", (s, e), c, count);
+                if counted && count > 1000 {
+                    assert!(c.abs_diff(count) <= (PAGE_SIZE / entry) as u64);
+                }
+            }
+
+            // 已经修改但还没有写出的叶子节点按实际内容计算
+            tx.delete_range(&b"30000000"[..]..&b"40000000"[..]).unwrap();
+            let (count, _) = exact(&tx, b"", b"99999999");
+            assert!(within(tx.approximate_count(..).unwrap(), count, (PAGE_SIZE / entry) as u64));
+            assert_eq!(tx.approximate_count(&b"30000000"[..]..&b"40000000"[..]).unwrap(), 0);
+            tx.rollback().unwrap();
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::VecDeque,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::{comparator::KeyOrder, db::DB, tx::TxId};
+
+/// DB::subscribe 默认缓存的事务数量
+pub const DEFAULT_FEED_CAPACITY: usize = 1024;
+
+// DB::watch 保留的最近提交的事务数量
+const WATCH_HISTORY: usize = 64;
+
+/// 已提交的写事务对一个 key 的修改，old 和 new 为 None 表示 key 不存在
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub key: Vec<u8>,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// 一个写事务提交的修改，只包括订阅范围内的 key
+    Commit { txid: TxId, changes: Vec<Change> },
+    /// 缓冲区已满，丢弃了这么多个最早的事务
+    Lagged(u64),
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    lagged: u64,
+    closed: bool,
+}
+
+pub(crate) struct Subscriber {
+    order: KeyOrder,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    capacity: usize,
+    queue: Mutex<Queue>,
+    cond: Condvar,
+}
+
+impl Subscriber {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.order.contains(&(self.start.as_ref().map(|k| k.as_slice()), self.end.as_ref().map(|k| k.as_slice())), key)
+    }
+}
+
+/// DB::subscribe 返回的接收端，drop 之后不再接收修改
+pub struct Subscription(Arc<Subscriber>);
+
+impl DB {
+    /// 订阅 range 内 key 的修改，每个提交的写事务产生一个 Event::Commit
+    pub fn subscribe<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> Subscription {
+        self.subscribe_bounded(range, DEFAULT_FEED_CAPACITY)
+    }
+
+    /// 最多缓存 capacity 个事务，超过时丢弃最早的事务，接收端会先收到 Event::Lagged
+    pub fn subscribe_bounded<'a, R: RangeBounds<&'a [u8]>>(&self, range: R, capacity: usize) -> Subscription {
+        let sub = Arc::new(Subscriber {
+            order: self.0.order.clone(),
+            start: range.start_bound().map(|k| k.to_vec()),
+            end: range.end_bound().map(|k| k.to_vec()),
+            capacity: capacity.max(1),
+            queue: Default::default(),
+            cond: Condvar::new(),
+        });
+        self.0.subscribers.lock().push(Arc::downgrade(&sub));
+        Subscription(sub)
+    }
+}
+
+// 最近提交的事务修改过的 key。只在有 DB::watch 等待时记录，
+// start 之后提交的事务都在 history 中
+#[derive(Default)]
+struct WatchState {
+    waiting: usize,
+    last: TxId,
+    start: TxId,
+    history: VecDeque<(TxId, Vec<Vec<u8>>)>,
+}
+
+impl WatchState {
+    fn find(&self, prefix: &[u8], since: TxId) -> Option<TxId> {
+        if self.last <= since {
+            return None;
+        }
+        // 不知道 since 之后的事务修改了哪些 key
+        if since + 1 < self.start {
+            return Some(self.last);
+        }
+        self.history
+            .iter()
+            .rev()
+            .find(|(id, keys)| *id > since && keys.iter().any(|k| k.starts_with(prefix)))
+            .map(|(id, _)| *id)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Watch {
+    state: Mutex<WatchState>,
+    cond: Condvar,
+}
+
+impl Watch {
+    pub(crate) fn active(&self) -> bool {
+        self.state.lock().waiting > 0
+    }
+
+    // 写事务提交之后、释放写锁之前调用，所以 txid 是递增的，history 按 txid 排列。
+    // keys 为 None 表示事务没有记录修改过的 key
+    pub(crate) fn notify(&self, txid: TxId, keys: Option<Vec<Vec<u8>>>) {
+        let mut s = self.state.lock();
+        debug_assert!(txid > s.last, "{} after {}", txid, s.last);
+        s.last = s.last.max(txid);
+        match keys {
+            Some(keys) if s.waiting > 0 => {
+                s.history.push_back((txid, keys));
+                if s.history.len() > WATCH_HISTORY {
+                    let (id, _) = s.history.pop_front().unwrap();
+                    s.start = id + 1;
+                }
+            }
+            _ => {
+                s.history.clear();
+                s.start = txid + 1;
+            }
+        }
+        self.cond.notify_all();
+    }
+}
+
+impl DB {
+    /// 阻塞直到 since 之后提交的写事务修改了以 prefix 开头的 key，返回这个事务的 id，超时返回 None。
+    /// 开始等待之前提交的事务无法确定修改了哪些 key 时，直接返回最新的事务 id，调用方需要重新读取
+    pub fn watch(&self, prefix: &[u8], since: TxId, timeout: Duration) -> Option<TxId> {
+        let deadline = Instant::now() + timeout;
+        let tx = self.begin_tx();
+        let current = tx.id();
+        let _ = tx.close();
+
+        let watch = &self.0.watch;
+        let mut s = watch.state.lock();
+        if s.last < current {
+            // 打开数据库之后还没有提交过事务
+            s.last = current;
+            s.start = s.start.max(current + 1);
+        }
+        s.waiting += 1;
+        let found = loop {
+            if let Some(id) = s.find(prefix, since) {
+                break Some(id);
+            }
+            if watch.cond.wait_until(&mut s, deadline).timed_out() {
+                break s.find(prefix, since);
+            }
+        };
+        s.waiting -= 1;
+        found
+    }
+}
+
+impl Subscription {
+    /// 阻塞直到有新的事件，数据库关闭后返回 None
+    pub fn recv(&self) -> Option<Event> {
+        let mut q = self.0.queue.lock();
+        loop {
+            if let Some(e) = pop(&mut q) {
+                return Some(e);
+            }
+            if q.closed {
+                return None;
+            }
+            self.0.cond.wait(&mut q);
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event> {
+        let deadline = Instant::now() + timeout;
+        let mut q = self.0.queue.lock();
+        loop {
+            if let Some(e) = pop(&mut q) {
+                return Some(e);
+            }
+            if q.closed || self.0.cond.wait_until(&mut q, deadline).timed_out() {
+                return pop(&mut q);
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Event> {
+        pop(&mut self.0.queue.lock())
+    }
+}
+
+fn pop(q: &mut Queue) -> Option<Event> {
+    if q.lagged > 0 {
+        return Some(Event::Lagged(std::mem::take(&mut q.lagged)));
+    }
+    q.events.pop_front()
+}
+
+// 写事务提交之后调用，同时清理已经 drop 的订阅
+pub(crate) fn publish(subs: &Mutex<Vec<Weak<Subscriber>>>, txid: TxId, changes: &[Change]) {
+    subs.lock().retain(|s| {
+        let Some(s) = s.upgrade() else { return false };
+        let changes: Vec<Change> = changes.iter().filter(|c| s.contains(&c.key)).cloned().collect();
+        if !changes.is_empty() {
+            let mut q = s.queue.lock();
+            if q.events.len() >= s.capacity {
+                q.events.pop_front();
+                q.lagged += 1;
+            }
+            q.events.push_back(Event::Commit { txid, changes });
+            s.cond.notify_all();
+        }
+        true
+    });
+}
+
+pub(crate) fn close(subs: &Mutex<Vec<Weak<Subscriber>>>) {
+    for s in subs.lock().drain(..).filter_map(|s| s.upgrade()) {
+        s.queue.lock().closed = true;
+        s.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) -> Change {
+        Change { key: key.to_vec(), old: old.map(|v| v.to_vec()), new: new.map(|v| v.to_vec()) }
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let db = crate::db::tests::temp_db("rultdb_test_subscribe.db");
+        let sub = db.subscribe(&b"b"[..]..&b"c"[..]);
+        let all = db.subscribe_bounded(.., 2);
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"a", b"1").unwrap();
+        tx.put(b"b1", b"1").unwrap();
+        tx.put(b"b2", b"1").unwrap();
+        tx.put(b"b2", b"2").unwrap();
+        let txid = tx.id();
+        tx.commit().unwrap();
+        assert_eq!(
+            sub.try_recv(),
+            Some(Event::Commit { txid, changes: vec![change(b"b1", None, Some(b"1")), change(b"b2", None, Some(b"2"))] })
+        );
+        assert_eq!(sub.try_recv(), None);
+
+        // 回滚的事务和范围外的修改不会发送
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"b1", b"x").unwrap();
+        tx.rollback().unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"a", b"2").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(sub.try_recv(), None);
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.delete(b"b1").unwrap();
+        tx.replace(b"b2", b"3").unwrap();
+        let txid = tx.id();
+        tx.commit().unwrap();
+        assert_eq!(
+            sub.recv_timeout(Duration::from_secs(1)),
+            Some(Event::Commit { txid, changes: vec![change(b"b1", Some(b"1"), None), change(b"b2", Some(b"2"), Some(b"3"))] })
+        );
+
+        // 缓冲区只保留最新的两个事务
+        assert_eq!(all.try_recv(), Some(Event::Lagged(1)));
+        assert!(matches!(all.try_recv(), Some(Event::Commit { changes, .. }) if changes == vec![change(b"a", Some(b"1"), Some(b"2"))]));
+        assert!(matches!(all.try_recv(), Some(Event::Commit { txid: id, .. }) if id == txid));
+        assert_eq!(all.recv_timeout(Duration::from_millis(10)), None);
+
+        drop(all);
+        let handle = std::thread::spawn(move || sub.recv());
+        drop(db);
+        assert_eq!(handle.join().unwrap(), None);
+    }
+
+    #[test]
+    fn test_watch() {
+        let db = crate::db::tests::temp_db("rultdb_test_watch.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"user/1", b"v").unwrap();
+        tx.commit().unwrap();
+        let since = db.begin_tx();
+        let since_id = since.id();
+        since.close().unwrap();
+        assert_eq!(db.watch(b"user/", since_id, Duration::from_millis(10)), None);
+
+        let waiter = db.clone();
+        let handle = std::thread::spawn(move || waiter.watch(b"user/", since_id, Duration::from_secs(10)));
+        while !db.0.watch.active() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"other", b"v").unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.delete(b"user/1").unwrap();
+        let txid = tx.id();
+        tx.commit().unwrap();
+        assert_eq!(handle.join().unwrap(), Some(txid));
+
+        // 没有等待时提交的事务不记录修改的 key，直接返回最新的事务 id
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"other", b"v2").unwrap();
+        let latest = tx.id();
+        tx.commit().unwrap();
+        assert_eq!(db.watch(b"user/", txid, Duration::from_secs(10)), Some(latest));
+        assert_eq!(db.watch(b"user/", latest, Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn test_commit_order() {
+        let db = crate::db::tests::temp_db("rultdb_test_feed_order.db");
+        let sub = db.subscribe(..);
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let mut tx = db.begin_rwtx().unwrap();
+                        tx.put(format!("{}/{}", t, i).as_bytes(), b"v").unwrap();
+                        tx.commit().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        // 多个线程同时提交时事件也按 txid 的顺序到达
+        let mut txids = Vec::new();
+        while let Some(Event::Commit { txid, .. }) = sub.try_recv() {
+            txids.push(txid);
+        }
+        assert_eq!(txids.len(), 200);
+        assert!(txids.windows(2).all(|w| w[0] < w[1]), "{:?}", txids);
+    }
+}
@@ -5,7 +5,7 @@ use crate::error::Result;
 
 
 
-#[derive(Default,Debug)]
+#[derive(Default,Debug,Clone)]
 pub struct FreeList {
     pub(crate) ids: Vec<PgId>,
     pub(crate) pending: HashMap<TxId, Vec<PgId>>,
@@ -21,15 +21,15 @@ impl FreeList {
         return PAGE_HEADER_SIZE + size_of::<PgId>() * count;
     }
 
-    fn count(&self) -> usize {
+    pub fn count(&self) -> usize {
         self.pending_count() + self.free_count()
     }
 
-    fn free_count(&self) -> usize {
+    pub fn free_count(&self) -> usize {
         self.ids.len()
     }
 
-    fn pending_count(&self) -> usize {
+    pub fn pending_count(&self) -> usize {
         self.pending.iter().map(|x| x.1.len()).sum()
     }
 
@@ -108,8 +108,8 @@ impl FreeList {
         if count == 0 {
             self.ids.clear();
         } else {
-            let ids = p.freelist();
-            self.ids = ids[idx..count].to_vec();
+            let ids = p.freelist_with_size(idx + count);
+            self.ids = ids[idx..idx + count].to_vec();
             self.ids.sort_unstable();
         }
 
@@ -130,11 +130,12 @@ impl FreeList {
             m.sort_unstable();
         } else {
             p.count = 0xFFFF;
-            let m = p.freelist_mut_with_size(count);
+            let m = p.freelist_mut_with_size(count + 1);
             m[0] = count as u64;
             self.copy_all(&mut m[1..]);
             m[1..].sort_unstable();
         }
+        p.set_checksum();
     }
 
     pub(crate) fn copy_all(&self, mut dst: &mut [PgId]) {
@@ -0,0 +1,474 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use crate::{
+    error::{Error, Result},
+    is_system_key,
+    keys::{self, PrefixRange},
+    table::get_raw,
+    tx::Tx,
+    MAX_KEY_SIZE,
+};
+
+// 系统 key "idx" + 编码后的索引名 + 编码后的索引值 + 主键保存索引，值为空
+const INDEX_PREFIX: &[u8] = b"\xffrultdb\x00idx\x00";
+
+/// 从 key 和 value 中提取索引值，一个 key 可以有多个索引值
+pub type IndexExtractor = Arc<dyn Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync>;
+
+/// 通过 Options::indexes 声明的二级索引，维护以 prefix 开头的 key。
+/// 提取函数不会保存在数据库中，修改之后需要调用 Tx::rebuild_index
+#[derive(Clone)]
+pub struct Index {
+    name: String,
+    prefix: Vec<u8>,
+    unique: bool,
+    extractor: IndexExtractor,
+}
+
+impl Index {
+    pub fn new(name: &str, prefix: &[u8], extractor: impl Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static) -> Self {
+        Self { name: name.to_string(), prefix: prefix.to_vec(), unique: false, extractor: Arc::new(extractor) }
+    }
+
+    /// 使用内置提取函数的索引，rultdb 命令行工具用它检查和重建索引。extractor 为：
+    /// "value" 整个值；"field:SEP:N" 值按单字节 SEP 分割后的第 N 个字段；"split:SEP" 所有字段
+    pub fn builtin(name: &str, prefix: &[u8], extractor: &str) -> Option<Self> {
+        let sep = |s: &str| match s.as_bytes() {
+            &[b] => Some(b),
+            _ => None,
+        };
+        let index = match extractor.split_once(':') {
+            None if extractor == "value" => Self::new(name, prefix, |_, v| vec![v.to_vec()]),
+            Some(("field", rest)) => {
+                let (s, n) = rest.rsplit_once(':')?;
+                let (s, n) = (sep(s)?, n.parse::<usize>().ok()?);
+                Self::new(name, prefix, move |_, v| v.split(|&b| b == s).nth(n).map(|f| f.to_vec()).into_iter().collect())
+            }
+            Some(("split", s)) => {
+                let s = sep(s)?;
+                Self::new(name, prefix, move |_, v| v.split(|&b| b == s).map(|f| f.to_vec()).collect())
+            }
+            _ => return None,
+        };
+        Some(index)
+    }
+
+    /// 不同的主键不能有相同的索引值，否则写入返回 ErrUniqueViolation
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn covers(&self, key: &[u8]) -> bool {
+        !is_system_key(key) && key.starts_with(&self.prefix)
+    }
+
+    // 去掉重复的索引值
+    fn extract(&self, key: &[u8], value: Option<&[u8]>) -> BTreeSet<Vec<u8>> {
+        value.map(|v| (self.extractor)(key, v).into_iter().collect()).unwrap_or_default()
+    }
+
+    // 索引值为 value 的所有项的公共前缀
+    fn value_prefix(&self, value: &[u8]) -> Vec<u8> {
+        let mut k = INDEX_PREFIX.to_vec();
+        keys::Encode::encode_to(self.name.as_str(), &mut k);
+        keys::Encode::encode_to(value, &mut k);
+        k
+    }
+
+    fn entry_key(&self, value: &[u8], key: &[u8]) -> Vec<u8> {
+        [self.value_prefix(value), key.to_vec()].concat()
+    }
+
+    fn name_prefix(&self) -> Vec<u8> {
+        let mut k = INDEX_PREFIX.to_vec();
+        keys::Encode::encode_to(self.name.as_str(), &mut k);
+        k
+    }
+}
+
+// 索引项的 key -> (索引值, 主键)
+type Entries = BTreeMap<Vec<u8>, (Vec<u8>, Vec<u8>)>;
+
+// 按顺序遍历以 prefix 开头的系统 key
+fn scan_system(tx: &Tx, prefix: &[u8], mut f: impl FnMut(&[u8]) -> Result<bool>) -> Result<()> {
+    let range = PrefixRange::new(prefix);
+    let mut c = tx.raw_cursor();
+    let mut item = c.seek(&range.start)?;
+    while let Some(k) = item.key().filter(|k| range.contains(k)) {
+        if !f(k)? {
+            break;
+        }
+        item = c.next()?;
+    }
+    Ok(())
+}
+
+// 解析索引项，返回 (索引值, 主键)
+fn parse_entry(name_prefix: &[u8], k: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut rest = &k[name_prefix.len()..];
+    let value = <Vec<u8> as keys::Decode>::decode_from(&mut rest)?;
+    Ok((value, rest.to_vec()))
+}
+
+impl Tx {
+    fn index(&self, name: &str) -> Result<Index> {
+        let db = self.db().unwrap();
+        db.0.indexes.iter().find(|i| i.name == name).cloned().ok_or_else(|| Error::ErrIndexNotFound(name.to_string()))
+    }
+
+    // 包含 key 的所有索引
+    fn covering_indexes(&self, key: &[u8]) -> Vec<Index> {
+        let db = self.db().unwrap();
+        db.0.indexes.iter().filter(|i| i.covers(key)).cloned().collect()
+    }
+
+    pub(crate) fn indexed(&self, key: &[u8]) -> bool {
+        self.db().unwrap().0.indexes.iter().any(|i| i.covers(key))
+    }
+
+    // 在修改 key 之前调用，new 为 None 表示删除。先检查所有唯一约束，之后才修改索引
+    pub(crate) fn update_indexes(&mut self, key: &[u8], new: Option<&[u8]>) -> Result<()> {
+        let indexes = self.covering_indexes(key);
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        let old = get_raw(self, key)?;
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for index in indexes.iter() {
+            let (old, new) = (index.extract(key, old), index.extract(key, new));
+            for value in new.difference(&old) {
+                let entry = index.entry_key(value, key);
+                if entry.len() > MAX_KEY_SIZE {
+                    return Err(Error::ErrKeyTooLarge);
+                }
+                if index.unique {
+                    let prefix = index.value_prefix(value);
+                    let mut conflict = false;
+                    // 已经过期但还没有清理的 key 不占用索引值
+                    scan_system(self, &prefix, |k| {
+                        let other = &k[prefix.len()..];
+                        conflict = other != key && !self.is_hidden(other)?;
+                        Ok(!conflict)
+                    })?;
+                    if conflict {
+                        return Err(Error::ErrUniqueViolation { index: index.name.clone(), value: value.clone() });
+                    }
+                }
+                added.push(entry);
+            }
+            removed.extend(old.difference(&new).map(|value| index.entry_key(value, key)));
+        }
+        for k in removed {
+            self.delete_raw(&k)?;
+        }
+        for k in added {
+            self.put_raw(&k, &[])?;
+        }
+        Ok(())
+    }
+
+    // delete_range 删除 range 内的 key 之前删除它们的索引项。需要读取 range 内的所有 key
+    pub(crate) fn clear_index_range(&mut self, range: &(Bound<&[u8]>, Bound<&[u8]>)) -> Result<()> {
+        if self.db().unwrap().0.indexes.is_empty() {
+            return Ok(());
+        }
+        let mut keys = Vec::new();
+        let mut c = self.raw_cursor();
+        let mut item = match range.0 {
+            Bound::Included(s) | Bound::Excluded(s) => c.seek(s)?,
+            Bound::Unbounded => c.first()?,
+        };
+        while let Some(k) = item.key() {
+            if self.0.order.contains(range, k) {
+                if self.indexed(k) {
+                    keys.push(k.to_vec());
+                }
+            } else if !matches!(range.0, Bound::Excluded(s) if s == k) {
+                break;
+            }
+            item = c.next()?;
+        }
+        for k in keys {
+            self.update_indexes(&k, None)?;
+        }
+        Ok(())
+    }
+
+    /// 索引值为 value 的所有主键，按主键的字节顺序排列。不包括已经过期的 key
+    pub fn index_get(&self, name: &str, value: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let index = self.index(name)?;
+        let prefix = index.value_prefix(value);
+        let mut keys = Vec::new();
+        scan_system(self, &prefix, |k| {
+            let key = &k[prefix.len()..];
+            if !self.is_hidden(key)? {
+                keys.push(key.to_vec());
+            }
+            Ok(true)
+        })?;
+        Ok(keys)
+    }
+
+    /// 索引值在 range 内的所有 (索引值, 主键)，按索引值的字节顺序排列
+    pub fn index_range<'a, R: RangeBounds<&'a [u8]>>(&self, name: &str, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let index = self.index(name)?;
+        let name_prefix = index.name_prefix();
+        let mut entries = Vec::new();
+        scan_system(self, &name_prefix, |k| {
+            let (value, key) = parse_entry(&name_prefix, k)?;
+            if !range.contains(&value.as_slice()) {
+                // 索引值按字节顺序排列，超过 end 之后不会再有 range 内的值
+                let before_start = match range.start_bound() {
+                    Bound::Included(s) => value.as_slice() < *s,
+                    Bound::Excluded(s) => value.as_slice() <= *s,
+                    Bound::Unbounded => false,
+                };
+                return Ok(before_start);
+            }
+            if !self.is_hidden(&key)? {
+                entries.push((value, key));
+            }
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    // 索引应该包含的所有项，从 keyspace 中的 key 计算
+    fn expected_entries(&self, index: &Index) -> Result<Entries> {
+        let mut entries = BTreeMap::new();
+        let mut c = self.raw_cursor();
+        // 使用比较器时以 prefix 开头的 key 不一定相邻
+        let mut item = if self.0.order.is_bytewise() { c.seek(&index.prefix)? } else { c.first()? };
+        while let (Some(k), Some(v)) = (item.key(), item.value()) {
+            if index.covers(k) {
+                for value in index.extract(k, Some(v)) {
+                    entries.insert(index.entry_key(&value, k), (value, k.to_vec()));
+                }
+            } else if self.0.order.is_bytewise() && !is_system_key(k) {
+                break;
+            }
+            item = c.next()?;
+        }
+        Ok(entries)
+    }
+
+    fn index_entries(&self, index: &Index) -> Result<Vec<Vec<u8>>> {
+        let mut entries = Vec::new();
+        scan_system(self, &index.name_prefix(), |k| {
+            entries.push(k.to_vec());
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    /// 检查索引和 keyspace 中的 key 是否一致，返回发现的问题
+    pub fn verify_index(&self, name: &str) -> Result<Vec<String>> {
+        let index = self.index(name)?;
+        let expected = self.expected_entries(&index)?;
+        let actual: BTreeSet<Vec<u8>> = self.index_entries(&index)?.into_iter().collect();
+        let mut problems = Vec::new();
+        for (k, (value, key)) in expected.iter() {
+            if !actual.contains(k) {
+                problems.push(format!("missing entry {:?} -> {:?}", value, key));
+            }
+        }
+        let name_prefix = index.name_prefix();
+        for k in actual.iter().filter(|k| !expected.contains_key(*k)) {
+            let (value, key) = parse_entry(&name_prefix, k)?;
+            problems.push(format!("stale entry {:?} -> {:?}", value, key));
+        }
+        if index.unique {
+            let mut owners: BTreeMap<&[u8], &[u8]> = BTreeMap::new();
+            for (value, key) in expected.values() {
+                if self.is_hidden(key)? {
+                    continue;
+                }
+                if let Some(other) = owners.insert(value, key) {
+                    problems.push(format!("duplicate value {:?} for {:?} and {:?}", value, other, key));
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// 删除索引的所有项并从 keyspace 中的 key 重新生成，返回生成的项数。
+    /// 唯一索引中有重复的值时返回 ErrUniqueViolation，不修改索引
+    pub fn rebuild_index(&mut self, name: &str) -> Result<u64> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        let index = self.index(name)?;
+        let expected = self.expected_entries(&index)?;
+        let mut seen = BTreeSet::new();
+        for (k, (value, key)) in expected.iter() {
+            if k.len() > MAX_KEY_SIZE {
+                return Err(Error::ErrKeyTooLarge);
+            }
+            if index.unique && !self.is_hidden(key)? && !seen.insert(value) {
+                return Err(Error::ErrUniqueViolation { index: index.name.clone(), value: value.clone() });
+            }
+        }
+        for k in self.index_entries(&index)? {
+            self.delete_raw(&k)?;
+        }
+        for k in expected.keys() {
+            self.put_raw(k, &[])?;
+        }
+        Ok(expected.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulk::BulkLoader;
+    use crate::db::{DBInner, Options, DB};
+    use crate::ttl::ManualClock;
+    use std::time::Duration;
+
+    // 值的格式为 "email,tag1,tag2,..."
+    fn open(name: &str) -> DB {
+        let path = std::env::temp_dir().join(name);
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        DBInner::open(path, options()).unwrap()
+    }
+
+    fn options() -> Options {
+        let email = Index::new("email", b"user/", |_, v| v.split(|&b| b == b',').take(1).map(|s| s.to_vec()).collect()).unique();
+        let tags = Index::new("tags", b"user/", |_, v| v.split(|&b| b == b',').skip(1).map(|s| s.to_vec()).collect());
+        Options { indexes: vec![email, tags], ..Default::default() }
+    }
+
+    #[test]
+    fn test_index() {
+        let db = open("rultdb_test_index.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"user/1", b"a@x,red,blue").unwrap();
+        tx.put(b"user/2", b"b@x,red").unwrap();
+        tx.put(b"other", b"c@x,red").unwrap();
+        assert!(matches!(tx.put(b"user/3", b"a@x"), Err(Error::ErrUniqueViolation { index, value }) if index == "email" && value == b"a@x"));
+        assert_eq!(tx.get(b"user/3").unwrap(), None);
+        // 同一个主键重新写入相同的唯一值
+        tx.put(b"user/1", b"a@x,blue").unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.index_get("email", b"b@x").unwrap(), vec![b"user/2".to_vec()]);
+        assert_eq!(tx.index_get("tags", b"red").unwrap(), vec![b"user/2".to_vec()]);
+        assert_eq!(tx.index_get("tags", b"blue").unwrap(), vec![b"user/1".to_vec()]);
+        assert!(matches!(tx.index_get("missing", b""), Err(Error::ErrIndexNotFound(_))));
+        tx.delete(b"user/2").unwrap();
+        assert!(tx.put_if_absent(b"user/3", b"b@x,green").unwrap());
+        assert!(matches!(tx.replace(b"user/3", b"a@x"), Err(Error::ErrUniqueViolation { .. })));
+        tx.compare_and_swap(b"user/4", None, Some(b"d@x,green")).unwrap();
+        assert_eq!(
+            tx.index_range("email", &b"b"[..]..).unwrap(),
+            vec![(b"b@x".to_vec(), b"user/3".to_vec()), (b"d@x".to_vec(), b"user/4".to_vec())]
+        );
+        assert_eq!(tx.index_get("tags", b"green").unwrap(), vec![b"user/3".to_vec(), b"user/4".to_vec()]);
+        assert_eq!(tx.delete_range(&b"user/3"[..]..).unwrap(), 2);
+        assert!(tx.index_get("tags", b"green").unwrap().is_empty());
+        assert!(tx.verify_index("email").unwrap().is_empty());
+        assert!(tx.verify_index("tags").unwrap().is_empty());
+        tx.commit().unwrap();
+        drop(db);
+
+        // 提取函数改变之后重建索引
+        let path = std::env::temp_dir().join("rultdb_test_index.db");
+        let tags = Index::new("tags", b"user/", |_, v| v.split(|&b| b == b',').map(|s| s.to_vec()).collect());
+        let db = DBInner::open(path.to_str().unwrap(), Options { indexes: vec![tags], ..Default::default() }).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.verify_index("tags").unwrap().len(), 1);
+        assert_eq!(tx.rebuild_index("tags").unwrap(), 2);
+        assert!(tx.verify_index("tags").unwrap().is_empty());
+        assert_eq!(tx.index_get("tags", b"a@x").unwrap(), vec![b"user/1".to_vec()]);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_index_after_import() {
+        let src = open("rultdb_test_index_import_src.db");
+        let mut tx = src.begin_rwtx().unwrap();
+        tx.put(b"user/1", b"a@x,red").unwrap();
+        tx.put(b"user/2", b"b@x,red").unwrap();
+        let mut dump = Vec::new();
+        tx.export(&mut dump).unwrap();
+        tx.rollback().unwrap();
+
+        // import 通过 BulkLoader 写入，完成之后重新生成索引
+        let db = open("rultdb_test_index_import.db");
+        assert_eq!(db.import(&mut dump.as_slice()).unwrap(), 2);
+        let tx = db.begin_tx();
+        assert!(tx.verify_index("email").unwrap().is_empty());
+        assert!(tx.verify_index("tags").unwrap().is_empty());
+        assert_eq!(tx.index_get("tags", b"red").unwrap(), vec![b"user/1".to_vec(), b"user/2".to_vec()]);
+        tx.close().unwrap();
+
+        let db = open("rultdb_test_index_import_unique.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        let pairs = [(&b"user/1"[..], &b"a@x"[..]), (b"user/2", b"a@x")];
+        assert!(matches!(BulkLoader::new(&mut tx).unwrap().load(pairs), Err(Error::ErrUniqueViolation { .. })));
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_index_value_with_zero() {
+        let db = open("rultdb_test_index_zero.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"user/1", b"a\0b").unwrap();
+        // "a" 的索引项不能匹配 "a\0b" 的索引项
+        tx.put(b"user/2", b"a").unwrap();
+        assert_eq!(tx.index_get("email", b"a").unwrap(), vec![b"user/2".to_vec()]);
+        assert_eq!(tx.index_get("email", b"a\0b").unwrap(), vec![b"user/1".to_vec()]);
+        assert_eq!(tx.index_range("email", &b"a"[..]..=&b"a"[..]).unwrap(), vec![(b"a".to_vec(), b"user/2".to_vec())]);
+        assert!(matches!(tx.put(b"user/3", b"a\0b"), Err(Error::ErrUniqueViolation { .. })));
+        assert!(tx.verify_index("email").unwrap().is_empty());
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_unique_index_expired_key() {
+        let path = std::env::temp_dir().join("rultdb_test_index_expired.db");
+        let _ = std::fs::remove_file(&path);
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let opt = Options { clock: Some(clock.clone()), ttl_sweep_batch: 0, ..options() };
+        let db = DBInner::open(path.to_str().unwrap(), opt).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put_with_ttl(b"user/1", b"a@x", Duration::from_secs(10)).unwrap();
+        tx.commit().unwrap();
+
+        // 过期但还没有清理的 key 不再占用唯一值
+        clock.advance(Duration::from_secs(10));
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"user/2", b"a@x").unwrap();
+        assert_eq!(tx.index_get("email", b"a@x").unwrap(), vec![b"user/2".to_vec()]);
+        assert!(tx.verify_index("email").unwrap().is_empty());
+        assert_eq!(tx.rebuild_index("email").unwrap(), 2);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_builtin_index() {
+        let extract = |spec: &str, v: &[u8]| Index::builtin("i", b"", spec).unwrap().extract(b"k", Some(v));
+        let set = |values: &[&[u8]]| values.iter().map(|v| v.to_vec()).collect::<BTreeSet<_>>();
+        assert_eq!(extract("value", b"a,b"), set(&[b"a,b"]));
+        assert_eq!(extract("field:,:1", b"a,b,c"), set(&[b"b"]));
+        assert_eq!(extract("field:,:3", b"a,b,c"), set(&[]));
+        assert_eq!(extract("field:::0", b"a:b"), set(&[b"a"]));
+        assert_eq!(extract("split:;", b"a;b;a"), set(&[b"a", b"b"]));
+        for spec in ["", "values", "field:,", "field:ab:0", "split:"] {
+            assert!(Index::builtin("i", b"", spec).is_none(), "{}", spec);
+        }
+    }
+}
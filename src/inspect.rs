@@ -0,0 +1,420 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    comparator::KeyOrder,
+    config::PAGE_SIZE,
+    error::{Error, Result},
+    freelist::FreeList,
+    page::{Meta, Page, PageFlag, PgId},
+    tx::Tx,
+};
+
+/// 页面类型，overflow 页面记录它所属的起始页面
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    Meta,
+    FreeList,
+    Branch,
+    Leaf,
+    Overflow(PgId),
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageStatus {
+    /// 从 meta 可达（meta 页面、freelist 页面以及 B+ 树中的页面）
+    InUse,
+    Free,
+    /// 已释放，但还有读事务可能在使用
+    Pending,
+    /// 既不可达也不在 freelist 中
+    Unreachable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageInfo {
+    pub id: PgId,
+    pub kind: PageKind,
+    pub count: u16,
+    pub overflow: u32,
+    pub parent: Option<PgId>,
+    pub status: PageStatus,
+}
+
+/// 解码后的页面内容，无法解码的页面返回原始字节
+pub enum PageContents {
+    Meta(Meta),
+    FreeList(Vec<PgId>),
+    Branch(Vec<(Vec<u8>, PgId)>),
+    Leaf(Vec<(Vec<u8>, Vec<u8>)>),
+    Raw(Vec<u8>),
+}
+
+impl PageKind {
+    fn from_flags(flags: &PageFlag) -> PageKind {
+        if *flags == PageFlag::MetaPage {
+            PageKind::Meta
+        } else if *flags == PageFlag::FreeListPage {
+            PageKind::FreeList
+        } else if *flags == PageFlag::BranchPage {
+            PageKind::Branch
+        } else if *flags == PageFlag::LeafPage {
+            PageKind::Leaf
+        } else {
+            PageKind::Unknown(flags.bits())
+        }
+    }
+}
+
+/// 直接读取数据库文件的内容，不需要能够正常打开数据库
+pub struct RawFile {
+    buf: Vec<u8>,
+    meta: Meta,
+    meta_page: PgId,
+}
+
+impl RawFile {
+    /// 读取文件并按照打开数据库时的规则选择 meta 页面：优先使用 txid 较大的有效 meta
+    pub fn open(path: &str) -> Result<RawFile> {
+        let buf = std::fs::read(path).map_err(|e| ("can't read file", e))?;
+        Self::from_buf(buf)
+    }
+
+    pub fn from_buf(buf: Vec<u8>) -> Result<RawFile> {
+        if buf.len() < 2 * PAGE_SIZE {
+            return Err(Error::ErrInvalid);
+        }
+        let metas = [Page::page_in_buffer(&buf, 0).meta(), Page::page_in_buffer(&buf, 1).meta()];
+        let order = if metas[1].txid > metas[0].txid { [1, 0] } else { [0, 1] };
+        let meta_page = order
+            .into_iter()
+            .find(|id| metas[*id].validate().is_ok())
+            .ok_or(Error::ErrInvalid)?;
+        let meta = metas[meta_page].clone();
+        Ok(RawFile { buf, meta, meta_page: meta_page as PgId })
+    }
+
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
+    /// 当前使用的 meta 所在的页面（0 或 1）
+    pub fn meta_page(&self) -> PgId {
+        self.meta_page
+    }
+
+    pub fn buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// 返回 pgid 之前的所有页面，freelist 取自 meta 指向的 freelist 页面，
+    /// 因此文件中无法区分 free 和 pending 页面
+    pub fn pages(&self) -> Vec<PageInfo> {
+        let free = read_freelist(&self.buf, &self.meta);
+        page_infos(self.data(), &self.meta, &free, &HashSet::new())
+    }
+
+    pub fn page_info(&self, pgid: PgId) -> Result<PageInfo> {
+        find_page(self.pages(), pgid)
+    }
+
+    pub fn page_contents(&self, pgid: PgId) -> Result<PageContents> {
+        decode_page(&self.buf, pgid)
+    }
+
+    fn data(&self) -> &[u8] {
+        let len = (self.meta.pgid as usize).saturating_mul(PAGE_SIZE).min(self.buf.len());
+        &self.buf[..len]
+    }
+}
+
+impl Tx {
+    /// 返回单个页面的类型、元素数量、overflow、父页面以及是否空闲，
+    /// 空闲状态取自数据库当前的 freelist
+    pub fn page_info(&self, pgid: PgId) -> Result<PageInfo> {
+        find_page(self.pages(), pgid)
+    }
+
+    pub fn pages(&self) -> Vec<PageInfo> {
+        let db = self.db().unwrap();
+        let (free, pending) = {
+            let freelist = db.0.freelist.read();
+            let free: HashSet<PgId> = freelist.ids.iter().copied().collect();
+            let pending: HashSet<PgId> = freelist.pending.values().flatten().copied().collect();
+            (free, pending)
+        };
+        let meta = self.meta();
+        self.with_data(|buf| page_infos(buf, &meta, &free, &pending))
+    }
+
+    pub fn page_contents(&self, pgid: PgId) -> Result<PageContents> {
+        self.with_data(|buf| decode_page(buf, pgid))
+    }
+
+    // 只暴露 pgid 之前的页面，mmap 超过文件长度的部分不能访问
+    fn with_data<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        let db = self.db().unwrap();
+        let mmap = db.0.state().mmap.as_ref().unwrap();
+        let len = (self.meta().pgid as usize * PAGE_SIZE).min(mmap.len());
+        f(&mmap[..len])
+    }
+}
+
+fn find_page(pages: Vec<PageInfo>, pgid: PgId) -> Result<PageInfo> {
+    let len = pages.len();
+    pages
+        .into_iter()
+        .find(|p| p.id == pgid)
+        .ok_or_else(|| format!("page {} out of range: {}", pgid, len).into())
+}
+
+fn read_freelist(buf: &[u8], meta: &Meta) -> HashSet<PgId> {
+    let mut freelist = FreeList::default();
+    if let Some(p) = page_at(buf, meta.freelist) {
+        if p.flags == PageFlag::FreeListPage && p.elements_in_bounds() {
+            freelist.read(p);
+        }
+    }
+    freelist.ids.into_iter().collect()
+}
+
+// 返回完整（包括 overflow）落在 buf 内的页面
+pub(crate) fn page_at(buf: &[u8], pgid: PgId) -> Option<&Page> {
+    let offset = (pgid as usize).checked_mul(PAGE_SIZE)?;
+    if offset + PAGE_SIZE > buf.len() {
+        return None;
+    }
+    let p = Page::page_in_buffer(buf, pgid);
+    let end = offset as u64 + (p.overflow as u64 + 1) * PAGE_SIZE as u64;
+    if end > buf.len() as u64 {
+        return None;
+    }
+    Some(p)
+}
+
+pub(crate) fn page_infos(
+    buf: &[u8],
+    meta: &Meta,
+    free: &HashSet<PgId>,
+    pending: &HashSet<PgId>,
+) -> Vec<PageInfo> {
+    // 可达页面 -> (类型, 父页面)
+    let mut owned: HashMap<PgId, (PageKind, Option<PgId>)> = HashMap::new();
+    owned.insert(0, (PageKind::Meta, None));
+    owned.insert(1, (PageKind::Meta, None));
+
+    let mark = |owned: &mut HashMap<PgId, (PageKind, Option<PgId>)>, p: &Page, pgid: PgId, parent: Option<PgId>| {
+        owned.insert(pgid, (PageKind::from_flags(&p.flags), parent));
+        for id in pgid + 1..=pgid + p.overflow as PgId {
+            owned.insert(id, (PageKind::Overflow(pgid), parent));
+        }
+    };
+    if let Some(p) = page_at(buf, meta.freelist) {
+        mark(&mut owned, p, meta.freelist, None);
+    }
+
+    let mut stack = vec![(meta.root, None)];
+    while let Some((pgid, parent)) = stack.pop() {
+        if pgid < 2 || owned.contains_key(&pgid) {
+            continue;
+        }
+        let Some(p) = page_at(buf, pgid) else {
+            continue;
+        };
+        mark(&mut owned, p, pgid, parent);
+        if p.flags == PageFlag::BranchPage && p.elements_in_bounds() {
+            for elem in p.branch_page_elements().iter().rev() {
+                stack.push((elem.value, Some(pgid)));
+            }
+        }
+    }
+
+    (0..(buf.len() / PAGE_SIZE) as PgId)
+        .map(|id| {
+            let p = Page::page_in_buffer(buf, id);
+            let (kind, parent, status) = match owned.get(&id) {
+                Some((kind, parent)) => (*kind, *parent, PageStatus::InUse),
+                None => {
+                    let status = if pending.contains(&id) {
+                        PageStatus::Pending
+                    } else if free.contains(&id) {
+                        PageStatus::Free
+                    } else {
+                        PageStatus::Unreachable
+                    };
+                    (PageKind::from_flags(&p.flags), None, status)
+                }
+            };
+            let (count, overflow) = match kind {
+                PageKind::Overflow(_) => (0, 0),
+                _ => (p.count, p.overflow),
+            };
+            PageInfo { id, kind, count, overflow, parent, status }
+        })
+        .collect()
+}
+
+/// 校验 meta 指向的 freelist 页面以及 B+ 树中的每一个页面：页面在文件范围内、类型正确、
+/// 没有被重复引用、元素不越界并且 key 有序。返回第一个有问题的页面
+pub(crate) fn verify_tree(buf: &[u8], meta: &Meta, order: &KeyOrder) -> Result<()> {
+    let pages = (buf.len() / PAGE_SIZE) as PgId;
+    if meta.pgid > pages {
+        return Err(Error::ErrCorrupted { pgid: pages });
+    }
+    let buf = &buf[..meta.pgid as usize * PAGE_SIZE];
+    let valid = |pgid: PgId, flag: PageFlag| -> Option<&Page> {
+        let p = page_at(buf, pgid).filter(|p| pgid >= 2 && p.id == pgid && p.flags == flag)?;
+        Some(p).filter(|p| p.elements_in_bounds() && p.verify_checksum().is_ok())
+    };
+    valid(meta.freelist, PageFlag::FreeListPage).ok_or(Error::ErrCorrupted { pgid: meta.freelist })?;
+
+    let mut visited = HashSet::new();
+    let mut stack = vec![meta.root];
+    while let Some(pgid) = stack.pop() {
+        let corrupted = || Error::ErrCorrupted { pgid };
+        let is_branch = page_at(buf, pgid).is_some_and(|p| p.flags == PageFlag::BranchPage);
+        let flag = if is_branch { PageFlag::BranchPage } else { PageFlag::LeafPage };
+        let p = valid(pgid, flag).ok_or_else(corrupted)?;
+        if !(pgid..=pgid + p.overflow as PgId).all(|id| visited.insert(id)) {
+            return Err(corrupted());
+        }
+        if is_branch {
+            let elems = p.branch_page_elements();
+            if elems.is_empty() || elems.windows(2).any(|w| order.cmp(w[0].key(), w[1].key()).is_ge()) {
+                return Err(corrupted());
+            }
+            stack.extend(elems.iter().map(|e| e.value));
+        } else if p.leaf_page_elements().windows(2).any(|w| order.cmp(w[0].key(), w[1].key()).is_ge()) {
+            return Err(corrupted());
+        }
+    }
+    Ok(())
+}
+
+/// 解码页面内容，元素越界等无法安全解码的页面返回页面的原始字节
+pub(crate) fn decode_page(buf: &[u8], pgid: PgId) -> Result<PageContents> {
+    let offset = (pgid as usize).saturating_mul(PAGE_SIZE);
+    if offset.saturating_add(PAGE_SIZE) > buf.len() {
+        return Err(format!("page {} out of range: {}", pgid, buf.len() / PAGE_SIZE).into());
+    }
+    let Some(p) = page_at(buf, pgid) else {
+        return Ok(PageContents::Raw(buf[offset..offset + PAGE_SIZE].to_vec()));
+    };
+    let raw = || buf[offset..offset + (p.overflow as usize + 1) * PAGE_SIZE].to_vec();
+    if !p.elements_in_bounds() {
+        return Ok(PageContents::Raw(raw()));
+    }
+    Ok(match PageKind::from_flags(&p.flags) {
+        PageKind::Meta => PageContents::Meta(p.meta().clone()),
+        PageKind::FreeList => {
+            let mut freelist = FreeList::default();
+            freelist.read(p);
+            PageContents::FreeList(freelist.ids)
+        }
+        PageKind::Branch => PageContents::Branch(
+            p.branch_page_elements()
+                .iter()
+                .map(|e| (e.key().to_vec(), e.value))
+                .collect(),
+        ),
+        PageKind::Leaf => PageContents::Leaf(
+            p.leaf_page_elements()
+                .iter()
+                .map(|e| (e.key().to_vec(), e.value().to_vec()))
+                .collect(),
+        ),
+        _ => PageContents::Raw(raw()),
+    })
+}
+
+/// 以 `offset  hex  |ascii|` 的格式输出数据，每行 16 字节
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' })
+            .collect();
+        out.push_str(&format!("{:08x}  {:<47}  |{}|\n", i * 16, hex.join(" "), ascii));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::temp_db;
+
+    #[test]
+    fn test_pages() {
+        let db = temp_db("rultdb_test_inspect_pages.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..1000 {
+            tx.put(format!("{:04}", i).as_bytes(), &[1u8; 100]).unwrap();
+        }
+        tx.commit().unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..500 {
+            tx.delete(format!("{:04}", i).as_bytes()).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_tx();
+        let meta = tx.meta();
+        let pages = tx.pages();
+        assert_eq!(pages.len() as PgId, meta.pgid);
+        assert!(pages.iter().all(|p| p.status != PageStatus::Unreachable));
+        assert!(pages.iter().any(|p| p.status == PageStatus::Pending || p.status == PageStatus::Free));
+
+        let root = tx.page_info(meta.root).unwrap();
+        assert_eq!(root.kind, PageKind::Branch);
+        assert_eq!(root.parent, None);
+        let leaf = pages.iter().find(|p| p.kind == PageKind::Leaf && p.status == PageStatus::InUse).unwrap();
+        assert_eq!(leaf.parent, Some(meta.root));
+        match tx.page_contents(leaf.id).unwrap() {
+            PageContents::Leaf(elems) => {
+                assert_eq!(elems.len(), leaf.count as usize);
+                assert!(elems.iter().all(|(_, v)| v == &[1u8; 100]));
+            }
+            _ => panic!("expected leaf page"),
+        }
+        assert!(tx.page_info(meta.pgid).is_err());
+        tx.close().unwrap();
+
+        let path = std::env::temp_dir().join("rultdb_test_inspect_pages.db");
+        let raw = RawFile::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(raw.meta().txid, meta.txid);
+        let raw_pages = raw.pages();
+        assert_eq!(raw_pages.len(), pages.len());
+        for (a, b) in raw_pages.iter().zip(pages.iter()) {
+            assert_eq!((a.kind, a.parent), (b.kind, b.parent));
+        }
+    }
+
+    #[test]
+    fn test_pages_during_commit() {
+        let db = temp_db("rultdb_test_inspect_concurrent.db");
+        let writer = db.clone();
+        let handle = std::thread::spawn(move || {
+            for i in 0..300 {
+                let mut tx = writer.begin_rwtx().unwrap();
+                tx.put(format!("{:05}", i).as_bytes(), &[0u8; 200]).unwrap();
+                tx.commit().unwrap();
+            }
+        });
+        // 读事务读取 freelist 时写事务可能正在修改它
+        while !handle.is_finished() {
+            let tx = db.begin_tx();
+            assert!(!tx.pages().is_empty());
+            tx.close().unwrap();
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let out = hexdump(b"rultdb\x00\x01");
+        assert_eq!(out, format!("00000000  {:<47}  |rultdb..|\n", "72 75 6c 74 64 62 00 01"));
+    }
+}
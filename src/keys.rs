@@ -0,0 +1,388 @@
+//! 保持顺序的 key 编码：编码结果按字节比较的顺序和值的顺序相同。
+//! 整数和浮点数使用定长的大端序编码，字符串中的 0 转义为 0x00 0xFF 并以 0x00 0x00 结尾，
+//! 一个值的编码不会是另一个值的编码的前缀。元组按元素顺序拼接，
+//! 所以元组前面几个元素的编码就是所有以它们开头的 key 的前缀
+
+use std::ops::Bound;
+
+use crate::error::{Error, Result};
+
+pub trait Encode {
+    fn encode_to(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    /// 从 input 开头解码一个值，并把 input 移动到值之后
+    fn decode_from(input: &mut &[u8]) -> Result<Self>;
+}
+
+pub fn encode<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode_to(&mut out);
+    out
+}
+
+/// 解码整个 key，有剩余的字节时返回错误
+pub fn decode<T: Decode>(mut input: &[u8]) -> Result<T> {
+    let value = T::decode_from(&mut input)?;
+    if !input.is_empty() {
+        return Err(Error::ErrInvalidKeyEncoding);
+    }
+    Ok(value)
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if input.len() < n {
+        return Err(Error::ErrInvalidKeyEncoding);
+    }
+    let (head, rest) = input.split_at(n);
+    *input = rest;
+    Ok(head)
+}
+
+macro_rules! unsigned {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+        }
+
+        impl Decode for $t {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                let b = take(input, size_of::<$t>())?;
+                Ok(<$t>::from_be_bytes(b.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+// 翻转符号位，负数排在正数之前
+macro_rules! signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl Encode for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_to(out);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                Ok((<$u>::decode_from(input)? ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+// 正数翻转符号位，负数翻转所有位，顺序和 total_cmp 相同
+macro_rules! float {
+    ($($t:ty => $u:ty),*) => {$(
+        impl Encode for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                (if bits & sign != 0 { !bits } else { bits ^ sign }).encode_to(out);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                let bits = <$u>::decode_from(input)?;
+                let sign = 1 << (<$u>::BITS - 1);
+                Ok(<$t>::from_bits(if bits & sign != 0 { bits ^ sign } else { !bits }))
+            }
+        }
+    )*};
+}
+
+unsigned!(u8, u16, u32, u64, u128);
+signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+float!(f32 => u32, f64 => u64);
+
+impl Encode for bool {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        match take(input, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::ErrInvalidKeyEncoding),
+        }
+    }
+}
+
+impl Encode for [u8] {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_bytes_prefix(self, out);
+        out.extend_from_slice(&[0x00, 0x00]);
+    }
+}
+
+impl Encode for str {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode_to(out);
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode_to(out);
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode_to(out);
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        let mut out = Vec::new();
+        loop {
+            match take(input, 1)?[0] {
+                0x00 => match take(input, 1)?[0] {
+                    0xFF => out.push(0x00),
+                    0x00 => return Ok(out),
+                    _ => return Err(Error::ErrInvalidKeyEncoding),
+                },
+                b => out.push(b),
+            }
+        }
+    }
+}
+
+impl Decode for String {
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(Vec::<u8>::decode_from(input)?).map_err(|_| Error::ErrInvalidKeyEncoding)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (**self).encode_to(out);
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident)+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(out);)+
+            }
+        }
+
+        impl<$($name: Decode),+> Decode for ($($name,)+) {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_from(input)?,)+))
+            }
+        }
+    };
+}
+
+tuple!(A);
+tuple!(A B);
+tuple!(A B C);
+tuple!(A B C D);
+tuple!(A B C D E);
+tuple!(A B C D E F);
+
+/// 转义之后不加结束符，得到所有以 bytes 开头的字符串编码的公共前缀
+pub fn encode_bytes_prefix(bytes: &[u8], out: &mut Vec<u8>) {
+    for &b in bytes {
+        out.push(b);
+        if b == 0x00 {
+            out.push(0xFF);
+        }
+    }
+}
+
+/// 大于所有以 prefix 开头的 key 的最小的 key。prefix 全部由 0xFF 组成时没有上界
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let i = prefix.iter().rposition(|&b| b != 0xFF)?;
+    let mut end = prefix[..=i].to_vec();
+    end[i] += 1;
+    Some(end)
+}
+
+/// 以 prefix 开头的所有 key 组成的范围。start 可以直接用于 Cursor::seek，
+/// bounds 可以传给 Tx::delete_range 等接收范围的方法
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixRange {
+    pub start: Vec<u8>,
+    pub end: Option<Vec<u8>>,
+}
+
+impl PrefixRange {
+    pub fn new(prefix: &[u8]) -> Self {
+        Self { start: prefix.to_vec(), end: prefix_end(prefix) }
+    }
+
+    /// 元组前面几个元素（编码为 T）相同的所有 key
+    pub fn of<T: Encode + ?Sized>(prefix: &T) -> Self {
+        Self::new(&encode(prefix))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key.starts_with(&self.start)
+    }
+
+    pub fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (Bound::Included(&self.start), self.end.as_deref().map_or(Bound::Unbounded, Bound::Excluded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cmp::Ordering, fmt::Debug};
+
+    use super::*;
+    use crate::db::tests::temp_db;
+
+    fn rand(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    // 随机的值两两比较，编码之后的字节顺序和值的顺序相同，并且可以解码回原来的值
+    fn check_order<T: Encode + Decode + Debug + PartialEq>(values: &[T], cmp: impl Fn(&T, &T) -> Ordering) {
+        for a in values {
+            let ea = encode(a);
+            assert_eq!(&decode::<T>(&ea).unwrap(), a);
+            for b in values {
+                assert_eq!(ea.cmp(&encode(b)), cmp(a, b), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    fn random_bytes(seed: &mut u64) -> Vec<u8> {
+        let len = rand(seed) % 6;
+        // 多产生一些 0x00 和 0xFF
+        (0..len).map(|_| [0x00, 0x01, 0xFF, b'a', b'b'][(rand(seed) % 5) as usize]).collect()
+    }
+
+    #[test]
+    fn test_integer_order() {
+        let mut seed = 0x2545F4914F6CDD1D;
+        let edges = [i64::MIN, i64::MIN + 1, -256, -1, 0, 1, 255, 256, i64::MAX - 1, i64::MAX];
+        let mut values: Vec<i64> = edges.to_vec();
+        values.extend((0..200).map(|_| rand(&mut seed) as i64 >> (rand(&mut seed) % 64)));
+        check_order(&values, |a, b| a.cmp(b));
+        let small: Vec<i8> = values.iter().map(|&v| v as i8).collect();
+        check_order(&small, |a, b| a.cmp(b));
+        let unsigned: Vec<u32> = values.iter().map(|&v| v as u32).collect();
+        check_order(&unsigned, |a, b| a.cmp(b));
+    }
+
+    #[test]
+    fn test_float_order() {
+        let mut seed = 0x9E3779B97F4A7C15;
+        let mut values = vec![f64::NEG_INFINITY, -1.5, -0.0, 0.0, f64::MIN_POSITIVE, 1.5, f64::MAX, f64::INFINITY];
+        values.extend((0..200).map(|_| f64::from_bits(rand(&mut seed))).filter(|v| !v.is_nan()));
+        for a in &values {
+            for b in &values {
+                assert_eq!(encode(a).cmp(&encode(b)), a.total_cmp(b), "{:?} {:?}", a, b);
+            }
+            assert_eq!(decode::<f64>(&encode(a)).unwrap().to_bits(), a.to_bits());
+        }
+        let singles: Vec<f32> = values.iter().map(|&v| v as f32).collect();
+        for a in &singles {
+            for b in &singles {
+                assert_eq!(encode(a).cmp(&encode(b)), a.total_cmp(b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytes_and_tuple_order() {
+        let mut seed = 0xD1B54A32D192ED03;
+        let bytes: Vec<Vec<u8>> = (0..200).map(|_| random_bytes(&mut seed)).collect();
+        check_order(&bytes, |a, b| a.cmp(b));
+
+        let tuples: Vec<(Vec<u8>, i32, bool)> = (0..200)
+            .map(|_| (random_bytes(&mut seed), (rand(&mut seed) % 5) as i32 - 2, rand(&mut seed) % 2 == 0))
+            .collect();
+        check_order(&tuples, |a, b| a.cmp(b));
+
+        assert_eq!(decode::<(String, u64)>(&encode(&("a\0b", 7u64))).unwrap(), ("a\0b".to_string(), 7));
+        assert!(matches!(decode::<u64>(&[1, 2, 3]), Err(Error::ErrInvalidKeyEncoding)));
+        assert!(matches!(decode::<u8>(&[1, 2]), Err(Error::ErrInvalidKeyEncoding)));
+        assert!(matches!(decode::<Vec<u8>>(b"abc"), Err(Error::ErrInvalidKeyEncoding)));
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+
+        // 前缀范围内的 key 正好是第一个元素相同的元组
+        let mut seed = 0xA0761D6478BD642F;
+        let range = PrefixRange::of(&(3u16,));
+        let prefix = PrefixRange::new(&{
+            let mut p = encode(&(3u16,));
+            encode_bytes_prefix(b"a", &mut p);
+            p
+        });
+        for _ in 0..500 {
+            let key = (rand(&mut seed) as u16 % 6, random_bytes(&mut seed));
+            let k = encode(&key);
+            assert_eq!(range.contains(&k), key.0 == 3);
+            assert_eq!(crate::comparator::KeyOrder::default().contains(&range.bounds(), &k), key.0 == 3);
+            assert_eq!(prefix.contains(&k), key.0 == 3 && key.1.starts_with(b"a"));
+        }
+
+        // 第一个元素是字符串时也一样，包括含有 0 的字符串
+        let range = PrefixRange::of(&("a",));
+        assert!(!range.contains(&encode(&("a\0b", 7u32))));
+        assert!(range.contains(&encode(&("a", 7u32))));
+        let bytes: Vec<Vec<u8>> = (0..100).map(|_| random_bytes(&mut seed)).collect();
+        for a in &bytes {
+            let range = PrefixRange::of(&(a,));
+            for b in &bytes {
+                let k = encode(&(b, rand(&mut seed) as u32));
+                assert_eq!(range.contains(&k), a == b, "{:?} {:?}", a, b);
+                assert_eq!(encode(b).starts_with(&encode(a)), a == b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_keys_in_tree() {
+        let db = temp_db("rultdb_test_keys_in_tree.db");
+        let mut seed = 0xE7037ED1A0B428DB;
+        let mut expected: Vec<(i64, String)> = (0..2000)
+            .map(|_| (rand(&mut seed) as i64 % 100, format!("{:x}", rand(&mut seed) % 1000)))
+            .collect();
+        let mut tx = db.begin_rwtx().unwrap();
+        for key in &expected {
+            tx.put(&encode(key), b"").unwrap();
+        }
+        tx.commit().unwrap();
+        expected.sort();
+        expected.dedup();
+
+        let tx = db.begin_tx();
+        let mut c = tx.cursor();
+        let mut item = c.first().unwrap();
+        let mut keys = Vec::new();
+        while let Some(k) = item.key() {
+            keys.push(decode::<(i64, String)>(k).unwrap());
+            item = c.next().unwrap();
+        }
+        assert_eq!(keys, expected);
+
+        let range = PrefixRange::of(&(-5i64,));
+        let first = c.seek(&range.start).unwrap().key().map(|k| decode::<(i64, String)>(k).unwrap());
+        assert_eq!(first, expected.iter().find(|k| k.0 >= -5).cloned());
+        tx.close().unwrap();
+    }
+}
@@ -6,12 +6,39 @@ pub mod node;
 pub mod cursor;
 pub mod db;
 pub mod config;
+pub mod inspect;
+pub mod surgery;
+pub mod salvage;
+pub mod dump;
+pub mod bulk;
+pub mod merge;
+pub mod ttl;
+pub mod feed;
+pub mod savepoint;
+pub mod comparator;
+pub mod keys;
+pub mod table;
+pub mod index;
+pub mod dup;
+pub mod rank;
+pub mod estimate;
 
 
 const MAX_KEY_SIZE: usize = 32768;
 
 const MAX_VALUE_SIZE: usize = (1 << 31) - 2;
 
+// 数据库内部使用的 key（例如 TTL 的过期索引）都以这个前缀开头，
+// 用户不能写入，游标也不会返回这些 key
+pub(crate) const SYSTEM_KEY_PREFIX: &[u8] = b"\xffrultdb\x00";
+
+// 大于所有系统 key 的最小 key
+pub(crate) const SYSTEM_KEY_END: &[u8] = b"\xffrultdb\x01";
+
+pub(crate) fn is_system_key(key: &[u8]) -> bool {
+    key.starts_with(SYSTEM_KEY_PREFIX)
+}
+
 pub(crate) const MIN_FILL_PERCENT: f64 = 0.1;
 
 pub(crate) const MAX_FILL_PERCENT: f64 = 1.0;
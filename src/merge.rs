@@ -0,0 +1,162 @@
+use crate::{
+    error::{Error, Result},
+    MAX_VALUE_SIZE,
+};
+
+/// 通过 Options::merge_operator 注册，Tx::merge 使用它直接修改叶子节点中的值，
+/// 不需要先 get 再 put
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// key 不存在时写入的值，默认为 operand
+    fn initial(&self, _key: &[u8], operand: &[u8]) -> Result<Vec<u8>> {
+        Ok(operand.to_vec())
+    }
+
+    /// 把 operand 合并到 key 已有的值中。返回错误时不应该修改 value
+    fn merge(&self, key: &[u8], value: &mut Vec<u8>, operand: &[u8]) -> Result<()>;
+}
+
+fn decode_u64(b: &[u8]) -> Result<u64> {
+    let b: [u8; 8] = b.try_into().map_err(|_| Error::ErrIncompatibleValue)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+/// 值和 operand 都是 8 字节大端序的 u64，相加时回绕
+pub struct U64Add;
+
+impl MergeOperator for U64Add {
+    fn name(&self) -> &str {
+        "u64_add"
+    }
+
+    fn initial(&self, _key: &[u8], operand: &[u8]) -> Result<Vec<u8>> {
+        Ok(decode_u64(operand)?.to_be_bytes().to_vec())
+    }
+
+    fn merge(&self, _key: &[u8], value: &mut Vec<u8>, operand: &[u8]) -> Result<()> {
+        let n = decode_u64(value)?.wrapping_add(decode_u64(operand)?);
+        value.copy_from_slice(&n.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// 保留较大的 u64
+pub struct U64Max;
+
+impl MergeOperator for U64Max {
+    fn name(&self) -> &str {
+        "u64_max"
+    }
+
+    fn initial(&self, _key: &[u8], operand: &[u8]) -> Result<Vec<u8>> {
+        Ok(decode_u64(operand)?.to_be_bytes().to_vec())
+    }
+
+    fn merge(&self, _key: &[u8], value: &mut Vec<u8>, operand: &[u8]) -> Result<()> {
+        let n = decode_u64(value)?.max(decode_u64(operand)?);
+        value.copy_from_slice(&n.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// 保留较小的 u64
+pub struct U64Min;
+
+impl MergeOperator for U64Min {
+    fn name(&self) -> &str {
+        "u64_min"
+    }
+
+    fn initial(&self, _key: &[u8], operand: &[u8]) -> Result<Vec<u8>> {
+        Ok(decode_u64(operand)?.to_be_bytes().to_vec())
+    }
+
+    fn merge(&self, _key: &[u8], value: &mut Vec<u8>, operand: &[u8]) -> Result<()> {
+        let n = decode_u64(value)?.min(decode_u64(operand)?);
+        value.copy_from_slice(&n.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// 把 operand 追加到值的末尾
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn merge(&self, _key: &[u8], value: &mut Vec<u8>, operand: &[u8]) -> Result<()> {
+        if value.len() + operand.len() > MAX_VALUE_SIZE {
+            return Err(Error::ErrValueTooLarge);
+        }
+        value.extend_from_slice(operand);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::{DBInner, Options};
+
+    fn open(name: &str, op: Arc<dyn MergeOperator>) -> crate::db::DB {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        DBInner::open(path.to_str().unwrap(), Options { merge_operator: Some(op), ..Default::default() }).unwrap()
+    }
+
+    #[test]
+    fn test_merge_u64_add() {
+        let db = open("rultdb_test_merge_u64_add.db", Arc::new(U64Add));
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..3000u64 {
+            tx.merge(format!("{:04}", i % 1000).as_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        assert!(matches!(tx.merge(b"0001", b"abc"), Err(Error::ErrIncompatibleValue)));
+        tx.put(b"bad", b"abc").unwrap();
+        assert!(matches!(tx.merge(b"bad", &1u64.to_be_bytes()), Err(Error::ErrIncompatibleValue)));
+        assert_eq!(tx.get(b"bad").unwrap(), Some(&b"abc"[..]));
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.merge(b"0007", &1u64.to_be_bytes()).unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.get(b"0007").unwrap(), Some(&(7 + 1007 + 2007 + 1u64).to_be_bytes()[..]));
+        assert_eq!(tx.get(b"0999").unwrap(), Some(&(999 + 1999 + 2999u64).to_be_bytes()[..]));
+        assert_eq!(tx.stats().key_n, 1001);
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_merge_builtin() {
+        let mut v = 5u64.to_be_bytes().to_vec();
+        U64Max.merge(b"k", &mut v, &3u64.to_be_bytes()).unwrap();
+        assert_eq!(v, 5u64.to_be_bytes());
+        U64Min.merge(b"k", &mut v, &3u64.to_be_bytes()).unwrap();
+        assert_eq!(v, 3u64.to_be_bytes());
+        U64Add.merge(b"k", &mut v, &u64::MAX.to_be_bytes()).unwrap();
+        assert_eq!(v, 2u64.to_be_bytes());
+
+        let db = open("rultdb_test_merge_append.db", Arc::new(Append));
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.merge(b"set", b"a,").unwrap();
+        tx.merge(b"set", b"b,").unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.merge(b"set", b"c").unwrap();
+        assert_eq!(tx.get(b"set").unwrap(), Some(&b"a,b,c"[..]));
+        tx.rollback().unwrap();
+
+        // 没有注册 merge operator
+        let db = crate::db::tests::temp_db("rultdb_test_merge_none.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        assert!(matches!(tx.merge(b"k", b"v"), Err(Error::ErrNoMergeOperator)));
+        tx.rollback().unwrap();
+    }
+}
@@ -1,6 +1,6 @@
-use std::{borrow::{Borrow, BorrowMut}, cell::{Ref, RefCell, RefMut}, collections::HashSet, sync::{Arc, Weak}};
+use std::{borrow::{Borrow, BorrowMut}, cell::{Ref, RefCell, RefMut}, collections::{HashMap, HashSet}, ops::{Bound, RangeBounds}, sync::{Arc, Weak}};
 
-use crate::{config::PAGE_SIZE, db, page::{BranchPageElement, LeafPageElement, Page, PageFlag, PgId, BRANCH_ELEMENT_SIZE, LEAF_ELEMENT_SIZE, MIN_KEY_PERPAGE, PAGE_HEADER_SIZE}, tx::Tx, MAX_FILL_PERCENT, MIN_FILL_PERCENT};
+use crate::{comparator::KeyOrder, config::PAGE_SIZE, db, merge::MergeOperator, page::{BranchPageElement, LeafPageElement, Page, PageFlag, PgId, BRANCH_COUNT_SIZE, BRANCH_ELEMENT_SIZE, LEAF_ELEMENT_SIZE, MIN_KEY_PERPAGE, PAGE_HEADER_SIZE}, tx::Tx, MAX_FILL_PERCENT, MIN_FILL_PERCENT};
 
 use crate::error::Result;
 #[derive(Clone)]
@@ -25,6 +25,9 @@ pub(crate) struct NodeInner {
     pub(crate) pgid: PgId,
     pub(crate) children: Vec<Node>,
     key: Option<Vec<u8>>,
+    order: KeyOrder,
+    // 计数 B+ 树中分支节点的 inode 记录子树的 key 数量
+    counted: bool,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -32,6 +35,8 @@ pub(crate) struct INode {
     pub(crate) pgid: PgId,
     pub(crate) key: Vec<u8>,
     pub(crate) value: Vec<u8>,
+    // 分支节点中子树的 key 数量，只在计数 B+ 树中维护
+    pub(crate) count: u64,
 }
 
 
@@ -46,6 +51,8 @@ impl NodeInner {
             pgid: 0,
             children: Vec::new(),
             key: None,
+            order: KeyOrder::default(),
+            counted: false,
         }
     }
 
@@ -59,12 +66,43 @@ impl NodeInner {
         self
     }
 
+    pub(crate) fn order(mut self, order: KeyOrder) -> NodeInner {
+        self.order = order;
+        self
+    }
+
+    pub(crate) fn counted(mut self, counted: bool) -> NodeInner {
+        self.counted = counted;
+        self
+    }
+
     pub(crate) fn build(self) -> Node {
         Node(Arc::new(RefCell::new(self)))
     }
 }
 
 
+// 复制事务中已经读取的节点并保持它们之间的父子关系，用于 savepoint
+pub(crate) fn copy_nodes(nodes: &HashMap<PgId, Node>, root: Option<&Node>) -> (HashMap<PgId, Node>, Option<Node>) {
+    let mut copies: HashMap<*const RefCell<NodeInner>, Node> = HashMap::new();
+    for n in nodes.values().chain(root) {
+        copies.entry(Arc::as_ptr(&n.0)).or_insert_with(|| {
+            let mut inner = n.node().clone();
+            inner.parent = None;
+            inner.children.clear();
+            inner.build()
+        });
+    }
+    let find = |n: &Node| copies.get(&Arc::as_ptr(&n.0)).cloned();
+    for n in nodes.values().chain(root) {
+        let copy = find(n).unwrap();
+        let parent = n.node().parent.as_ref().and_then(|p| p.upgrade()).and_then(|p| find(&p));
+        copy.node_mut().parent = parent.map(|p| WeakNode(Arc::downgrade(&p.0)));
+        copy.node_mut().children = n.node().children.iter().filter_map(find).collect();
+    }
+    (nodes.iter().map(|(id, n)| (*id, find(n).unwrap())).collect(), root.and_then(find))
+}
+
 impl Node {
     pub fn node(&self) -> Ref<NodeInner> {
         (*(self.0)).borrow()
@@ -93,10 +131,21 @@ impl Node {
     fn page_element_size(&self) -> usize {
         if self.node().is_leaf {
             return LEAF_ELEMENT_SIZE;
+        } else if self.node().counted {
+            return BRANCH_ELEMENT_SIZE + BRANCH_COUNT_SIZE;
         }
         BRANCH_ELEMENT_SIZE 
     }
 
+    // 节点中 key 的数量，分支节点为所有子树的 key 数量之和
+    pub(crate) fn key_count(&self) -> u64 {
+        let n = self.node();
+        if n.is_leaf {
+            return n.inodes.len() as u64;
+        }
+        n.inodes.iter().map(|i| i.count).sum()
+    }
+
     pub(crate) fn child_at(
         &self,
         tx: &Tx,
@@ -127,6 +176,9 @@ impl Node {
                 let elem = p.branch_page_element(i);
                 inode.pgid = elem.value;
                 inode.key = elem.key().to_vec();
+                if node_mut.counted {
+                    inode.count = p.branch_count(i);
+                }
             }
             assert!(inode.key.len() > 0, "read: zero-length inode key");
             node_mut.inodes.push(inode);
@@ -141,10 +193,10 @@ impl Node {
 
     pub(crate) fn del(&self, key: &[u8]) {
         let (exact, index) = {
-            match self
-                .node()
+            let n = self.node();
+            match n
                 .inodes
-                .binary_search_by(|inode| inode.key.as_slice().cmp(key))
+                .binary_search_by(|inode| n.order.cmp(&inode.key, key))
             {
                 Ok(v) => (true, v),
                 Err(e) => (false, e),
@@ -167,10 +219,10 @@ impl Node {
             panic!("put: zero-length new key")
         }
         let (exact, index) = {
-            match self
-                .node()
+            let n = self.node();
+            match n
                 .inodes
-                .binary_search_by(|inode| inode.key.as_slice().cmp(old_key))
+                .binary_search_by(|inode| n.order.cmp(&inode.key, old_key))
             {
                 Ok(v) => (true, v),
                 Err(e) => (false, e),
@@ -188,6 +240,27 @@ impl Node {
             assert!(inode.key.len() > 0, "put: zero-length inode key")
         }
     }
+    // 子节点写出之后更新父节点中记录的子树 key 数量
+    fn set_count(&self, key: &[u8], count: u64) {
+        let mut n = self.node_mut();
+        if let Ok(index) = n.inodes.binary_search_by(|inode| n.order.cmp(&inode.key, key)) {
+            n.inodes[index].count = count;
+        }
+    }
+
+    // 在叶子节点中直接合并 key 的值，key 不存在时插入 op.initial 的值
+    pub(crate) fn merge(&self, key: &[u8], operand: &[u8], op: &dyn MergeOperator) -> Result<()> {
+        let mut n = self.node_mut();
+        match n.inodes.binary_search_by(|inode| n.order.cmp(&inode.key, key)) {
+            Ok(index) => op.merge(key, &mut n.inodes[index].value, operand)?,
+            Err(index) => {
+                let value = op.initial(key, operand)?;
+                n.inodes.insert(index, INode { pgid: 0, key: key.to_vec(), value, count: 0 });
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn write(&self, p: &mut Page) {
         if self.node().is_leaf {
             p.flags = PageFlag::LeafPage;
@@ -197,6 +270,7 @@ impl Node {
         assert!(self.node().inodes.len() < 0xFFFF);
         p.count = self.node().inodes.len() as u16;
         if p.count == 0 {
+            p.set_checksum();
             return;
         }
 
@@ -217,6 +291,9 @@ impl Node {
                 elem.pos = unsafe { buf_ptr.sub(elem as *const BranchPageElement as usize) } as u32;
                 elem.ksize = item.key.len() as u32;
                 elem.value = item.pgid;
+                if self.node().counted {
+                    p.set_branch_count(i, item.count);
+                }
             }
             let (klen, vlen) = (item.key.len(), item.value.len());
             unsafe {
@@ -226,14 +303,15 @@ impl Node {
                 buf_ptr = buf_ptr.add(vlen);
             }
         }
+        p.set_checksum();
     }
 
 
     fn child_index(&self, key: &[u8]) -> usize {
-        match self
-            .node()
+        let n = self.node();
+        match n
             .inodes
-            .binary_search_by(|inode| inode.key.as_slice().cmp(key))
+            .binary_search_by(|inode| n.order.cmp(&inode.key, key))
         {
             Ok(v) => v,
             Err(e) => e,
@@ -256,7 +334,7 @@ impl Node {
             None => None,
             Some(mut p) => {
                 let index = p.child_index(self.node().key.as_ref().unwrap());
-                if index as isize > self.num_children() as isize - 1 {
+                if index + 1 >= p.num_children() {
                     return None;
                 }
                 Some(p.child_at(tx, index + 1, Some(WeakNode(Arc::downgrade(&p.0)))))
@@ -281,8 +359,7 @@ impl Node {
         if self.node().pgid != 0 {
             let db = tx.db().unwrap();
             db.0.freelist
-                .try_write()
-                .unwrap()
+                .write()
                 .free(tx.id(), unsafe {
                     &*db.0.page(self.node().pgid)
                 });
@@ -325,10 +402,19 @@ impl Node {
                 node_mut.is_leaf = child.node().is_leaf;
                 node_mut.inodes = child.node_mut().inodes.drain(..).collect();
                 node_mut.children = child.node_mut().children.drain(..).collect();
+                // 已经读取的子节点的父节点改为当前节点
+                for inode in node_mut.inodes.iter() {
+                    if let Some(c) = tx.0.nodes.borrow().get(&inode.pgid) {
+                        c.node_mut().parent = Some(WeakNode(Arc::downgrade(&self.0)));
+                    }
+                }
                 //删除老得叶子节点
                 child.node_mut().parent = None;
                 tx.0.nodes.borrow_mut().remove(&child.node().pgid);
                 child.free(tx);
+            } else if !self.node().is_leaf && self.node().inodes.is_empty() {
+                // 所有子节点都被删除的根节点变为空的叶子节点
+                self.node_mut().is_leaf = true;
             }
             return Ok(());
         }
@@ -349,18 +435,24 @@ impl Node {
         }
         //下面的情况是当前节点有数据
         let use_next_sibing = p.child_index(self.node().key.as_ref().unwrap()) == 0; //找到需要rebalance的节点的位置
-        let mut target = if use_next_sibing {
+        let target = if use_next_sibing {
             //当前节点是最左边的节点
-            self.next_sibling(tx).unwrap()
+            self.next_sibling(tx)
         } else {
             //左边的兄弟节点
-            self.prev_sibling(tx).unwrap()
+            self.prev_sibling(tx)
+        };
+        // 父节点只有这一个子节点（delete_range 删除了其他子节点），由父节点的 rebalance 处理
+        let Some(mut target) = target else {
+            return Ok(());
         };
         // 如果当前节点和target节点都太小了，则合并他们
         if use_next_sibing {
-            for inode in target.node().inodes.iter() {
+            let pgids: Vec<PgId> = target.node().inodes.iter().map(|inode| inode.pgid).collect();
+            for pgid in pgids {
                 //如果目标节点是当前节点的右边的兄弟节点，则将target节点合并到当前节点，
-                if let Some(child) = tx.0.nodes.borrow_mut().get_mut(&inode.pgid) {
+                let child = tx.0.nodes.borrow().get(&pgid).cloned();
+                if let Some(child) = child {
                     child.parent().unwrap().remove_child(child.clone());
                     child.node_mut().parent = Some(WeakNode(Arc::downgrade(&self.0))); //重新计算其父节点为当前节点
                                                                             //将child加入当前node的子节点中
@@ -385,8 +477,10 @@ impl Node {
         } else {
             {
                 //如果target节点是当前节点的左边的兄弟节点，则将当前节点合并到左边的兄弟节点
-                for inode in self.node().inodes.iter() {
-                    if let Some(child) = tx.0.nodes.borrow_mut().get_mut(&inode.pgid) {
+                let pgids: Vec<PgId> = self.node().inodes.iter().map(|inode| inode.pgid).collect();
+                for pgid in pgids {
+                    let child = tx.0.nodes.borrow().get(&pgid).cloned();
+                    if let Some(child) = child {
                         child.parent().unwrap().remove_child(child.clone());
                         child.node_mut().parent = Some(WeakNode(Arc::downgrade(&target.0)));
                         child
@@ -452,11 +546,107 @@ impl Node {
         let threshold = (page_size as f64 * fill_percent) as usize;
         let (split_index, _) = self.split_index(threshold);
 
-        let next = NodeInner::new().leaf(self.node().is_leaf).build();
+        let next = NodeInner::new().leaf(self.node().is_leaf).order(self.node().order.clone()).counted(self.node().counted).build();
         next.node_mut().inodes = self.node_mut().inodes.drain(split_index..).collect();
         Some(next)
     }
 
+    /// 删除 range 内的所有 key，返回删除的 key 数量。完全落在 range 内的子节点直接释放页面，
+    /// 只有和 range 边界相交的子节点会被读取为 Node
+    pub(crate) fn delete_range(&self, tx: &Tx, range: &(Bound<&[u8]>, Bound<&[u8]>)) -> u64 {
+        let order = self.node().order.clone();
+        if self.node().is_leaf {
+            let before = self.node().inodes.len();
+            self.node_mut().inodes.retain(|i| {
+                let keep = !order.contains(range, &i.key);
+                if !keep {
+                    tx.record_removed(&i.key, &i.value);
+                }
+                keep
+            });
+            let n = before - self.node().inodes.len();
+            if n > 0 {
+                self.node_mut().unbalanced = true;
+            }
+            return n as u64;
+        }
+
+        let mut count = 0;
+        let mut freed = Vec::new();
+        let len = self.node().inodes.len();
+        for i in 0..len {
+            // 子节点 i 中的 key 在 [inodes[i].key, inodes[i + 1].key) 内，第一个子节点没有下界
+            let (inside, outside) = {
+                let n = self.node();
+                let lower = if i == 0 { None } else { Some(n.inodes[i].key.as_slice()) };
+                let upper = n.inodes.get(i + 1).map(|inode| inode.key.as_slice());
+                let cmp = |a: &[u8], b: &[u8]| order.cmp(a, b);
+                let inside = match range.0 {
+                    Bound::Unbounded => true,
+                    Bound::Included(s) => lower.is_some_and(|l| cmp(s, l).is_le()),
+                    Bound::Excluded(s) => lower.is_some_and(|l| cmp(s, l).is_lt()),
+                } && match range.1 {
+                    Bound::Unbounded => true,
+                    Bound::Included(e) | Bound::Excluded(e) => upper.is_some_and(|u| cmp(u, e).is_le()),
+                };
+                let outside = match range.0 {
+                    Bound::Unbounded => false,
+                    Bound::Included(s) | Bound::Excluded(s) => upper.is_some_and(|u| cmp(u, s).is_le()),
+                } || match range.1 {
+                    Bound::Unbounded => false,
+                    Bound::Included(e) => lower.is_some_and(|l| cmp(l, e).is_gt()),
+                    Bound::Excluded(e) => lower.is_some_and(|l| cmp(l, e).is_ge()),
+                };
+                (inside, outside)
+            };
+            if inside {
+                freed.push(self.node().inodes[i].pgid);
+            } else if !outside {
+                let child = self.child_at(tx, i, Some(WeakNode(Arc::downgrade(&self.0))));
+                count += child.delete_range(tx, range);
+            }
+        }
+
+        if !freed.is_empty() {
+            let mut n = self.node_mut();
+            n.inodes.retain(|i| !freed.contains(&i.pgid));
+            n.children.retain(|c| !freed.contains(&c.node().pgid));
+            n.unbalanced = true;
+        }
+        count + freed.into_iter().map(|pgid| Self::free_subtree(tx, pgid)).sum::<u64>()
+    }
+
+    // 释放以 pgid 为根的子树，返回其中 key 的数量
+    fn free_subtree(tx: &Tx, pgid: PgId) -> u64 {
+        let node = tx.0.nodes.borrow().get(&pgid).cloned();
+        let Some(mut n) = node else {
+            return tx.free_pages(pgid);
+        };
+        let count = if n.node().is_leaf {
+            n.node().inodes.iter().for_each(|i| tx.record_removed(&i.key, &i.value));
+            n.node().inodes.len() as u64
+        } else {
+            let pgids: Vec<PgId> = n.node().inodes.iter().map(|i| i.pgid).collect();
+            pgids.into_iter().map(|id| Self::free_subtree(tx, id)).sum()
+        };
+        tx.0.nodes.borrow_mut().remove(&pgid);
+        n.node_mut().children.clear();
+        n.free(tx);
+        count
+    }
+
+    // 节点以及它的所有祖先节点都是父节点的最后一个子节点
+    fn is_rightmost(&self) -> bool {
+        let mut n = self.clone();
+        while let Some(p) = n.parent() {
+            if p.node().inodes.last().map(|i| i.pgid) != Some(n.node().pgid) {
+                return false;
+            }
+            n = p;
+        }
+        true
+    }
+
     fn node_less_than(&self, v: usize) -> bool {
         let mut sz = PAGE_HEADER_SIZE;
         let elsz = self.page_element_size();
@@ -476,9 +666,10 @@ impl Node {
             return Ok(self.clone());
         }
 
+        let order = self.node().order.clone();
         self.node_mut()
             .children
-            .sort_by(|a, b| (*a).node().inodes[0].key.cmp(&(*b).node().inodes[0].key));
+            .sort_by(|a, b| order.cmp(&a.node().inodes[0].key, &b.node().inodes[0].key));
 
         let children = self.node().children.clone();
         for child in children.iter() {
@@ -499,7 +690,8 @@ impl Node {
         let mut tx = atx.clone();
         let db = tx.db().unwrap();
 
-        let mut nodes = self.split(PAGE_SIZE, tx.0.fill_percent);
+        let fill_percent = if tx.append_mode() && self.is_rightmost() { MAX_FILL_PERCENT } else { tx.fill_percent() };
+        let mut nodes = self.split(PAGE_SIZE, fill_percent);
 
         // 这里设置父节点信息
         let parent_node = 
@@ -520,7 +712,7 @@ impl Node {
                 p.node_mut().children.extend_from_slice(&nodes[1..]);
                 Some(p)
             } else {
-                let parent = NodeInner::new().leaf(false).build();
+                let parent = NodeInner::new().leaf(false).order(self.node().order.clone()).counted(self.node().counted).build();
                 parent
                     .node_mut()
                     .children
@@ -533,8 +725,7 @@ impl Node {
             let prekey = n.node().key.clone();
             if n.node().pgid > 0 {
                 db.0.freelist
-                    .try_write()
-                    .unwrap()
+                    .write()
                     .free(tx.0.meta.borrow().txid, unsafe { &*db.0.page(n.node().pgid) });
                 n.node_mut().pgid = 0;
             }
@@ -543,8 +734,9 @@ impl Node {
             let page = p.to_page_mut();
             let new_id = page.id;
             n.node_mut().pgid = page.id;
-            let first_key = n.node().inodes.first().unwrap().key.clone();
-            n.node_mut().key = Some(first_key);
+            // 所有 key 都被删除的根节点没有第一个 key
+            let first_key = n.node().inodes.first().map(|i| i.key.clone());
+            n.node_mut().key = first_key;
             n.write(page);
             tx.0.pages.borrow_mut().insert(page.id, p);
             n.node_mut().spilled = true;
@@ -557,11 +749,13 @@ impl Node {
                     }
                     let pgid = n.node().pgid;
                     parent.put(key, key, &vec![], pgid);
+                    parent.set_count(key, n.key_count());
                 } else {
                     let n1 = n.node();
                     let inode = n1.inodes.first().unwrap();
                     let pgid = n.node().pgid;
                     parent.put(&inode.key, &inode.key, &vec![], pgid);
+                    parent.set_count(&inode.key, n.key_count());
                 }
                 if n.node().parent.is_none() {
                     n.node_mut().parent.replace(WeakNode(Arc::downgrade(&parent.0)));
@@ -569,8 +763,10 @@ impl Node {
             }
         }
 
-        if let Some(mut p) = parent_node {
-            p.node_mut().children.clear();
+        // 只有根节点分裂产生的新父节点（pgid 为 0）需要在这里继续 spill，
+        // 已存在的父节点会在它自己的 spill 中写出
+        if let Some(p) = parent_node.as_ref().filter(|p| p.node().pgid == 0) {
+            self.node_mut().children.clear();
             return p.spill(atx);
         }
 
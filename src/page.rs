@@ -2,6 +2,7 @@
 
 use std::fmt::Debug;
 use std::{marker::PhantomData, mem::offset_of};
+use crate::comparator::BYTEWISE;
 use crate::config::PAGE_SIZE;
 use crate::error::Result;
 use crate::error::Error;
@@ -12,6 +13,7 @@ use std::mem::size_of;
 use crate::tx::TxId;
 
 bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct PageFlag: u16 {
         const BranchPage = 0x01;
         const LeafPage = 0x02;
@@ -31,14 +33,29 @@ pub const BRANCH_ELEMENT_SIZE:usize = size_of::<BranchPageElement>();
 pub type PgId = u64;
 
 pub const MAGIC:u32 = 0x4499;
-pub const VERSION:u32 = 0x01;
+/// 文件格式版本：2 增加了数据页面的 checksum，3 在 meta 中增加了序列号、比较器名字和 flags。
+/// 不支持原地升级，版本不同的文件打开时返回 ErrVersionMismatch
+pub const VERSION:u32 = 0x03;
 
+/// meta 页面中记录的比较器名字的最大长度
+pub const COMPARATOR_NAME_SIZE: usize = 32;
 
+/// Meta::flags：分支页面在元素数组之后保存每个子树中 key 的数量
+pub const META_COUNTED: u32 = 0x01;
+
+/// 计数 B+ 树中每个分支元素额外占用的字节数
+pub const BRANCH_COUNT_SIZE: usize = size_of::<u64>();
+
+
+#[repr(C)]
 pub struct Page{
     pub id: PgId,
     pub flags: PageFlag,
     pub count: u16,
     pub overflow: u32,
+    /// 整个页面（包括 overflow）的 crc32，0 表示该页面没有 checksum
+    pub checksum: u32,
+    reserved: u32,
     pub ptr: PhantomData<u8>,
 }
 
@@ -108,14 +125,21 @@ impl LeafPageElement {
 }
 
 #[derive(Clone)]
+#[repr(C)]
 pub struct Meta{
     pub magic: u32,
     pub version: u32,
     pub flags: u32,
+    // 保证 checksum 覆盖的字节中没有未初始化的填充
+    pub(crate) reserved: u32,
     pub root: PgId,
     pub freelist: PgId,
     pub pgid: PgId,
     pub txid: TxId,
+    /// Tx::next_sequence 使用的序列号，和事务一起提交
+    pub sequence: u64,
+    /// 创建数据库时使用的比较器的名字，不足的部分用 0 填充
+    pub comparator: [u8; COMPARATOR_NAME_SIZE],
     pub checksum: u32,
 }
 
@@ -127,6 +151,20 @@ impl Meta {
         crc32fast::hash(data)
     }
 
+    /// 是否为计数 B+ 树，创建数据库时通过 Options::counted 决定
+    pub fn counted(&self) -> bool {
+        self.flags & META_COUNTED != 0
+    }
+
+    /// 没有记录名字时视为按字节比较
+    pub fn comparator_name(&self) -> String {
+        let len = self.comparator.iter().position(|&b| b == 0).unwrap_or(COMPARATOR_NAME_SIZE);
+        if len == 0 {
+            return BYTEWISE.to_string();
+        }
+        String::from_utf8_lossy(&self.comparator[..len]).into_owned()
+    }
+
     pub fn validate(&self) -> Result<()> {
         if self.magic != MAGIC {
             return Err(Error::ErrInvalid);
@@ -142,6 +180,7 @@ impl Meta {
         p.flags = PageFlag::MetaPage;
         p.count = 0;
         p.overflow = 0;
+        p.checksum = 0;
         *p.meta_mut() = self.clone();
     }
 
@@ -149,6 +188,28 @@ impl Meta {
 }
 
 impl Page {
+    /// 计算页面（包括 overflow）除 checksum 字段以外所有字节的 crc32，结果不会为 0
+    pub fn compute_checksum(&self) -> u32 {
+        let size = (self.overflow as usize + 1) * PAGE_SIZE;
+        let data = unsafe { std::slice::from_raw_parts(self as *const Page as *const u8, size) };
+        let offset = offset_of!(Page, checksum);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&data[..offset]);
+        hasher.update(&data[offset + size_of::<u32>()..]);
+        hasher.finalize().max(1)
+    }
+
+    pub fn set_checksum(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    pub fn verify_checksum(&self) -> Result<()> {
+        if self.checksum != 0 && self.checksum != self.compute_checksum() {
+            return Err(Error::ErrCorrupted { pgid: self.id });
+        }
+        Ok(())
+    }
+
     pub fn from_mut_buf(buf: &mut [u8]) -> &mut Page {
         unsafe{
             &mut *(buf.as_mut_ptr() as *mut Page )
@@ -220,6 +281,10 @@ impl Page {
         self.elements::<PgId>()
     }
 
+    pub(crate) fn freelist_with_size(&self, size: usize) -> &[PgId] {
+        unsafe { std::slice::from_raw_parts(self.data_ptr() as *const PgId, size) }
+    }
+
     pub(crate) fn freelist_mut(&mut self) -> &mut [PgId] {
         self.elements_mut::<PgId>()
     }
@@ -235,6 +300,17 @@ impl Page {
         self.branch_page_elements().get(index).unwrap()
     }
 
+    // 计数 B+ 树的分支页面中第 index 个子树的 key 数量，保存在元素数组之后
+    pub(crate) fn branch_count(&self, index: usize) -> u64 {
+        let offset = self.count as usize * BRANCH_ELEMENT_SIZE + index * BRANCH_COUNT_SIZE;
+        unsafe { std::ptr::read_unaligned(self.data_ptr().add(offset) as *const u64) }
+    }
+
+    pub(crate) fn set_branch_count(&mut self, index: usize, count: u64) {
+        let offset = self.count as usize * BRANCH_ELEMENT_SIZE + index * BRANCH_COUNT_SIZE;
+        unsafe { std::ptr::write_unaligned(self.data_mut_ptr().add(offset) as *mut u64, count) }
+    }
+
     pub(crate) fn leaf_page_element_mut(&mut self, index: usize) -> &mut LeafPageElement {
         self.leaf_page_elements_mut().get_mut(index).unwrap()
     }
@@ -242,6 +318,41 @@ impl Page {
     pub(crate) fn branch_page_element_mut(&mut self, index: usize) -> &mut BranchPageElement {
         self.branch_page_elements_mut().get_mut(index).unwrap()
     }
+    /// 检查元素数组以及 key/value 数据是否都落在页面（包括 overflow）范围内，
+    /// 读取可能已损坏的页面之前调用
+    pub(crate) fn elements_in_bounds(&self) -> bool {
+        let size = (self.overflow as u64 + 1) * PAGE_SIZE as u64;
+        let count = self.count as u64;
+        if self.flags.contains(PageFlag::LeafPage) {
+            if PAGE_HEADER_SIZE as u64 + count * LEAF_ELEMENT_SIZE as u64 > size {
+                return false;
+            }
+            self.leaf_page_elements().iter().enumerate().all(|(i, e)| {
+                let offset = (PAGE_HEADER_SIZE + i * LEAF_ELEMENT_SIZE) as u64;
+                offset + e.pos as u64 + e.ksize as u64 + e.vsize as u64 <= size
+            })
+        } else if self.flags.contains(PageFlag::BranchPage) {
+            if PAGE_HEADER_SIZE as u64 + count * BRANCH_ELEMENT_SIZE as u64 > size {
+                return false;
+            }
+            self.branch_page_elements().iter().enumerate().all(|(i, e)| {
+                let offset = (PAGE_HEADER_SIZE + i * BRANCH_ELEMENT_SIZE) as u64;
+                offset + e.pos as u64 + e.ksize as u64 <= size
+            })
+        } else if self.flags.contains(PageFlag::FreeListPage) {
+            let mut count = count;
+            if count == 0xFFFF {
+                if (PAGE_HEADER_SIZE + size_of::<PgId>()) as u64 > size {
+                    return false;
+                }
+                count = self.freelist_with_size(1)[0] + 1;
+            }
+            PAGE_HEADER_SIZE as u64 + count.saturating_mul(size_of::<PgId>() as u64) <= size
+        } else {
+            true
+        }
+    }
+
     pub(crate) fn page_in_buffer_mut(buf: & mut [u8], id: PgId) -> & mut Page {
         Page::from_mut_buf(&mut buf[(id as usize * PAGE_SIZE)..])
     }
@@ -0,0 +1,249 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::{
+    error::{Error, Result},
+    node::Node,
+    page::{Page, PageFlag, PgId},
+    tx::{PageNode, Tx},
+    SYSTEM_KEY_END, SYSTEM_KEY_PREFIX,
+};
+
+// 计数 B+ 树（Options::counted）在分支页面中记录每个子树的 key 数量，
+// count、rank 和 nth 只沿着一条从根到叶子的路径查找，复杂度为 O(log n)。
+// 写事务中已经读取为 Node 的子树的数量在 spill 之前可能已经变化，需要递归统计
+impl Tx {
+    /// range 内用户 key 的数量。已经过期但还没有被清理的 key 也会被统计
+    pub fn count<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> Result<u64> {
+        self.check_counted()?;
+        let mut count = 0;
+        for (start, end) in self.0.order.user_ranges(&range) {
+            let lower = match start {
+                Bound::Included(s) => self.rank_all(s, false)?,
+                Bound::Excluded(s) => self.rank_all(s, true)?,
+                Bound::Unbounded => 0,
+            };
+            let upper = match end {
+                Bound::Included(e) => self.rank_all(e, true)?,
+                Bound::Excluded(e) => self.rank_all(e, false)?,
+                Bound::Unbounded => self.subtree_count(self.root_id())?,
+            };
+            count += upper.saturating_sub(lower);
+        }
+        Ok(count)
+    }
+
+    /// 小于 key 的用户 key 的数量
+    pub fn rank(&self, key: &[u8]) -> Result<u64> {
+        self.count(..key)
+    }
+
+    /// 按顺序排列的第 i 个（从 0 开始）用户 key 和它的值
+    pub fn nth(&self, i: u64) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.check_counted()?;
+        // 跳过系统 key：按字节比较时系统 key 是中间连续的一段，否则排在所有用户 key 之后
+        let start = self.rank_all(SYSTEM_KEY_PREFIX, false)?;
+        let i = if i < start {
+            i
+        } else if self.0.order.is_bytewise() {
+            i + self.rank_all(SYSTEM_KEY_END, false)? - start
+        } else {
+            return Ok(None);
+        };
+        self.nth_all(self.root_id(), i)
+    }
+
+    fn check_counted(&self) -> Result<()> {
+        if !self.counted() {
+            return Err(Error::ErrNotCounted);
+        }
+        Ok(())
+    }
+
+    // 小于 key（inclusive 时小于等于 key）的所有 key 的数量，包括系统 key
+    fn rank_all(&self, key: &[u8], inclusive: bool) -> Result<u64> {
+        let order = &self.0.order;
+        let before = |k: &[u8]| {
+            let o = order.cmp(k, key);
+            o.is_lt() || (inclusive && o.is_eq())
+        };
+        let mut pgid = self.root_id();
+        let mut rank = 0;
+        loop {
+            match self.page_node(pgid)? {
+                PageNode::Node(n) => {
+                    let node = n.node();
+                    if node.is_leaf {
+                        return Ok(rank + node.inodes.partition_point(|i| before(&i.key)) as u64);
+                    }
+                    // 第一个 key 小于等于 key 的最后一个子节点，没有时为第一个子节点
+                    let index = node.inodes.partition_point(|i| order.cmp(&i.key, key).is_le()).saturating_sub(1);
+                    for inode in &node.inodes[..index] {
+                        rank += self.inode_count(inode.pgid, inode.count)?;
+                    }
+                    pgid = node.inodes[index].pgid;
+                }
+                PageNode::Page(p) => {
+                    let p = unsafe { &*p };
+                    if p.flags.contains(PageFlag::LeafPage) {
+                        return Ok(rank + p.leaf_page_elements().partition_point(|e| before(e.key())) as u64);
+                    }
+                    let elems = p.branch_page_elements();
+                    let index = elems.partition_point(|e| order.cmp(e.key(), key).is_le()).saturating_sub(1);
+                    rank += (0..index).map(|i| p.branch_count(i)).sum::<u64>();
+                    pgid = elems[index].value;
+                }
+            }
+        }
+    }
+
+    // 所有 key 中的第 i 个，包括系统 key
+    fn nth_all(&self, mut pgid: PgId, mut i: u64) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            match self.page_node(pgid)? {
+                PageNode::Node(n) => {
+                    let node = n.node();
+                    if node.is_leaf {
+                        return Ok(node.inodes.get(i as usize).map(|inode| (inode.key.clone(), inode.value.clone())));
+                    }
+                    let mut next = None;
+                    for inode in &node.inodes {
+                        let count = self.inode_count(inode.pgid, inode.count)?;
+                        if i < count {
+                            next = Some(inode.pgid);
+                            break;
+                        }
+                        i -= count;
+                    }
+                    match next {
+                        Some(id) => pgid = id,
+                        None => return Ok(None),
+                    }
+                }
+                PageNode::Page(p) => {
+                    let p: &Page = unsafe { &*p };
+                    if p.flags.contains(PageFlag::LeafPage) {
+                        if i >= p.count as u64 {
+                            return Ok(None);
+                        }
+                        let e = p.leaf_page_element(i as usize);
+                        return Ok(Some((e.key().to_vec(), e.value().to_vec())));
+                    }
+                    let mut next = None;
+                    for (index, e) in p.branch_page_elements().iter().enumerate() {
+                        let count = p.branch_count(index);
+                        if i < count {
+                            next = Some(e.value);
+                            break;
+                        }
+                        i -= count;
+                    }
+                    match next {
+                        Some(id) => pgid = id,
+                        None => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+
+    // 分支节点中记录的子树数量，子树已经读取为 Node 时重新统计
+    fn inode_count(&self, pgid: PgId, count: u64) -> Result<u64> {
+        if self.0.nodes.borrow().contains_key(&pgid) {
+            return self.subtree_count(pgid);
+        }
+        Ok(count)
+    }
+
+    fn subtree_count(&self, pgid: PgId) -> Result<u64> {
+        match self.page_node(pgid)? {
+            PageNode::Node(n) => self.node_count(&n),
+            PageNode::Page(p) => {
+                let p = unsafe { &*p };
+                if p.flags.contains(PageFlag::LeafPage) {
+                    return Ok(p.count as u64);
+                }
+                Ok((0..p.count as usize).map(|i| p.branch_count(i)).sum())
+            }
+        }
+    }
+
+    fn node_count(&self, n: &Node) -> Result<u64> {
+        let node = n.node();
+        if node.is_leaf {
+            return Ok(node.inodes.len() as u64);
+        }
+        let mut count = 0;
+        for inode in &node.inodes {
+            count += self.inode_count(inode.pgid, inode.count)?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{tests::temp_db, DBInner, Options};
+    use crate::error::Error;
+
+    #[test]
+    fn test_count_rank_nth() {
+        let path = std::env::temp_dir().join("rultdb_test_counted.db");
+        let _ = std::fs::remove_file(&path);
+        let db = DBInner::open(path.to_str().unwrap(), Options { counted: true, ..Default::default() }).unwrap();
+
+        // 伪随机的插入和删除，多次提交以产生分裂和合并
+        let mut x: u64 = 0x2545F4914F6CDD1D;
+        let mut rand = move || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        for round in 0..6 {
+            let mut tx = db.begin_rwtx().unwrap();
+            for _ in 0..800 {
+                let k = format!("{:06}", rand() % 5000);
+                if round % 3 == 2 && rand() % 2 == 0 {
+                    tx.delete(k.as_bytes()).unwrap();
+                } else {
+                    tx.put(k.as_bytes(), b"v").unwrap();
+                }
+            }
+            if round == 4 {
+                tx.delete_range(&b"001000"[..]..&b"002000"[..]).unwrap();
+            }
+            tx.put_with_ttl(b"000500", b"v", std::time::Duration::from_secs(3600)).unwrap();
+            // 提交之前读取为 Node 的子树也要统计正确
+            verify(&tx);
+            tx.commit().unwrap();
+
+            let tx = db.begin_tx();
+            verify(&tx);
+            assert!(tx.check().is_empty(), "{:?}", tx.check());
+            tx.close().unwrap();
+        }
+
+        let db = temp_db("rultdb_test_not_counted.db");
+        let tx = db.begin_tx();
+        assert!(matches!(tx.count(..), Err(Error::ErrNotCounted)));
+    }
+
+    fn verify(tx: &crate::tx::Tx) {
+        let mut keys = Vec::new();
+        let mut c = tx.cursor();
+        let mut item = c.first().unwrap();
+        while let Some(k) = item.key() {
+            keys.push(k.to_vec());
+            item = c.next().unwrap();
+        }
+        assert_eq!(tx.count(..).unwrap(), keys.len() as u64);
+        assert_eq!(tx.nth(keys.len() as u64).unwrap(), None);
+        for (i, k) in keys.iter().enumerate().step_by(37) {
+            assert_eq!(tx.rank(k).unwrap(), i as u64);
+            assert_eq!(tx.nth(i as u64).unwrap().unwrap().0, *k);
+        }
+        let (s, e) = (&b"000300"[..], &b"003000"[..]);
+        let expected = keys.iter().filter(|k| k.as_slice() >= s && k.as_slice() <= e).count();
+        assert_eq!(tx.count(s..=e).unwrap(), expected as u64);
+    }
+}
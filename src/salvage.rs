@@ -0,0 +1,259 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
+
+use crate::{
+    bulk::BulkLoader,
+    comparator::{self, KeyOrder, BYTEWISE},
+    config::PAGE_SIZE,
+    db::{DBInner, Options},
+    error::Result,
+    inspect::page_at,
+    page::{Page, PageFlag, PgId, MAGIC},
+    MAX_KEY_SIZE, MAX_VALUE_SIZE,
+};
+
+#[derive(Debug, Default)]
+pub struct SalvageReport {
+    /// 扫描的页面数量
+    pub pages: u64,
+    /// 成功解码的叶子页面数量
+    pub leaf_pages: u64,
+    /// 写入新数据库的 key 数量
+    pub keys: u64,
+    /// 只在 meta 不可达的页面中找到的 key，可能是已经被删除的 key
+    pub unreachable_keys: u64,
+    /// 在多个无法判断新旧的页面中值不同的 key，保留页面 id 最小的值
+    pub conflicts: u64,
+    /// 看起来是叶子页面但无法解码的页面以及原因
+    pub skipped: Vec<(PgId, String)>,
+}
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+struct Entry {
+    // 包含该值的页面可达的 meta 的 txid + 1，不可达的页面为 0
+    rank: u64,
+    value: Vec<u8>,
+}
+
+/// 不依赖 meta 页面，扫描 src 中每个页面大小的块，从看起来有效的叶子页面中恢复 key/value，
+/// 写入新的数据库 dst。同一个 key 优先使用 txid 最大的 meta 可达的页面中的值
+pub fn salvage(src: &str, dst: &str) -> Result<SalvageReport> {
+    if Path::new(dst).exists() {
+        return Err(format!("{}: already exists", dst).into());
+    }
+    let buf = std::fs::read(src).map_err(|e| ("can't read file", e))?;
+    let ranks = reachable_leaves(&buf);
+    let opt = source_options(&buf)?;
+    let order = KeyOrder::new(opt.comparator.clone());
+
+    let mut report = SalvageReport::default();
+    let mut entries: BTreeMap<Vec<u8>, Entry> = BTreeMap::new();
+    for pgid in 2..(buf.len() / PAGE_SIZE) as PgId {
+        report.pages += 1;
+        let p = Page::page_in_buffer(&buf, pgid);
+        if p.flags != PageFlag::LeafPage {
+            continue;
+        }
+        let pairs = match decode_leaf(&buf, pgid, &order) {
+            Ok(pairs) => pairs,
+            Err(reason) => {
+                report.skipped.push((pgid, reason));
+                continue;
+            }
+        };
+        report.leaf_pages += 1;
+        let rank = ranks.get(&pgid).copied().unwrap_or(0);
+        for (key, value) in pairs {
+            match entries.get_mut(&key) {
+                None => {
+                    entries.insert(key, Entry { rank, value });
+                }
+                Some(e) if rank > e.rank => *e = Entry { rank, value },
+                Some(e) if rank == e.rank && e.value != value => report.conflicts += 1,
+                Some(_) => {}
+            }
+        }
+    }
+
+    report.keys = entries.len() as u64;
+    report.unreachable_keys = entries.values().filter(|e| e.rank == 0).count() as u64;
+    // 新的数据库使用和 src 相同的比较器和计数模式
+    let mut sorted: Vec<_> = entries.iter().collect();
+    sorted.sort_by(|a, b| order.cmp(a.0, b.0));
+    let db = DBInner::open(dst, opt)?;
+    let mut tx = db.begin_rwtx()?;
+    let loaded = BulkLoader::new_raw(&mut tx).and_then(|l| l.load(sorted.into_iter().map(|(k, e)| (k, &e.value))));
+    match loaded {
+        Ok(_) => tx.commit()?,
+        Err(e) => {
+            tx.rollback()?;
+            return Err(e);
+        }
+    }
+    Ok(report)
+}
+
+// magic 正确的 meta 页面中 txid 最大的一个记录的比较器和计数模式，都不正确时使用默认选项
+fn source_options(buf: &[u8]) -> Result<Options> {
+    let metas = if buf.len() < 2 * PAGE_SIZE { Vec::new() } else { (0..2).map(|id| Page::page_in_buffer(buf, id).meta()).collect() };
+    let Some(meta) = metas.into_iter().filter(|m| m.magic == MAGIC).max_by_key(|m| m.txid) else {
+        return Ok(Options::default());
+    };
+    let comparator = match meta.comparator_name().as_str() {
+        BYTEWISE => None,
+        name => Some(comparator::builtin(name).ok_or_else(|| format!("unknown comparator {}", name))?),
+    };
+    Ok(Options { comparator, counted: meta.counted(), ..Default::default() })
+}
+
+// 从 magic 正确的 meta 页面（忽略 checksum）出发遍历 B+ 树，返回每个可达叶子页面的 rank
+fn reachable_leaves(buf: &[u8]) -> HashMap<PgId, u64> {
+    let mut ranks = HashMap::new();
+    if buf.len() < 2 * PAGE_SIZE {
+        return ranks;
+    }
+    for id in 0..2 {
+        let meta = Page::page_in_buffer(buf, id).meta();
+        if meta.magic != MAGIC {
+            continue;
+        }
+        let rank = meta.txid.saturating_add(1);
+        let mut visited = HashSet::new();
+        let mut stack = vec![meta.root];
+        while let Some(pgid) = stack.pop() {
+            let Some(p) = page_at(buf, pgid).filter(|p| pgid >= 2 && p.id == pgid && visited.insert(pgid)) else {
+                continue;
+            };
+            if p.flags == PageFlag::BranchPage && p.elements_in_bounds() {
+                stack.extend(p.branch_page_elements().iter().map(|e| e.value));
+            } else if p.flags == PageFlag::LeafPage {
+                let r = ranks.entry(pgid).or_insert(0);
+                *r = rank.max(*r);
+            }
+        }
+    }
+    ranks
+}
+
+fn decode_leaf(buf: &[u8], pgid: PgId, order: &KeyOrder) -> std::result::Result<Pairs, String> {
+    let p = page_at(buf, pgid).ok_or_else(|| "overflow out of file bounds".to_string())?;
+    if p.id != pgid {
+        return Err(format!("invalid page id {}", p.id));
+    }
+    if !p.elements_in_bounds() {
+        return Err("elements out of page bounds".to_string());
+    }
+    if p.verify_checksum().is_err() {
+        return Err("checksum mismatch".to_string());
+    }
+    let elems = p.leaf_page_elements();
+    if elems.iter().any(|e| e.ksize == 0 || e.ksize as usize > MAX_KEY_SIZE || e.vsize as usize > MAX_VALUE_SIZE) {
+        return Err("invalid key or value size".to_string());
+    }
+    if elems.windows(2).any(|w| order.cmp(w[0].key(), w[1].key()).is_ge()) {
+        return Err("keys out of order".to_string());
+    }
+    Ok(elems.iter().map(|e| (e.key().to_vec(), e.value().to_vec())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::{PageKind, PageStatus, RawFile};
+
+    fn paths(name: &str) -> (String, String) {
+        let p = |s: &str| {
+            let p = std::env::temp_dir().join(format!("rultdb_test_salvage_{}{}.db", name, s));
+            let _ = std::fs::remove_file(&p);
+            p.to_str().unwrap().to_string()
+        };
+        (p(""), p("_dst"))
+    }
+
+    fn put_all(path: &str, n: usize, value: &[u8]) {
+        let db = DBInner::open(path, Default::default()).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..n {
+            tx.put(format!("{:04}", i).as_bytes(), value).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    fn corrupt(path: &str, pgid: PgId, f: impl Fn(&mut [u8])) {
+        let mut buf = std::fs::read(path).unwrap();
+        f(&mut buf[pgid as usize * PAGE_SIZE..(pgid as usize + 1) * PAGE_SIZE]);
+        std::fs::write(path, &buf).unwrap();
+    }
+
+    #[test]
+    fn test_salvage_invalid_metas() {
+        let (src, dst) = paths("invalid_metas");
+        put_all(&src, 500, &[1u8; 50]);
+        put_all(&src, 500, &[2u8; 50]);
+        // 两个 meta 的 checksum 都无效时数据库无法打开，但仍然可以根据 txid 选择最新的值
+        for id in 0..2 {
+            corrupt(&src, id, |b| Page::from_mut_buf(b).meta_mut().checksum ^= 1);
+        }
+        assert!(RawFile::open(&src).is_err());
+
+        let report = salvage(&src, &dst).unwrap();
+        assert_eq!(report.keys, 500);
+        assert_eq!(report.unreachable_keys, 0);
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+        let db = DBInner::open(&dst, Default::default()).unwrap();
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"0123").unwrap(), Some(&[2u8; 50][..]));
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        tx.close().unwrap();
+        assert!(salvage(&src, &dst).is_err());
+    }
+
+    #[test]
+    fn test_salvage_comparator() {
+        let (src, dst) = paths("comparator");
+        let opt = || Options { comparator: comparator::builtin("reverse_bytewise"), counted: true, ..Default::default() };
+        let db = DBInner::open(&src, opt()).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..500 {
+            tx.put(format!("{:04}", i).as_bytes(), &[1u8; 50]).unwrap();
+        }
+        tx.put_with_ttl(b"9999", b"v", std::time::Duration::from_secs(3600)).unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        // 按 src 的比较器检查叶子页面中 key 的顺序，dst 使用同样的选项
+        let report = salvage(&src, &dst).unwrap();
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+        let db = DBInner::open(&dst, opt()).unwrap();
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(tx.count(..).unwrap(), 501);
+        assert_eq!(tx.cursor().first().unwrap().key(), Some(&b"9999"[..]));
+        assert!(tx.ttl(b"9999").unwrap().is_some());
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_salvage_skips_corrupted_pages() {
+        let (src, dst) = paths("corrupted_pages");
+        put_all(&src, 500, &[1u8; 50]);
+        let raw = RawFile::open(&src).unwrap();
+        let leaf = raw
+            .pages()
+            .into_iter()
+            .find(|i| i.kind == PageKind::Leaf && i.status == PageStatus::InUse)
+            .unwrap();
+        corrupt(&src, leaf.id, |b| b[PAGE_SIZE - 1] ^= 1);
+        for id in 0..2 {
+            corrupt(&src, id, |b| b.fill(0));
+        }
+
+        let report = salvage(&src, &dst).unwrap();
+        assert_eq!(report.skipped, vec![(leaf.id, "checksum mismatch".to_string())]);
+        assert_eq!(report.keys, 500 - leaf.count as u64);
+        assert_eq!(report.unreachable_keys, report.keys);
+    }
+}
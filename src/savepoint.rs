@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    error::{Error, Result},
+    freelist::FreeList,
+    node::{copy_nodes, Node},
+    page::{Meta, PgId},
+    tx::Tx,
+};
+
+/// Tx::savepoint 返回的句柄
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(u64);
+
+// 创建 savepoint 时事务的状态，节点是复制的，之后的修改不会影响它
+pub(crate) struct SavepointState {
+    id: u64,
+    meta: Meta,
+    nodes: HashMap<PgId, Node>,
+    root: Option<Node>,
+    pages: HashSet<PgId>,
+    freelist: FreeList,
+    has_ttl: Option<bool>,
+    modified: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    callbacks: (usize, usize),
+}
+
+impl Tx {
+    /// 记录写事务当前的状态，之后可以通过 rollback_to 撤销这之后的修改
+    pub fn savepoint(&self) -> Result<Savepoint> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        let db = self.db().unwrap();
+        let (nodes, root) = copy_nodes(&self.0.nodes.borrow(), self.0.root_node.borrow().as_ref());
+        let id = self.0.next_savepoint.get();
+        self.0.next_savepoint.set(id + 1);
+        self.0.savepoints.borrow_mut().push(SavepointState {
+            id,
+            meta: self.0.meta.borrow().clone(),
+            nodes,
+            root,
+            pages: self.0.pages.borrow().keys().copied().collect(),
+            freelist: db.0.freelist.read().clone(),
+            has_ttl: self.0.has_ttl.get(),
+            modified: self.0.modified.borrow().clone(),
+            callbacks: (self.0.on_commit.borrow().len(), self.0.on_rollback.borrow().len()),
+        });
+        Ok(Savepoint(id))
+    }
+
+    /// 撤销 savepoint 之后的所有修改，包括之后分配和释放的页面以及注册的回调。
+    /// savepoint 仍然可以使用，之后创建的 savepoint 失效
+    pub fn rollback_to(&mut self, sp: Savepoint) -> Result<()> {
+        let mut savepoints = self.0.savepoints.borrow_mut();
+        let i = position(&savepoints, sp)?;
+        savepoints.truncate(i + 1);
+        let state = &savepoints[i];
+
+        let (nodes, root) = copy_nodes(&state.nodes, state.root.as_ref());
+        *self.0.nodes.borrow_mut() = nodes;
+        *self.0.root_node.borrow_mut() = root;
+        *self.0.meta.borrow_mut() = state.meta.clone();
+        self.0.pages.borrow_mut().retain(|id, _| state.pages.contains(id));
+        *self.db().unwrap().0.freelist.write() = state.freelist.clone();
+        self.0.has_ttl.set(state.has_ttl);
+        *self.0.modified.borrow_mut() = state.modified.clone();
+        self.0.on_commit.borrow_mut().truncate(state.callbacks.0);
+        self.0.on_rollback.borrow_mut().truncate(state.callbacks.1);
+        self.0.last_leaf.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// 保留 savepoint 之后的修改，savepoint 和之后创建的 savepoint 失效
+    pub fn release(&mut self, sp: Savepoint) -> Result<()> {
+        let mut savepoints = self.0.savepoints.borrow_mut();
+        let i = position(&savepoints, sp)?;
+        savepoints.truncate(i);
+        Ok(())
+    }
+}
+
+fn position(savepoints: &[SavepointState], sp: Savepoint) -> Result<usize> {
+    savepoints.iter().position(|s| s.id == sp.0).ok_or(Error::ErrSavepointNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::temp_db;
+
+    fn keys(tx: &Tx) -> Vec<Vec<u8>> {
+        let mut c = tx.cursor();
+        let mut item = c.first().unwrap();
+        let mut keys = Vec::new();
+        while let Some(k) = item.key() {
+            keys.push(k.to_vec());
+            item = c.next().unwrap();
+        }
+        keys
+    }
+
+    #[test]
+    fn test_savepoint() {
+        let db = temp_db("rultdb_test_savepoint.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..2000 {
+            tx.put(format!("{:05}", i).as_bytes(), &[b'v'; 100]).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put(b"00001", b"changed").unwrap();
+        tx.next_sequence().unwrap();
+        let before = keys(&tx);
+        let sp = tx.savepoint().unwrap();
+        // 释放整个子树的页面，之后再写入
+        assert_eq!(tx.delete_range(&b"00100"[..]..&b"01900"[..]).unwrap(), 1800);
+        for i in 5000..6000 {
+            tx.put(format!("{:05}", i).as_bytes(), b"new").unwrap();
+        }
+        tx.next_sequence().unwrap();
+        let inner = tx.savepoint().unwrap();
+        tx.put(b"00001", b"inner").unwrap();
+        tx.rollback_to(sp).unwrap();
+        assert!(matches!(tx.rollback_to(inner), Err(Error::ErrSavepointNotFound)));
+        assert_eq!(keys(&tx), before);
+        assert_eq!(tx.get(b"00001").unwrap(), Some(&b"changed"[..]));
+        assert_eq!(tx.sequence(), 1);
+
+        // 回滚之后 savepoint 仍然可以使用
+        tx.delete(b"00002").unwrap();
+        tx.rollback_to(sp).unwrap();
+        assert_eq!(tx.get(b"00002").unwrap(), Some(&[b'v'; 100][..]));
+        tx.delete(b"00003").unwrap();
+        tx.release(sp).unwrap();
+        assert!(matches!(tx.release(sp), Err(Error::ErrSavepointNotFound)));
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(keys(&tx).len(), 1999);
+        assert_eq!(tx.get(b"00003").unwrap(), None);
+        assert_eq!(tx.get(b"05000").unwrap(), None);
+        assert_eq!(tx.sequence(), 1);
+        tx.close().unwrap();
+
+        let tx = db.begin_tx();
+        assert!(matches!(tx.savepoint(), Err(Error::ErrTxNotWritable)));
+        tx.close().unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use crate::{
+    config::PAGE_SIZE,
+    error::{Error, Result},
+    freelist::FreeList,
+    inspect::{page_infos, PageKind, PageStatus, RawFile},
+    node::{INode, NodeInner},
+    page::{Meta, OwnedPage, Page, PageFlag, PgId},
+};
+
+// 所有操作都先把 src 复制到 dst，然后只修改 dst，返回对 dst 做的每一处修改
+
+/// 用另一个 meta 页面覆盖当前使用的（txid 较大的）meta 页面，数据库回退到上一个事务
+pub fn revert_meta(src: &str, dst: &str) -> Result<Vec<String>> {
+    copy_file(src, dst)?;
+    let buf = std::fs::read(dst).map_err(|e| ("can't read file", e))?;
+    if buf.len() < 2 * PAGE_SIZE {
+        return Err(Error::ErrInvalid);
+    }
+    let meta0 = Page::page_in_buffer(&buf, 0).meta();
+    let meta1 = Page::page_in_buffer(&buf, 1).meta();
+    let (active, other) = if meta1.txid > meta0.txid { (1, 0) } else { (0, 1) };
+    let other_meta = Page::page_in_buffer(&buf, other).meta().clone();
+    other_meta.validate()?;
+    let active_txid = Page::page_in_buffer(&buf, active).meta().txid;
+
+    write_meta(dst, active, &other_meta)?;
+    Ok(vec![format!(
+        "meta page {}: txid {} replaced by copy of meta page {} (txid {}, root {})",
+        active, active_txid, other, other_meta.txid, other_meta.root
+    )])
+}
+
+/// 用 backup 文件中同一位置的页面（包括 overflow）覆盖 pgid
+pub fn copy_page(src: &str, dst: &str, pgid: PgId, backup: &str) -> Result<Vec<String>> {
+    let backup_buf = std::fs::read(backup).map_err(|e| ("can't read backup file", e))?;
+    let offset = pgid as usize * PAGE_SIZE;
+    if pgid < 2 || offset + PAGE_SIZE > backup_buf.len() {
+        return Err(format!("page {}: not found in backup file", pgid).into());
+    }
+    let overflow = Page::page_in_buffer(&backup_buf, pgid).overflow as usize;
+    let end = offset + (overflow + 1) * PAGE_SIZE;
+    if end > backup_buf.len() {
+        return Err(format!("page {}: overflow {} out of backup file bounds", pgid, overflow).into());
+    }
+
+    copy_file(src, dst)?;
+    let dst_buf = std::fs::read(dst).map_err(|e| ("can't read file", e))?;
+    let before = dst_buf
+        .get(offset..offset + PAGE_SIZE)
+        .map(|b| describe(Page::from_buf(b)))
+        .unwrap_or_else(|| "missing".to_string());
+    write_at(dst, &backup_buf[offset..end], offset as u64)?;
+    Ok(vec![format!(
+        "page {}: {} replaced by {} from {} ({} bytes)",
+        pgid,
+        before,
+        describe(Page::page_in_buffer(&backup_buf, pgid)),
+        backup,
+        end - offset
+    )])
+}
+
+/// 将一个（损坏的）叶子页面清空并从父节点中移除。父节点因此变空时同样清空并继续向上移除，
+/// 根节点最多被清空为一个空的叶子页面。被移除的页面不会加入 freelist，之后需要 rebuild_freelist
+pub fn clear_page(src: &str, dst: &str, pgid: PgId) -> Result<Vec<String>> {
+    let raw = RawFile::open(src)?;
+    let info = raw.page_info(pgid)?;
+    if info.status != PageStatus::InUse {
+        return Err(format!("page {}: not reachable from meta", pgid).into());
+    }
+    match info.kind {
+        PageKind::Leaf | PageKind::Unknown(_) => {}
+        kind => return Err(format!("page {}: can't clear {:?} page", pgid, kind).into()),
+    }
+
+    copy_file(src, dst)?;
+    let counted = raw.meta().counted();
+    let mut changes = Vec::new();
+    let mut target = pgid;
+    let mut parent = info.parent;
+    loop {
+        write_at(dst, &empty_leaf(target), target * PAGE_SIZE as u64)?;
+        changes.push(format!("page {}: cleared to an empty leaf", target));
+        let Some(parent_id) = parent else {
+            break;
+        };
+
+        let p = Page::page_in_buffer(raw.buf(), parent_id);
+        let count = |i: usize| if counted { p.branch_count(i) } else { 0 };
+        let removed: u64 = (0..p.count as usize).filter(|&i| p.branch_page_element(i).value == target).map(count).sum();
+        let inodes: Vec<INode> = p
+            .branch_page_elements()
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.value != target)
+            .map(|(i, e)| INode { pgid: e.value, key: e.key().to_vec(), value: Vec::new(), count: count(i) })
+            .collect();
+        changes.push(format!("page {}: removed branch element pointing to page {}", parent_id, target));
+        if !inodes.is_empty() {
+            let node = NodeInner::new().leaf(false).counted(counted).build();
+            node.node_mut().inodes = inodes;
+            let mut page = OwnedPage::from_vec(vec![0u8; (p.overflow as usize + 1) * PAGE_SIZE]);
+            let np = page.to_page_mut();
+            np.id = parent_id;
+            np.overflow = p.overflow;
+            node.write(np);
+            write_at(dst, &page.value, parent_id * PAGE_SIZE as u64)?;
+            if counted && removed > 0 {
+                changes.extend(subtract_counts(&raw, dst, parent_id, removed)?);
+            }
+            break;
+        }
+        // 父节点已经没有子节点，继续清空父节点
+        target = parent_id;
+        parent = raw.page_info(parent_id)?.parent;
+    }
+    Ok(changes)
+}
+
+/// 遍历 meta 可达的页面，把其余页面全部写入新的 freelist。新的 freelist 写在文件末尾，
+/// 并更新当前使用的 meta 页面
+pub fn rebuild_freelist(src: &str, dst: &str) -> Result<Vec<String>> {
+    let raw = RawFile::open(src)?;
+    let mut meta = raw.meta().clone();
+    let pages = raw.pages();
+    if (pages.len() as PgId) < meta.pgid {
+        return Err(format!("file too short: {} pages, meta pgid {}", pages.len(), meta.pgid).into());
+    }
+    let old_free = pages.iter().filter(|p| p.status == PageStatus::Free).count();
+
+    // 不把旧的 freelist 页面算作可达页面
+    let reachable = page_infos(raw.buf(), &Meta { freelist: 0, ..meta.clone() }, &Default::default(), &Default::default());
+    let freelist = FreeList {
+        ids: reachable
+            .iter()
+            .filter(|p| p.id >= 2 && p.id < meta.pgid && p.status != PageStatus::InUse)
+            .map(|p| p.id)
+            .collect(),
+        pending: Default::default(),
+    };
+
+    copy_file(src, dst)?;
+    let count = freelist.size() / PAGE_SIZE + 1;
+    let mut page = OwnedPage::from_vec(vec![0u8; count * PAGE_SIZE]);
+    let p = page.to_page_mut();
+    p.id = meta.pgid;
+    p.overflow = (count - 1) as u32;
+    freelist.write(p);
+    write_at(dst, &page.value, meta.pgid * PAGE_SIZE as u64)?;
+
+    let mut changes = vec![
+        format!("freelist: {} free pages (was {})", freelist.ids.len(), old_free),
+        format!("page {}: new freelist page ({} pages)", meta.pgid, count),
+    ];
+    let active = raw.meta_page();
+    changes.push(format!(
+        "meta page {}: freelist {} -> {}, pgid {} -> {}",
+        active,
+        meta.freelist,
+        meta.pgid,
+        meta.pgid,
+        meta.pgid + count as PgId
+    ));
+    meta.freelist = meta.pgid;
+    meta.pgid += count as PgId;
+    write_meta(dst, active, &meta)?;
+    Ok(changes)
+}
+
+fn copy_file(src: &str, dst: &str) -> Result<()> {
+    if Path::new(dst).exists() {
+        return Err(format!("{}: already exists", dst).into());
+    }
+    std::fs::copy(src, dst).map_err(|e| ("can't copy database file", e))?;
+    Ok(())
+}
+
+// 计数 B+ 树中从 pgid 的所有祖先节点记录的子树 key 数量中减去 removed
+fn subtract_counts(raw: &RawFile, dst: &str, mut pgid: PgId, removed: u64) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    while let Some(parent_id) = raw.page_info(pgid)?.parent {
+        let p = Page::page_in_buffer(raw.buf(), parent_id);
+        let size = (p.overflow as usize + 1) * PAGE_SIZE;
+        let mut page = OwnedPage::from_vec(raw.buf()[parent_id as usize * PAGE_SIZE..][..size].to_vec());
+        let np = page.to_page_mut();
+        if let Some(i) = (0..np.count as usize).find(|&i| np.branch_page_element(i).value == pgid) {
+            let count = np.branch_count(i).saturating_sub(removed);
+            np.set_branch_count(i, count);
+            np.set_checksum();
+            write_at(dst, &page.value, parent_id * PAGE_SIZE as u64)?;
+            changes.push(format!("page {}: key count of page {} set to {}", parent_id, pgid, count));
+        }
+        pgid = parent_id;
+    }
+    Ok(changes)
+}
+
+fn write_at(path: &str, data: &[u8], offset: u64) -> Result<()> {
+    let f: File = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| ("can't open file", e))?;
+    f.write_at(data, offset).map_err(|e| ("can't write to file", e))?;
+    f.sync_all().map_err(|e| ("can't sync file", e))?;
+    Ok(())
+}
+
+fn write_meta(path: &str, id: PgId, meta: &Meta) -> Result<()> {
+    let mut buf = vec![0u8; PAGE_SIZE];
+    let p = Page::page_in_buffer_mut(&mut buf, 0);
+    meta.clone().write(p);
+    p.id = id;
+    write_at(path, &buf, id * PAGE_SIZE as u64)
+}
+
+fn empty_leaf(pgid: PgId) -> Vec<u8> {
+    let mut buf = vec![0u8; PAGE_SIZE];
+    let p = Page::from_mut_buf(&mut buf);
+    p.id = pgid;
+    p.flags = PageFlag::LeafPage;
+    p.set_checksum();
+    buf
+}
+
+fn describe(p: &Page) -> String {
+    format!("{:?} page (count {}, overflow {})", p.flags, p.count, p.overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DBInner;
+
+    fn paths(name: &str) -> Vec<String> {
+        ["", "_dst", "_dst2"]
+            .iter()
+            .map(|s| {
+                let p = std::env::temp_dir().join(format!("rultdb_test_surgery_{}{}.db", name, s));
+                let _ = std::fs::remove_file(&p);
+                p.to_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn fill(path: &str, n: usize) {
+        let db = DBInner::open(path, Default::default()).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..n {
+            tx.put(format!("{:04}", i).as_bytes(), &[1u8; 100]).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_revert_meta() {
+        let p = paths("revert_meta");
+        fill(&p[0], 10);
+        {
+            let db = DBInner::open(&p[0], Default::default()).unwrap();
+            let mut tx = db.begin_rwtx().unwrap();
+            tx.put(b"new", b"value").unwrap();
+            tx.commit().unwrap();
+        }
+        let changes = revert_meta(&p[0], &p[1]).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(revert_meta(&p[0], &p[1]).is_err());
+
+        let db = DBInner::open(&p[1], Default::default()).unwrap();
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"new").unwrap(), None);
+        assert_eq!(tx.get(b"0009").unwrap(), Some(&[1u8; 100][..]));
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_copy_page() {
+        let p = paths("copy_page");
+        fill(&p[0], 100);
+        let root = RawFile::open(&p[0]).unwrap().meta().root;
+        let mut corrupted = std::fs::read(&p[0]).unwrap();
+        corrupted[root as usize * PAGE_SIZE..(root as usize + 1) * PAGE_SIZE].fill(0xAB);
+        std::fs::write(&p[1], &corrupted).unwrap();
+
+        copy_page(&p[1], &p[2], root, &p[0]).unwrap();
+        assert_eq!(std::fs::read(&p[2]).unwrap(), std::fs::read(&p[0]).unwrap());
+    }
+
+    #[test]
+    fn test_clear_page_and_rebuild_freelist() {
+        let p = paths("clear_page");
+        fill(&p[0], 1000);
+        let raw = RawFile::open(&p[0]).unwrap();
+        let leaf = raw
+            .pages()
+            .into_iter()
+            .find(|i| i.kind == PageKind::Leaf && i.status == PageStatus::InUse)
+            .unwrap();
+        let changes = clear_page(&p[0], &p[1], leaf.id).unwrap();
+        assert_eq!(changes.len(), 2);
+        {
+            let db = DBInner::open(&p[1], Default::default()).unwrap();
+            let tx = db.begin_tx();
+            assert_eq!(tx.check(), vec![format!("page {}: unreachable unfreed", leaf.id)]);
+            tx.close().unwrap();
+        }
+
+        rebuild_freelist(&p[1], &p[2]).unwrap();
+        let db = DBInner::open(&p[2], Default::default()).unwrap();
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        let n = tx.stats().key_n;
+        assert!(n > 0 && n < 1000);
+        assert_eq!(tx.get(b"0999").unwrap(), Some(&[1u8; 100][..]));
+        tx.close().unwrap();
+    }
+}
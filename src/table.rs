@@ -0,0 +1,460 @@
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    cursor::Cursor,
+    error::{Error, Result},
+    keys::{self, PrefixRange},
+    tx::Tx,
+    MAX_KEY_SIZE, MAX_VALUE_SIZE, SYSTEM_KEY_PREFIX,
+};
+
+// 系统 key "tbl" + 表名保存表的定义，值为编码后的 (key 类型, value 类型)
+const DEF_PREFIX: &[u8] = b"\xffrultdb\x00tbl\x00";
+// 系统 key "tab" + 编码后的表名 + 编码后的 key 保存表中的数据。
+// 表的数据在系统 key 中，不受比较器影响，也不会出现在游标和订阅中
+const DATA_PREFIX: &[u8] = b"\xffrultdb\x00tab\x00";
+
+/// 表的 key 和 value 的编码方式。Item 是解码后的类型，可以借用 mmap 中的数据
+pub trait Codec {
+    type Item<'a>;
+
+    /// 记录在表的定义中，打开表时检查
+    fn type_name() -> String;
+
+    fn encode(item: &Self::Item<'_>, out: &mut Vec<u8>);
+
+    fn decode(bytes: &[u8]) -> Result<Self::Item<'_>>;
+}
+
+/// 编码结果按字节比较的顺序和值的顺序相同，可以作为表的 key
+pub trait KeyCodec: Codec {}
+
+macro_rules! fixed {
+    ($($t:ty),*) => {$(
+        impl Codec for $t {
+            type Item<'a> = $t;
+
+            fn type_name() -> String {
+                stringify!($t).to_string()
+            }
+
+            fn encode(item: &$t, out: &mut Vec<u8>) {
+                keys::Encode::encode_to(item, out);
+            }
+
+            fn decode(bytes: &[u8]) -> Result<$t> {
+                keys::decode(bytes)
+            }
+        }
+
+        impl KeyCodec for $t {}
+    )*};
+}
+
+fixed!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64, bool);
+
+// 字节串和字符串单独作为 key 或 value 时不需要转义
+impl Codec for &[u8] {
+    type Item<'a> = &'a [u8];
+
+    fn type_name() -> String {
+        "bytes".to_string()
+    }
+
+    fn encode(item: &&[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(item);
+    }
+
+    fn decode(bytes: &[u8]) -> Result<&[u8]> {
+        Ok(bytes)
+    }
+}
+
+impl Codec for &str {
+    type Item<'a> = &'a str;
+
+    fn type_name() -> String {
+        "str".to_string()
+    }
+
+    fn encode(item: &&str, out: &mut Vec<u8>) {
+        out.extend_from_slice(item.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<&str> {
+        std::str::from_utf8(bytes).map_err(|_| Error::ErrIncompatibleValue)
+    }
+}
+
+impl Codec for Vec<u8> {
+    type Item<'a> = Vec<u8>;
+
+    fn type_name() -> String {
+        <&[u8]>::type_name()
+    }
+
+    fn encode(item: &Vec<u8>, out: &mut Vec<u8>) {
+        out.extend_from_slice(item);
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec for String {
+    type Item<'a> = String;
+
+    fn type_name() -> String {
+        <&str>::type_name()
+    }
+
+    fn encode(item: &String, out: &mut Vec<u8>) {
+        out.extend_from_slice(item.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        <&str>::decode(bytes).map(|s| s.to_string())
+    }
+}
+
+impl KeyCodec for &[u8] {}
+impl KeyCodec for &str {}
+impl KeyCodec for Vec<u8> {}
+impl KeyCodec for String {}
+
+// 元组使用 keys 模块的编码，元素类型需要同时实现 keys::Encode 和 keys::Decode
+macro_rules! tuple {
+    ($($name:ident)+) => {
+        impl<$($name: Codec + keys::Encode + keys::Decode),+> Codec for ($($name,)+) {
+            type Item<'a> = ($($name,)+);
+
+            fn type_name() -> String {
+                format!("({})", [$($name::type_name()),+].join(","))
+            }
+
+            fn encode(item: &Self, out: &mut Vec<u8>) {
+                keys::Encode::encode_to(item, out);
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self> {
+                keys::decode(bytes)
+            }
+        }
+
+        impl<$($name: Codec + keys::Encode + keys::Decode),+> KeyCodec for ($($name,)+) {}
+    };
+}
+
+tuple!(A);
+tuple!(A B);
+tuple!(A B C);
+tuple!(A B C D);
+
+/// 表的名字和类型，通常定义为常量：
+/// `const USERS: TableDefinition<&str, u64> = TableDefinition::new("users");`
+pub struct TableDefinition<K: KeyCodec, V: Codec> {
+    name: &'static str,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: KeyCodec, V: Codec> TableDefinition<K, V> {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name, _marker: PhantomData }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<K: KeyCodec, V: Codec> Clone for TableDefinition<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: KeyCodec, V: Codec> Copy for TableDefinition<K, V> {}
+
+fn def_key(name: &str) -> Vec<u8> {
+    [DEF_PREFIX, name.as_bytes()].concat()
+}
+
+fn data_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = DATA_PREFIX.to_vec();
+    keys::Encode::encode_to(name, &mut prefix);
+    prefix
+}
+
+// 游标在 key 处的值，key 不存在时返回 None
+pub(crate) fn get_raw<'a>(tx: &Tx, key: &[u8]) -> Result<Option<&'a [u8]>> {
+    let item = tx.raw_cursor().seek(key)?;
+    Ok(if item.key() == Some(key) { item.value() } else { None })
+}
+
+impl Tx {
+    /// 打开表。写事务中表不存在时创建表，只读事务中返回 ErrTableNotFound。
+    /// 表已经存在并且 key 或 value 的类型与定义不同时返回 ErrTableTypeMismatch
+    pub fn open_table<K: KeyCodec, V: Codec>(&mut self, def: TableDefinition<K, V>) -> Result<Table<K, V>> {
+        if def.name.is_empty() || def_key(def.name).len() > MAX_KEY_SIZE {
+            return Err(Error::ErrKeyRequired);
+        }
+        let expected = keys::encode(&(K::type_name(), V::type_name()));
+        match get_raw(self, &def_key(def.name))? {
+            Some(found) if found == expected => {}
+            Some(found) => {
+                let (k, v) = keys::decode::<(String, String)>(found)?;
+                return Err(Error::ErrTableTypeMismatch {
+                    name: def.name.to_string(),
+                    expected: format!("{}, {}", K::type_name(), V::type_name()),
+                    found: format!("{}, {}", k, v),
+                });
+            }
+            None if self.writable() => self.put_raw(&def_key(def.name), &expected)?,
+            None => return Err(Error::ErrTableNotFound(def.name.to_string())),
+        }
+        Ok(Table { tx: self.clone(), prefix: data_prefix(def.name), _marker: PhantomData })
+    }
+
+    /// 删除表的定义和所有数据，返回表是否存在
+    pub fn delete_table(&mut self, name: &str) -> Result<bool> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        if get_raw(self, &def_key(name))?.is_none() {
+            return Ok(false);
+        }
+        let range = PrefixRange::new(&data_prefix(name));
+        let mut keys = Vec::new();
+        let mut c = self.raw_cursor();
+        let mut item = c.seek(&range.start)?;
+        while let Some(k) = item.key().filter(|k| range.contains(k)) {
+            keys.push(k.to_vec());
+            item = c.next()?;
+        }
+        for k in keys.iter() {
+            self.delete_raw(k)?;
+        }
+        self.delete_raw(&def_key(name))?;
+        Ok(true)
+    }
+
+    /// 所有表的名字
+    pub fn list_tables(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut c = self.raw_cursor();
+        let mut item = c.seek(DEF_PREFIX)?;
+        while let Some(name) = item.key().and_then(|k| k.strip_prefix(DEF_PREFIX)) {
+            names.push(String::from_utf8_lossy(name).into_owned());
+            item = c.next()?;
+        }
+        Ok(names)
+    }
+}
+
+/// Tx::open_table 返回的表，读取的 key 和 value 在访问时才解码
+pub struct Table<K: KeyCodec, V: Codec> {
+    tx: Tx,
+    prefix: Vec<u8>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: KeyCodec, V: Codec> Table<K, V> {
+    fn key(&self, key: &K::Item<'_>) -> Result<Vec<u8>> {
+        let mut k = self.prefix.clone();
+        K::encode(key, &mut k);
+        if k.len() > MAX_KEY_SIZE {
+            return Err(Error::ErrKeyTooLarge);
+        }
+        Ok(k)
+    }
+
+    pub fn get(&self, key: &K::Item<'_>) -> Result<Option<V::Item<'_>>> {
+        get_raw(&self.tx, &self.key(key)?)?.map(V::decode).transpose()
+    }
+
+    pub fn insert(&mut self, key: &K::Item<'_>, value: &V::Item<'_>) -> Result<()> {
+        if !self.tx.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        let k = self.key(key)?;
+        let mut v = Vec::new();
+        V::encode(value, &mut v);
+        if v.len() > MAX_VALUE_SIZE {
+            return Err(Error::ErrValueTooLarge);
+        }
+        self.tx.put_raw(&k, &v)
+    }
+
+    /// 删除 key，返回 key 是否存在
+    pub fn remove(&mut self, key: &K::Item<'_>) -> Result<bool> {
+        if !self.tx.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        let k = self.key(key)?;
+        if get_raw(&self.tx, &k)?.is_none() {
+            return Ok(false);
+        }
+        self.tx.delete_raw(&k)?;
+        Ok(true)
+    }
+
+    /// 按 key 的顺序遍历 range 内的数据
+    pub fn range<'k, R: RangeBounds<K::Item<'k>>>(&self, range: R) -> Result<Range<'_, K, V>> {
+        let table = PrefixRange::new(&self.prefix);
+        let end = match range.end_bound() {
+            Bound::Included(k) => Bound::Included(self.key(k)?),
+            Bound::Excluded(k) => Bound::Excluded(self.key(k)?),
+            Bound::Unbounded => table.end.map_or(Bound::Unbounded, Bound::Excluded),
+        };
+        let mut cursor = self.tx.raw_cursor();
+        let first = match range.start_bound() {
+            Bound::Included(k) => cursor.seek(&self.key(k)?)?,
+            Bound::Excluded(k) => {
+                let k = self.key(k)?;
+                let item = cursor.seek(&k)?;
+                if item.key() == Some(&k[..]) { cursor.next()? } else { item }
+            }
+            Bound::Unbounded => cursor.seek(&table.start)?,
+        };
+        let first = first.key().zip(first.value());
+        Ok(Range { cursor, first, prefix: self.prefix.len(), end, _marker: PhantomData })
+    }
+
+    pub fn iter(&self) -> Result<Range<'_, K, V>> {
+        self.range::<std::ops::RangeFull>(..)
+    }
+}
+
+/// 表中的一项，key 和 value 在调用时才解码
+pub struct Entry<'a, K: KeyCodec, V: Codec> {
+    key: &'a [u8],
+    value: &'a [u8],
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<'a, K: KeyCodec, V: Codec> Entry<'a, K, V> {
+    pub fn key(&self) -> Result<K::Item<'a>> {
+        K::decode(self.key)
+    }
+
+    pub fn value(&self) -> Result<V::Item<'a>> {
+        V::decode(self.value)
+    }
+
+    /// 编码后的 key，不包括表的前缀
+    pub fn raw_key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    pub fn raw_value(&self) -> &'a [u8] {
+        self.value
+    }
+}
+
+pub struct Range<'t, K: KeyCodec, V: Codec> {
+    cursor: Cursor,
+    first: Option<(&'t [u8], &'t [u8])>,
+    prefix: usize,
+    end: Bound<Vec<u8>>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<'t, K: KeyCodec, V: Codec> Iterator for Range<'t, K, V> {
+    type Item = Result<Entry<'t, K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = match self.first.take() {
+            Some(kv) => kv,
+            None => {
+                let item = match self.cursor.next() {
+                    Ok(item) => item,
+                    Err(e) => return Some(Err(e)),
+                };
+                item.key().zip(item.value())?
+            }
+        };
+        let in_range = match &self.end {
+            Bound::Included(e) => k <= &e[..],
+            Bound::Excluded(e) => k < &e[..],
+            Bound::Unbounded => k.starts_with(SYSTEM_KEY_PREFIX),
+        };
+        if !in_range {
+            // 之后不再移动游标
+            self.end = Bound::Excluded(Vec::new());
+            return None;
+        }
+        Some(Ok(Entry { key: &k[self.prefix..], value: v, _marker: PhantomData }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::temp_db;
+
+    const USERS: TableDefinition<&str, u64> = TableDefinition::new("users");
+    const EVENTS: TableDefinition<(i64, String), &[u8]> = TableDefinition::new("events");
+
+    #[test]
+    fn test_table() {
+        let db = temp_db("rultdb_test_table.db");
+        let mut tx = db.begin_rwtx().unwrap();
+        let mut users = tx.open_table(USERS).unwrap();
+        users.insert(&"alice", &30).unwrap();
+        users.insert(&"bob", &25).unwrap();
+        users.insert(&"carol", &41).unwrap();
+        assert_eq!(users.get(&"bob").unwrap(), Some(25));
+        assert!(users.remove(&"bob").unwrap());
+        assert!(!users.remove(&"bob").unwrap());
+
+        let mut events = tx.open_table(EVENTS).unwrap();
+        for i in -50i64..50 {
+            events.insert(&(i, format!("e{}", i)), &&b"payload"[..]).unwrap();
+        }
+        // 表的数据不会出现在普通的游标中
+        tx.put(b"plain", b"v").unwrap();
+        assert_eq!(tx.cursor().first().unwrap().key(), Some(&b"plain"[..]));
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.list_tables().unwrap(), vec!["events".to_string(), "users".to_string()]);
+        let users = tx.open_table(USERS).unwrap();
+        let all: Vec<(&str, u64)> = users.iter().unwrap().map(|e| e.unwrap()).map(|e| (e.key().unwrap(), e.value().unwrap())).collect();
+        assert_eq!(all, vec![("alice", 30), ("carol", 41)]);
+
+        let events = tx.open_table(EVENTS).unwrap();
+        let keys: Vec<i64> = events
+            .range((-3, String::new())..(2, String::new()))
+            .unwrap()
+            .map(|e| e.unwrap().key().unwrap().0)
+            .collect();
+        assert_eq!(keys, (-3..2).collect::<Vec<_>>());
+        let last = events.range((Bound::Excluded((48, "e48".to_string())), Bound::Unbounded)).unwrap();
+        assert_eq!(last.map(|e| e.unwrap().value().unwrap()).collect::<Vec<_>>(), vec![&b"payload"[..]]);
+
+        const WRONG: TableDefinition<&str, i64> = TableDefinition::new("users");
+        assert!(matches!(
+            tx.open_table(WRONG),
+            Err(Error::ErrTableTypeMismatch { expected, found, .. }) if expected == "str, i64" && found == "str, u64"
+        ));
+        const MISSING: TableDefinition<u32, u32> = TableDefinition::new("missing");
+        assert!(matches!(tx.open_table(MISSING), Err(Error::ErrTableNotFound(_))));
+        tx.close().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        assert!(tx.delete_table("events").unwrap());
+        assert!(!tx.delete_table("events").unwrap());
+        let events = tx.open_table(EVENTS).unwrap();
+        assert_eq!(events.iter().unwrap().count(), 0);
+        assert_eq!(tx.open_table(USERS).unwrap().iter().unwrap().count(), 2);
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        tx.close().unwrap();
+    }
+}
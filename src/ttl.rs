@@ -0,0 +1,531 @@
+use std::{
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::{
+    db::DB,
+    error::{Error, Result},
+    table::get_raw,
+    tx::Tx,
+    is_system_key, MAX_KEY_SIZE,
+};
+
+pub(crate) const DEFAULT_TTL_SWEEP_BATCH: usize = 100;
+
+// 系统 key "exp" + key 保存 key 的过期时间（毫秒，大端序 u64）
+const EXPIRY_PREFIX: &[u8] = b"\xffrultdb\x00exp\x00";
+// 系统 key "ttl" + 过期时间 + key 是按过期时间排序的索引，值为空
+const INDEX_PREFIX: &[u8] = b"\xffrultdb\x00ttl\x00";
+
+/// 判断 key 是否过期时使用的时钟，返回从 UNIX_EPOCH 开始的时间
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+}
+
+/// 只有调用 set 或 advance 时才会改变的时钟，用于测试
+#[derive(Default)]
+pub struct ManualClock {
+    millis: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: Duration) -> Self {
+        ManualClock { millis: AtomicU64::new(now.as_millis() as u64) }
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.millis.store(now.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.millis.fetch_add(d.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+}
+
+fn expiry_key(key: &[u8]) -> Vec<u8> {
+    [EXPIRY_PREFIX, key].concat()
+}
+
+fn index_key(at: u64, key: &[u8]) -> Vec<u8> {
+    [INDEX_PREFIX, &at.to_be_bytes(), key].concat()
+}
+
+fn decode_time(v: &[u8]) -> Result<u64> {
+    let b: [u8; 8] = v.try_into().map_err(|_| Error::Unexpected("invalid ttl entry".to_string()))?;
+    Ok(u64::from_be_bytes(b))
+}
+
+impl Tx {
+    /// 写入 key，ttl 之后 key 对 get 和游标不可见，并在之后的写事务中被删除。
+    /// 对 key 调用 put 或 delete 会清除它的 TTL
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        if is_system_key(key) {
+            return Err(Error::ErrReservedKey);
+        } else if INDEX_PREFIX.len() + 8 + key.len() > MAX_KEY_SIZE {
+            return Err(Error::ErrKeyTooLarge);
+        }
+        self.put(key, value)?;
+        let at = self.now().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64);
+        self.put_raw(&expiry_key(key), &at.to_be_bytes())?;
+        self.put_raw(&index_key(at, key), &[])?;
+        self.0.has_ttl.set(Some(true));
+        Ok(())
+    }
+
+    /// key 剩余的存活时间，key 不存在或者没有设置 TTL 时返回 None
+    pub fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        if is_system_key(key) || get_raw(self, key)?.is_none() {
+            return Ok(None);
+        }
+        // 只读取一次时钟，和判断 key 是否已经过期使用同一个时间
+        let now = self.now();
+        match self.expire_at(key)? {
+            Some(at) if at <= now => Ok(None),
+            at => Ok(at.map(|at| Duration::from_millis(at.saturating_sub(now)))),
+        }
+    }
+
+    /// 删除最多 limit 个已经过期的 key，返回删除的数量
+    pub fn sweep_expired(&mut self, limit: usize) -> Result<usize> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        if !self.has_ttl()? {
+            return Ok(0);
+        }
+        let now = self.now();
+        let mut expired = Vec::new();
+        let mut c = self.raw_cursor();
+        let mut item = c.seek(INDEX_PREFIX)?;
+        while let Some(k) = item.key() {
+            let Some(rest) = k.strip_prefix(INDEX_PREFIX).filter(|r| r.len() >= 8) else {
+                break;
+            };
+            let at = decode_time(&rest[..8])?;
+            if at > now || expired.len() >= limit {
+                break;
+            }
+            expired.push((k.to_vec(), rest[8..].to_vec(), at));
+            item = c.next()?;
+        }
+
+        let mut count = 0;
+        for (index, key, at) in expired {
+            self.delete_raw(&index)?;
+            // 索引可能已经过时，只删除过期时间和索引一致的 key
+            if self.expire_at(&key)? == Some(at) {
+                self.delete_raw(&expiry_key(&key))?;
+                self.delete_raw(&key)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn now(&self) -> u64 {
+        self.db().unwrap().0.clock.now().as_millis() as u64
+    }
+
+    pub(crate) fn has_ttl(&self) -> Result<bool> {
+        if let Some(b) = self.0.has_ttl.get() {
+            return Ok(b);
+        }
+        let mut c = self.raw_cursor();
+        let b = c.seek(EXPIRY_PREFIX)?.key().is_some_and(|k| k.starts_with(EXPIRY_PREFIX));
+        self.0.has_ttl.set(Some(b));
+        Ok(b)
+    }
+
+    fn expire_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        let k = expiry_key(key);
+        let mut c = self.raw_cursor();
+        let item = c.seek_item(&k)?;
+        match (item.key(), item.value()) {
+            (Some(found), Some(v)) if found == k.as_slice() => Ok(Some(decode_time(v)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// 系统 key 和已经过期的 key 对游标和 get 不可见
+    pub(crate) fn is_hidden(&self, key: &[u8]) -> Result<bool> {
+        if is_system_key(key) {
+            return Ok(true);
+        }
+        if !self.has_ttl()? {
+            return Ok(false);
+        }
+        Ok(self.expire_at(key)?.is_some_and(|at| at <= self.now()))
+    }
+
+    // 删除 key 的过期时间和索引
+    pub(crate) fn clear_ttl(&mut self, key: &[u8]) -> Result<()> {
+        if !self.has_ttl()? {
+            return Ok(());
+        }
+        if let Some(at) = self.expire_at(key)? {
+            self.delete_raw(&expiry_key(key))?;
+            self.delete_raw(&index_key(at, key))?;
+        }
+        Ok(())
+    }
+
+    // key 已经过期时删除它，之后的写入不会继承过期时间
+    pub(crate) fn purge_expired(&mut self, key: &[u8]) -> Result<()> {
+        if self.has_ttl()? && self.is_hidden(key)? {
+            self.clear_ttl(key)?;
+            self.delete_raw(key)?;
+        }
+        Ok(())
+    }
+
+    // 删除 range 内所有 key 的过期时间和索引
+    pub(crate) fn clear_ttl_range(&mut self, range: &(Bound<&[u8]>, Bound<&[u8]>)) -> Result<()> {
+        if !self.has_ttl()? {
+            return Ok(());
+        }
+        // 过期时间按 key 的字节顺序保存，使用比较器时需要检查所有 key
+        let bytewise = self.0.order.is_bytewise();
+        let start = match range.0 {
+            Bound::Included(s) | Bound::Excluded(s) if bytewise => expiry_key(s),
+            _ => EXPIRY_PREFIX.to_vec(),
+        };
+        let mut entries = Vec::new();
+        let mut c = self.raw_cursor();
+        let mut item = c.seek(&start)?;
+        while let (Some(k), Some(v)) = (item.key(), item.value()) {
+            let Some(key) = k.strip_prefix(EXPIRY_PREFIX) else {
+                break;
+            };
+            if self.0.order.contains(range, key) {
+                entries.push((key.to_vec(), decode_time(v)?));
+            } else if bytewise && !matches!(range.0, Bound::Excluded(s) if s == key) {
+                break;
+            }
+            item = c.next()?;
+        }
+        for (key, at) in entries {
+            self.delete_raw(&expiry_key(&key))?;
+            self.delete_raw(&index_key(at, &key))?;
+        }
+        Ok(())
+    }
+}
+
+/// DB::start_sweeper 启动的后台线程，stop 或 drop 时结束
+pub struct Sweeper {
+    shared: Arc<SweeperShared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct SweeperShared {
+    stop: Mutex<bool>,
+    cond: Condvar,
+    errors: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+// 连续失败时等待时间加倍，最多为 interval 的 64 倍
+const MAX_SWEEP_BACKOFF: u32 = 64;
+
+impl DB {
+    /// 启动一个后台线程，每隔 interval 删除过期的 key，每个写事务最多删除 batch 个。
+    /// 删除失败时线程不会退出，而是延长等待时间后重试，错误可以通过 Sweeper::last_error 查看
+    pub fn start_sweeper(&self, interval: Duration, batch: usize) -> Sweeper {
+        let shared = Arc::new(SweeperShared {
+            stop: Mutex::new(false),
+            cond: Condvar::new(),
+            errors: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&self.0);
+        let s = shared.clone();
+        let handle = std::thread::spawn(move || {
+            let mut backoff = 1;
+            loop {
+                {
+                    let mut stopped = s.stop.lock();
+                    if !*stopped {
+                        s.cond.wait_for(&mut stopped, interval * backoff);
+                    }
+                    if *stopped {
+                        return;
+                    }
+                }
+                let Some(inner) = weak.upgrade() else {
+                    return;
+                };
+                match DB(inner).sweep(batch.max(1)) {
+                    Ok(()) => backoff = 1,
+                    Err(e) => {
+                        s.errors.fetch_add(1, Ordering::Relaxed);
+                        *s.last_error.lock() = Some(e.to_string());
+                        backoff = (backoff * 2).min(MAX_SWEEP_BACKOFF);
+                    }
+                }
+            }
+        });
+        Sweeper { shared, handle: Some(handle) }
+    }
+
+    fn sweep(&self, batch: usize) -> Result<()> {
+        loop {
+            let mut tx = self.begin_rwtx()?;
+            let n = match tx.sweep_expired(batch) {
+                Ok(n) => n,
+                Err(e) => {
+                    tx.rollback()?;
+                    return Err(e);
+                }
+            };
+            if n == 0 {
+                return tx.rollback();
+            }
+            tx.commit()?;
+            if n < batch {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Sweeper {
+    pub fn stop(self) {}
+
+    /// 后台删除失败的次数
+    pub fn error_count(&self) -> u64 {
+        self.shared.errors.load(Ordering::Relaxed)
+    }
+
+    /// 最近一次删除失败的错误
+    pub fn last_error(&self) -> Option<String> {
+        self.shared.last_error.lock().clone()
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        *self.shared.stop.lock() = true;
+        self.shared.cond.notify_all();
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{DBInner, Options};
+
+    fn open(name: &str, clock: Arc<ManualClock>, ttl_sweep_batch: usize) -> DB {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let opt = Options { clock: Some(clock), ttl_sweep_batch, ..Default::default() };
+        DBInner::open(path.to_str().unwrap(), opt).unwrap()
+    }
+
+    fn keys(tx: &Tx, raw: bool) -> Vec<Vec<u8>> {
+        let mut c = if raw { tx.raw_cursor() } else { tx.cursor() };
+        let mut item = c.first().unwrap();
+        let mut keys = Vec::new();
+        while let Some(k) = item.key() {
+            keys.push(k.to_vec());
+            item = c.next().unwrap();
+        }
+        keys
+    }
+
+    // 每次读取都前进 step 的时钟
+    struct TickingClock {
+        millis: AtomicU64,
+        step: u64,
+    }
+
+    impl Clock for TickingClock {
+        fn now(&self) -> Duration {
+            Duration::from_millis(self.millis.fetch_add(self.step, Ordering::SeqCst))
+        }
+    }
+
+    #[test]
+    fn test_ttl_reads_clock_once() {
+        let path = std::env::temp_dir().join("rultdb_test_ttl_ticking.db");
+        let _ = std::fs::remove_file(&path);
+        let clock = Arc::new(TickingClock { millis: AtomicU64::new(1_000_000), step: 10_000 });
+        let opt = Options { clock: Some(clock), ttl_sweep_batch: 0, ..Default::default() };
+        let db = DBInner::open(path.to_str().unwrap(), opt).unwrap();
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put_with_ttl(b"k", b"v", Duration::from_secs(15)).unwrap();
+        // 剩余时间随着时钟减少，过期之后返回 None
+        let mut last = Duration::MAX;
+        while let Some(ttl) = tx.ttl(b"k").unwrap() {
+            assert!(ttl < last && ttl <= Duration::from_secs(15), "{:?}", ttl);
+            last = ttl;
+        }
+        assert_eq!(tx.ttl(b"k").unwrap(), None);
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let db = open("rultdb_test_ttl_expiry.db", clock.clone(), DEFAULT_TTL_SWEEP_BATCH);
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..300 {
+            let k = format!("{:04}", i);
+            match i % 3 {
+                0 => tx.put(k.as_bytes(), b"v").unwrap(),
+                1 => tx.put_with_ttl(k.as_bytes(), b"v", Duration::from_secs(10)).unwrap(),
+                _ => tx.put_with_ttl(k.as_bytes(), b"v", Duration::from_secs(20)).unwrap(),
+            }
+        }
+        assert!(matches!(tx.put(b"\xffrultdb\x00exp\x000000", b"v"), Err(Error::ErrReservedKey)));
+        assert_eq!(tx.ttl(b"0001").unwrap(), Some(Duration::from_secs(10)));
+        assert_eq!(tx.ttl(b"0000").unwrap(), None);
+        tx.commit().unwrap();
+
+        let tx = db.begin_tx();
+        assert_eq!(keys(&tx, false).len(), 300);
+        tx.close().unwrap();
+
+        clock.advance(Duration::from_secs(15));
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"0001").unwrap(), None);
+        assert_eq!(tx.get(b"0002").unwrap(), Some(&b"v"[..]));
+        assert_eq!(keys(&tx, false).len(), 200);
+        assert_eq!(tx.cursor().seek(b"0001").unwrap().key(), Some(&b"0002"[..]));
+        tx.close().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        // 过期的 key 被视为不存在，写入之后不再继承过期时间
+        assert!(tx.put_if_absent(b"0004", b"new").unwrap());
+        // put 清除 TTL
+        tx.put(b"0005", b"new").unwrap();
+        tx.commit().unwrap();
+
+        clock.advance(Duration::from_secs(10));
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.get(b"0004").unwrap(), Some(&b"new"[..]));
+        assert_eq!(tx.get(b"0005").unwrap(), Some(&b"new"[..]));
+        assert_eq!(tx.get(b"0002").unwrap(), None);
+        // 提交时删除一批过期的 key
+        tx.commit().unwrap();
+        let tx = db.begin_tx();
+        assert!(tx.check().is_empty(), "{:?}", tx.check());
+        assert_eq!(keys(&tx, false).len(), 102);
+        assert!(keys(&tx, true).len() < 3 * 200 + 102);
+        tx.close().unwrap();
+
+        let mut tx = db.begin_rwtx().unwrap();
+        while tx.sweep_expired(50).unwrap() > 0 {}
+        tx.commit().unwrap();
+        let tx = db.begin_tx();
+        assert_eq!(keys(&tx, true), keys(&tx, false));
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_ttl_delete_range() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let db = open("rultdb_test_ttl_delete_range.db", clock.clone(), 0);
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..100 {
+            tx.put_with_ttl(format!("{:04}", i).as_bytes(), b"v", Duration::from_secs(10)).unwrap();
+        }
+        tx.put(b"\xffz", b"v").unwrap();
+        assert_eq!(tx.delete_range(b"0010".as_slice()..b"0020".as_slice()).unwrap(), 10);
+        tx.put(b"0015", b"v").unwrap();
+        tx.commit().unwrap();
+
+        clock.advance(Duration::from_secs(10));
+        let tx = db.begin_tx();
+        assert_eq!(keys(&tx, false), vec![b"0015".to_vec(), b"\xffz".to_vec()]);
+        tx.close().unwrap();
+
+        // 条件写入覆盖的 key 不再继承原来的过期时间
+        let mut tx = db.begin_rwtx().unwrap();
+        tx.put_with_ttl(b"0030", b"v", Duration::from_secs(10)).unwrap();
+        tx.put_with_ttl(b"0031", b"v", Duration::from_secs(10)).unwrap();
+        tx.compare_and_swap(b"0030", Some(b"v"), Some(b"w")).unwrap();
+        assert_eq!(tx.replace(b"0031", b"w").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(tx.ttl(b"0030").unwrap(), None);
+        assert_eq!(tx.ttl(b"0031").unwrap(), None);
+        tx.commit().unwrap();
+        clock.advance(Duration::from_secs(10));
+
+        let mut tx = db.begin_rwtx().unwrap();
+        assert_eq!(tx.get(b"0030").unwrap(), Some(&b"w"[..]));
+        assert_eq!(tx.get(b"0031").unwrap(), Some(&b"w"[..]));
+        assert_eq!(tx.clear().unwrap(), 92);
+        tx.commit().unwrap();
+        let tx = db.begin_tx();
+        assert!(keys(&tx, true).is_empty());
+        tx.close().unwrap();
+    }
+
+    #[test]
+    fn test_ttl_sweeper() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let db = open("rultdb_test_ttl_sweeper.db", clock.clone(), 0);
+        let mut tx = db.begin_rwtx().unwrap();
+        for i in 0..1000 {
+            tx.put_with_ttl(format!("{:04}", i).as_bytes(), b"v", Duration::from_secs(10)).unwrap();
+        }
+        tx.put(b"keep", b"v").unwrap();
+        tx.commit().unwrap();
+
+        let sweeper = db.start_sweeper(Duration::from_millis(1), 100);
+        clock.advance(Duration::from_secs(10));
+        let start = std::time::Instant::now();
+        loop {
+            let tx = db.begin_tx();
+            let n = keys(&tx, true).len();
+            tx.close().unwrap();
+            if n == 1 {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "{} keys left", n);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(sweeper.error_count(), 0);
+        sweeper.stop();
+        let mut tx = db.begin_tx();
+        assert_eq!(tx.get(b"keep").unwrap(), Some(&b"v"[..]));
+        tx.close().unwrap();
+
+        // 只读数据库中无法开启写事务，线程记录错误之后继续运行
+        drop(db);
+        let path = std::env::temp_dir().join("rultdb_test_ttl_sweeper.db");
+        let db = DBInner::open(path.to_str().unwrap(), Options { read_only: true, ..Default::default() }).unwrap();
+        let sweeper = db.start_sweeper(Duration::from_millis(1), 100);
+        let start = std::time::Instant::now();
+        while sweeper.error_count() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(sweeper.last_error(), Some(Error::ErrDatabaseReadOnly.to_string()));
+        sweeper.stop();
+    }
+}
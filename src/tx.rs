@@ -1,6 +1,6 @@
-use std::{borrow::Borrow, cell::{RefCell, RefMut}, collections::HashMap, io::WriterPanicked, marker::PhantomData, sync::{Arc, Weak}};
+use std::{borrow::Borrow, cell::{Cell, RefCell, RefMut}, ops::RangeBounds, collections::{BTreeMap, HashMap, HashSet}, io::{Write, WriterPanicked}, marker::PhantomData, sync::{atomic::Ordering, Arc, Weak}};
 
-use crate::{config::PAGE_SIZE, cursor::Cursor, db::{WeakDB, DB}, error::Error, node::{Node, NodeInner, WeakNode}, page::{Meta, OwnedPage, Page, PgId}, DEFAULT_FILL_PERCENT, MAX_KEY_SIZE, MAX_VALUE_SIZE};
+use crate::{config::PAGE_SIZE, cursor::Cursor, db::{CommitInfo, WeakDB, DB, NO_IGNORED_META}, error::Error, feed::{self, Change}, freelist::FreeList, node::{Node, NodeInner, WeakNode}, comparator::KeyOrder, savepoint::SavepointState, page::{Meta, OwnedPage, Page, PageFlag, PgId, BRANCH_ELEMENT_SIZE, LEAF_ELEMENT_SIZE, PAGE_HEADER_SIZE}, is_system_key, DEFAULT_FILL_PERCENT, MAX_FILL_PERCENT, MAX_KEY_SIZE, MAX_VALUE_SIZE, MIN_FILL_PERCENT};
 
 
 use crate::error::Result;
@@ -30,7 +30,22 @@ pub struct TxInner {
     pub(crate) pages: RefCell<HashMap<PgId, OwnedPage>>,
     pub(crate) nodes: RefCell<HashMap<PgId, Node>>,
     pub(crate) root_node: RefCell<Option<Node>>,
-    pub(crate) fill_percent: f64,
+    pub(crate) fill_percent: Cell<f64>,
+    pub(crate) append: Cell<bool>,
+    // append 模式下缓存的最右侧路径（从根节点到最后一个叶子节点的页面 id）
+    pub(crate) last_leaf: RefCell<Vec<PgId>>,
+    // 数据库中是否有设置了 TTL 的 key，第一次使用时查找
+    pub(crate) has_ttl: Cell<Option<bool>>,
+    // 事务中修改过的 key 和它们修改之前的值，只在注册了 Options::on_commit 或者有订阅时记录
+    pub(crate) modified: RefCell<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+    // 事务开始时决定是否记录修改过的 key 以及它们原来的值
+    pub(crate) track_keys: bool,
+    pub(crate) track_values: bool,
+    pub(crate) on_commit: RefCell<Vec<Box<dyn FnOnce() + Send>>>,
+    pub(crate) on_rollback: RefCell<Vec<Box<dyn FnOnce() + Send>>>,
+    pub(crate) savepoints: RefCell<Vec<SavepointState>>,
+    pub(crate) next_savepoint: Cell<u64>,
+    pub(crate) order: KeyOrder,
 }
 
 
@@ -42,20 +57,39 @@ impl Tx {
     pub fn rollback(&mut self) -> Result<()> {
         let db = self.0.weak_db.0.upgrade().unwrap();
         if self.0.writable {
-            db.freelist.try_write().unwrap().rollback(self.id())?;
-            let free_page = db.page(db.state.try_read().unwrap().borrow().meta().freelist);
-            db.freelist.try_write().unwrap().reload(unsafe {&*free_page})?;
+            db.freelist.write().rollback(self.id())?;
+            let free_page = db.page(db.state().meta().freelist);
+            db.freelist.write().reload(unsafe {&*free_page})?;
         }
         self.close()?;
+        self.0.on_commit.take();
+        for f in self.0.on_rollback.take() {
+            f();
+        }
         Ok(())
 
     }
     pub fn commit(&mut self) -> Result<()> {
         if !self.0.writable {
-            return Ok(())
+            return self.close();
         }
         let db = self.db().unwrap();
 
+        let batch = db.0.ttl_sweep_batch;
+        if batch > 0 {
+            if let Err(e) = self.sweep_expired(batch) {
+                self.rollback()?;
+                return Err(e);
+            }
+        }
+        // 在 spill 之前读取修改之后的值
+        let changes = match self.changes() {
+            Ok(changes) => changes,
+            Err(e) => {
+                self.rollback()?;
+                return Err(e);
+            }
+        };
         self.rebalance(PAGE_SIZE as usize)?;
         if let Err(e) = self.spill() {
             self.rollback()?;
@@ -63,13 +97,12 @@ impl Tx {
         }
         //回收旧的freelist列表
         db.0.freelist
-            .try_write()
-            .unwrap()
+            .write()
             .free(self.0.meta.borrow().txid, unsafe {
                 &*db.0.page(self.0.meta.borrow().freelist)
             });
 
-        let size = db.0.freelist.try_read().unwrap().size();
+        let size = db.0.freelist.read().size();
         let mut p = match db.0.allocate(size /PAGE_SIZE as usize + 1) {
             Ok(_p) => _p,
             Err(e) => {
@@ -79,7 +112,7 @@ impl Tx {
         };
 
         let page = p.to_page_mut();
-        db.0.freelist.try_write().unwrap().write(page);
+        db.0.freelist.write().write(page);
 
         self.0.meta.borrow_mut().freelist = page.id;
         self.0.pages.borrow_mut().insert(page.id, p);
@@ -100,12 +133,74 @@ impl Tx {
             return Err(e);
         }
 
-        self.close();
+        // 提交已经持久化，之后才调用回调。在释放写锁之前通知，订阅者按 txid 的顺序收到事务
+        let info = CommitInfo { txid: self.id(), keys: self.0.modified.take().into_keys().collect() };
+        if let Some(f) = &db.0.on_commit {
+            f(&info);
+        }
+        if !changes.is_empty() {
+            feed::publish(&db.0.subscribers, self.id(), &changes);
+        }
+        db.0.watch.notify(info.txid, self.0.track_keys.then_some(info.keys));
+        self.close()?;
+        self.0.on_rollback.take();
+        for f in self.0.on_commit.take() {
+            f();
+        }
         Ok(())
     }
+
+    /// 注册一个在事务提交并写入 meta 页面之后调用的回调，事务回滚时不会调用
+    pub fn on_commit(&self, f: impl FnOnce() + Send + 'static) {
+        self.0.on_commit.borrow_mut().push(Box::new(f));
+    }
+
+    /// 注册一个在事务回滚（包括提交失败）之后调用的回调
+    pub fn on_rollback(&self, f: impl FnOnce() + Send + 'static) {
+        self.0.on_rollback.borrow_mut().push(Box::new(f));
+    }
+
+    // 是否需要记录修改过的 key
+    pub(crate) fn tracking(&self) -> bool {
+        self.0.track_keys
+    }
+
+    // 在修改 key 之前调用，第一次修改时记录 key 原来的值
+    pub(crate) fn record_modified(&self, key: &[u8]) -> Result<()> {
+        if self.tracking() && !is_system_key(key) && !self.0.modified.borrow().contains_key(key) {
+            let old = match self.0.track_values {
+                true => self.clone().get(key)?.map(|v| v.to_vec()),
+                false => None,
+            };
+            self.0.modified.borrow_mut().insert(key.to_vec(), old);
+        }
+        Ok(())
+    }
+
+    // 已经知道原来的值时使用，不需要再查找
+    pub(crate) fn record_removed(&self, key: &[u8], old: &[u8]) {
+        if self.tracking() && !is_system_key(key) {
+            self.0.modified.borrow_mut().entry(key.to_vec()).or_insert_with(|| Some(old.to_vec()));
+        }
+    }
+
+    // 修改过的 key 当前的值，没有订阅时返回空
+    fn changes(&self) -> Result<Vec<Change>> {
+        if !self.0.track_values {
+            return Ok(Vec::new());
+        }
+        let mut changes = Vec::new();
+        for (key, old) in self.0.modified.borrow().iter() {
+            let new = self.clone().get(key)?.map(|v| v.to_vec());
+            if *old != new {
+                changes.push(Change { key: key.clone(), old: old.clone(), new });
+            }
+        }
+        Ok(changes)
+    }
     pub fn close(&self) -> Result<()> {
+        self.db().unwrap().0.remove_tx(self.clone()); 
         if self.0.writable {
-            self.db().unwrap().0.remove_tx(self.clone()); 
             unsafe { self.0.weak_db.0.upgrade().unwrap().rw_lock.raw().unlock() };
 
         }else {
@@ -119,6 +214,14 @@ impl Tx {
 
 
     pub fn new(writable: bool, weak_db: WeakDB, meta: Meta) -> Self {
+        let order = weak_db.0.upgrade().map(|db| db.order.clone()).unwrap_or_default();
+        let (track_keys, track_values) = match weak_db.0.upgrade() {
+            Some(db) if writable => {
+                let values = !db.subscribers.lock().is_empty();
+                (values || db.on_commit.is_some() || db.watch.active(), values)
+            }
+            _ => (false, false),
+        };
         let tx = Arc::new(
             TxInner{
                 writable,
@@ -127,8 +230,19 @@ impl Tx {
                 pages: Default::default(),
                 nodes: RefCell::new(HashMap::new()),
                 root_node: Default::default(),
-                fill_percent: DEFAULT_FILL_PERCENT,
-                
+                fill_percent: Cell::new(DEFAULT_FILL_PERCENT),
+                append: Cell::new(false),
+                last_leaf: Default::default(),
+                has_ttl: Default::default(),
+                modified: Default::default(),
+                track_keys,
+                track_values,
+                on_commit: Default::default(),
+                on_rollback: Default::default(),
+                savepoints: Default::default(),
+                next_savepoint: Default::default(),
+                order,
+
             }
         );
         Tx(tx)
@@ -171,6 +285,13 @@ impl Tx {
         self.db().unwrap().0.write_at(&buf, id * PAGE_SIZE as u64) ?;
         let ow = OwnedPage::from_vec(buf);
         self.db().unwrap().0.sync()?;
+        // 打开时被放弃的 meta 页面已经被覆盖，可以重新使用
+        let _ = self.db().unwrap().0.state().ignored_meta.compare_exchange(
+            id,
+            NO_IGNORED_META,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
         Ok(())
     }
 }
@@ -180,53 +301,270 @@ impl Tx {
     pub fn root_id(&self) -> PgId {
         self.0.meta.borrow().root
     }
-    fn cursor(&mut self ) -> Cursor{
+
+    /// 节点分裂时每个页面的填充比例，范围 [0.1, 1.0]，默认 0.5。
+    /// key 顺序写入时使用较大的值可以减少半空的页面
+    pub fn set_fill_percent(&mut self, fill_percent: f64) {
+        self.0.fill_percent.set(fill_percent.clamp(MIN_FILL_PERCENT, MAX_FILL_PERCENT));
+    }
+
+    pub fn fill_percent(&self) -> f64 {
+        self.0.fill_percent.get()
+    }
+
+    /// append 模式适用于 key 单调递增的写入：最右侧的节点分裂时填满左边的页面，
+    /// 剩余的 key 放在新的右侧页面，并且 seek 直接定位到最后一个叶子节点
+    pub fn set_append_mode(&mut self, append: bool) {
+        self.0.append.set(append);
+        self.0.last_leaf.borrow_mut().clear();
+    }
+
+    pub fn append_mode(&self) -> bool {
+        self.0.append.get()
+    }
+
+    /// 数据库是否使用计数 B+ 树
+    pub fn counted(&self) -> bool {
+        self.0.meta.borrow().counted()
+    }
+
+    /// 按数据库的比较器比较两个 key
+    pub fn compare_keys(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        self.0.order.cmp(a, b)
+    }
+
+    pub fn meta(&self) -> Meta {
+        self.0.meta.borrow().clone()
+    }
+
+    /// 当前的序列号
+    pub fn sequence(&self) -> u64 {
+        self.0.meta.borrow().sequence
+    }
+
+    /// 设置序列号，事务回滚时恢复
+    pub fn set_sequence(&mut self, sequence: u64) -> Result<()> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        self.0.meta.borrow_mut().sequence = sequence;
+        Ok(())
+    }
+
+    /// 序列号加一并返回新的值
+    pub fn next_sequence(&mut self) -> Result<u64> {
+        if !self.writable() {
+            return Err(Error::ErrTxNotWritable);
+        }
+        let mut meta = self.0.meta.borrow_mut();
+        meta.sequence += 1;
+        Ok(meta.sequence)
+    }
+
+    pub fn writable(&self) -> bool {
+        self.0.writable
+    }
+
+    pub fn cursor(&self) -> Cursor{
         Cursor::new(self.clone())
     }
 
+    /// 不跳过内部使用的系统 key 和已经过期的 key 的游标，用于复制整个数据库
+    pub fn raw_cursor(&self) -> Cursor {
+        Cursor::new_raw(self.clone())
+    }
+
     pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        if key.len() == 0 {
-            return Err(Error::ErrKeyRequired);
-        } else if key.len() > MAX_KEY_SIZE {
-            return Err(Error::ErrKeyTooLarge);
-        } else if value.len() > MAX_VALUE_SIZE {
-            return Err(Error::ErrValueTooLarge);
-        }
+        check_key_value(key, value)?;
+        // 违反唯一索引时不修改 TTL
+        self.put_raw(key, value)?;
+        self.clear_ttl(key)
+    }
 
+    // 不检查 key，也不修改 key 的 TTL
+    pub(crate) fn put_raw(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.update_indexes(key, Some(value))?;
+        self.record_modified(key)?;
         let mut c = self.cursor();
-        let item = c.seek(key)?;
-
+        c.seek_item(key)?;
         c.node()?.put(key, key, value, 0);
         Ok(())
     }
 
+    pub(crate) fn delete_raw(&mut self, key: &[u8]) -> Result<()> {
+        self.update_indexes(key, None)?;
+        self.record_modified(key)?;
+        let mut c = self.cursor();
+        c.seek_item(key)?;
+        c.node()?.del(key);
+        Ok(())
+    }
 
-    pub fn get(&mut self, key: &[u8]) -> Option<&[u8]> {
+    /// 使用 Options::merge_operator 把 operand 合并到 key 的值中，key 不存在时写入初始值。
+    /// 只查找一次，直接修改叶子节点中的值
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let op = self.db().unwrap().0.merge_operator.clone().ok_or(Error::ErrNoMergeOperator)?;
+        check_key_value(key, operand)?;
+        self.purge_expired(key)?;
+        if self.indexed(key) {
+            // 先计算合并后的值，通过 put_raw 维护索引
+            let value = match self.get(key)? {
+                Some(v) => {
+                    let mut v = v.to_vec();
+                    op.merge(key, &mut v, operand)?;
+                    v
+                }
+                None => op.initial(key, operand)?,
+            };
+            return self.put_raw(key, &value);
+        }
+        self.record_modified(key)?;
         let mut c = self.cursor();
-        let item  = c.seek(key).unwrap();
-        if item.0.is_none() {
-            return None;
+        c.seek_item(key)?;
+        c.node()?.merge(key, operand, op.as_ref())
+    }
+
+    /// key 当前的值等于 expected 时写入 new，new 为 None 时删除 key。
+    /// expected 为 None 表示 key 不存在。不相等时返回 ErrCompareAndSwap 和当前的值
+    pub fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<()> {
+        check_key_value(key, new.unwrap_or_default())?;
+        let (c, current) = self.seek_exact(key)?;
+        if current.as_deref() != expected {
+            return Err(Error::ErrCompareAndSwap { current });
         }
-        //return item.0; 
+        if new.is_none() && current.is_none() {
+            return Ok(());
+        }
+        self.write_found(c, key, new)
+    }
 
-        if item.0.unwrap() ==  key {
-            return item.1
-        }else {
-            return None;
+    /// key 不存在时写入，返回是否写入
+    pub fn put_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        check_key_value(key, value)?;
+        let (c, current) = self.seek_exact(key)?;
+        if current.is_some() {
+            return Ok(false);
         }
+        self.write_found(c, key, Some(value))?;
+        Ok(true)
+    }
 
+    /// key 存在时写入新的值，返回旧的值。key 不存在时不写入，返回 None
+    pub fn replace(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        check_key_value(key, value)?;
+        let (c, current) = self.seek_exact(key)?;
+        if current.is_some() {
+            self.write_found(c, key, Some(value))?;
+        }
+        Ok(current)
     }
-    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+
+    // 定位到 key 所在的叶子节点，返回游标和 key 当前的值，之后可以直接通过游标修改节点。
+    // 不能使用 seek：key 大于叶子节点的最后一个 key 时 seek 会移动到下一个叶子节点，
+    // 使节点中的 key 小于父节点中记录的 key。已经过期的 key 先被删除
+    fn seek_exact(&mut self, key: &[u8]) -> Result<(Cursor, Option<Vec<u8>>)> {
+        self.purge_expired(key)?;
         let mut c = self.cursor();
-        c.seek(key)?;
-        c.node()?.del(key);
+        let item = c.seek_item(key)?;
+        let current = if item.key() == Some(key) { item.value().map(|v| v.to_vec()) } else { None };
+        Ok((c, current))
+    }
+
+    // 通过 seek_exact 返回的游标写入，new 为 None 时删除。key 有索引或者数据库中有 TTL 时
+    // 和 put/delete 走同样的路径，维护索引并清除 key 的过期时间
+    fn write_found(&mut self, mut c: Cursor, key: &[u8], new: Option<&[u8]>) -> Result<()> {
+        if self.indexed(key) || self.has_ttl()? {
+            return match new {
+                Some(value) => self.put(key, value),
+                None => self.delete(key),
+            };
+        }
+        self.record_modified(key)?;
+        match new {
+            Some(value) => c.node()?.put(key, key, value, 0),
+            None => c.node()?.del(key),
+        }
         Ok(())
     }
+
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<&[u8]>> {
+        let mut c = self.cursor();
+        let item = c.seek_item(key)?;
+        if item.0 == Some(key) && !self.is_hidden(key)? {
+            return Ok(item.1);
+        }
+        Ok(None)
+    }
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        check_key_value(key, &[])?;
+        self.clear_ttl(key)?;
+        self.delete_raw(key)
+    }
+    /// 删除 range 内的所有 key，返回删除的 key 数量。完全落在 range 内的子树不会被读取为 Node，
+    /// 只是把它们的页面加入 freelist
+    pub fn delete_range<'a, R: RangeBounds<&'a [u8]>>(&mut self, range: R) -> Result<u64> {
+        // 系统 key 不会被删除
+        let pieces = self.0.order.user_ranges(&range);
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        for piece in pieces.iter() {
+            self.clear_index_range(piece)?;
+        }
+        let root = self.node(self.root_id(), None);
+        let mut count = 0;
+        for piece in pieces.iter() {
+            count += root.delete_range(self, piece);
+        }
+        // 根节点的所有子节点都被删除时变为空的叶子节点
+        if root.node().inodes.is_empty() {
+            root.node_mut().is_leaf = true;
+        }
+        self.0.last_leaf.borrow_mut().clear();
+        self.clear_ttl_range(&range)?;
+        Ok(count)
+    }
+
+    /// 删除所有 key
+    pub fn clear(&mut self) -> Result<u64> {
+        self.delete_range(..)
+    }
+
+    // 不读取为 Node，直接释放以 pgid 为根的子树的所有页面，返回其中 key 的数量
+    pub(crate) fn free_pages(&self, pgid: PgId) -> u64 {
+        let db = self.db().unwrap();
+        let mut freelist = db.0.freelist.write();
+        let mut count = 0;
+        self.for_each_page(pgid, 0, &mut |p, _| {
+            if p.flags.contains(PageFlag::LeafPage) {
+                count += p.count as u64;
+                if self.tracking() {
+                    p.leaf_page_elements().iter().for_each(|e| self.record_removed(e.key(), e.value()));
+                }
+            }
+            freelist.free(self.id(), p);
+        });
+        count
+    }
+
     pub(crate) fn page_node(&self, id: PgId) -> Result<PageNode> {
         if let Some(node) = self.0.nodes.borrow().get(&id) {
             return Ok(PageNode::Node(node.clone()));
         }
-        let page = self.db().unwrap().0.page(id);
+        let db = self.db().unwrap();
+        if !db.0.verify_checksums {
+            return Ok(PageNode::Page(db.0.page(id)));
+        }
+        // 校验失败时不能继续读取页面内容
+        let pgid = self.0.meta.borrow().pgid;
+        if id >= pgid {
+            return Err(Error::ErrCorrupted { pgid: id });
+        }
+        let page = db.0.page(id);
+        let p = unsafe { &*page };
+        if id + p.overflow as PgId >= pgid || !p.elements_in_bounds() {
+            return Err(Error::ErrCorrupted { pgid: id });
+        }
+        p.verify_checksum()?;
         Ok(PageNode::Page(page))
     }
 
@@ -236,12 +574,12 @@ impl Tx {
         }
 
         let mut n = if let Some(p) = parent {
-            let n = NodeInner::new().parent(p.clone()).build();
+            let n = NodeInner::new().parent(p.clone()).order(self.0.order.clone()).counted(self.counted()).build();
             let parent_node =  p.upgrade().unwrap();
             parent_node.node_mut().children.push(n.clone());
             n
         } else {
-            let n = NodeInner::new().build();
+            let n = NodeInner::new().order(self.0.order.clone()).counted(self.counted()).build();
             self.0.root_node.replace(Some(n.clone()));
             n
         };
@@ -256,9 +594,13 @@ impl Tx {
     }
 
     pub(crate) fn rebalance(&mut self, page_size: usize) -> Result<()> {
-        let nodes = self.0.nodes.clone();
-        for n in nodes.borrow_mut().values_mut() {
-            n.rebalance(page_size,self)?;
+        let nodes: Vec<Node> = self.0.nodes.borrow().values().cloned().collect();
+        for mut n in nodes {
+            // 跳过已经被合并到其它节点中的节点
+            let live = self.0.nodes.borrow().get(&n.node().pgid).is_some_and(|m| Arc::ptr_eq(&m.0, &n.0));
+            if live {
+                n.rebalance(page_size, self)?;
+            }
         }
         Ok(())
     }
@@ -278,6 +620,199 @@ impl Tx {
     } 
 }
 
+/// 遍历 B+ 树页面得到的统计信息
+#[derive(Debug, Default, Clone)]
+pub struct TreeStats {
+    pub branch_page_n: usize,
+    pub branch_overflow_n: usize,
+    pub leaf_page_n: usize,
+    pub leaf_overflow_n: usize,
+    pub key_n: usize,
+    pub depth: usize,
+    pub branch_alloc: usize,
+    pub branch_inuse: usize,
+    pub leaf_alloc: usize,
+    pub leaf_inuse: usize,
+}
+
+impl Tx {
+    /// 从 pgid 开始深度优先遍历已提交的页面，不包含写事务中还未 spill 的修改
+    pub(crate) fn for_each_page<F: FnMut(&Page, usize)>(&self, pgid: PgId, depth: usize, f: &mut F) {
+        let p = unsafe { &*self.db().unwrap().0.page(pgid) };
+        f(p, depth);
+        if p.flags.contains(PageFlag::BranchPage) {
+            for elem in p.branch_page_elements() {
+                self.for_each_page(elem.value, depth + 1, f);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        let mut s = TreeStats::default();
+        self.for_each_page(self.root_id(), 1, &mut |p, depth| {
+            let alloc = (p.overflow as usize + 1) * PAGE_SIZE;
+            if p.flags.contains(PageFlag::LeafPage) {
+                s.leaf_page_n += 1;
+                s.leaf_overflow_n += p.overflow as usize;
+                s.leaf_alloc += alloc;
+                s.key_n += p.count as usize;
+                s.leaf_inuse += PAGE_HEADER_SIZE
+                    + p.leaf_page_elements()
+                        .iter()
+                        .map(|e| LEAF_ELEMENT_SIZE + (e.ksize + e.vsize) as usize)
+                        .sum::<usize>();
+            } else if p.flags.contains(PageFlag::BranchPage) {
+                s.branch_page_n += 1;
+                s.branch_overflow_n += p.overflow as usize;
+                s.branch_alloc += alloc;
+                s.branch_inuse += PAGE_HEADER_SIZE
+                    + p.branch_page_elements()
+                        .iter()
+                        .map(|e| BRANCH_ELEMENT_SIZE + e.ksize as usize)
+                        .sum::<usize>();
+            }
+            s.depth = s.depth.max(depth);
+        });
+        s
+    }
+
+    /// 检查页面的一致性：每个页面要么可达、要么在 freelist 中，可达页面不能被重复引用，
+    /// 页面内的 key 有序且落在父节点给出的范围内。返回发现的所有问题
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let meta = self.meta();
+        let db = self.db().unwrap();
+
+        let mut reachable: HashSet<PgId> = HashSet::new();
+        reachable.insert(0);
+        reachable.insert(1);
+        let mut freed: HashSet<PgId> = HashSet::new();
+        if meta.freelist < 2 || meta.freelist >= meta.pgid {
+            errors.push(format!("freelist page {}: out of bounds: {}", meta.freelist, meta.pgid));
+        } else {
+            let p = unsafe { &*db.0.page(meta.freelist) };
+            if !p.flags.contains(PageFlag::FreeListPage) {
+                errors.push(format!("page {}: invalid freelist page type: {:#x}", meta.freelist, p.flags.bits()));
+            } else if meta.freelist + p.overflow as PgId >= meta.pgid {
+                errors.push(format!("page {}: freelist overflow out of bounds: {}", meta.freelist, p.overflow));
+            } else {
+                for id in meta.freelist..=meta.freelist + p.overflow as PgId {
+                    reachable.insert(id);
+                }
+                if p.verify_checksum().is_err() {
+                    errors.push(format!("page {}: checksum mismatch", meta.freelist));
+                }
+                let mut freelist = FreeList::default();
+                freelist.read(p);
+                freed.extend(freelist.ids);
+            }
+        }
+
+        self.check_page(meta.root, None, None, &meta, &freed, &mut reachable, &mut errors);
+
+        for id in 0..meta.pgid {
+            if !reachable.contains(&id) && !freed.contains(&id) {
+                errors.push(format!("page {}: unreachable unfreed", id));
+            }
+        }
+        errors
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_page(
+        &self,
+        pgid: PgId,
+        min: Option<&[u8]>,
+        max: Option<&[u8]>,
+        meta: &Meta,
+        freed: &HashSet<PgId>,
+        reachable: &mut HashSet<PgId>,
+        errors: &mut Vec<String>,
+    ) -> u64 {
+        if pgid < 2 || pgid >= meta.pgid {
+            errors.push(format!("page {}: out of bounds: {}", pgid, meta.pgid));
+            return 0;
+        }
+        let p = unsafe { &*self.db().unwrap().0.page(pgid) };
+        if p.id != pgid {
+            errors.push(format!("page {}: invalid page id {}", pgid, p.id));
+        }
+        if pgid + p.overflow as PgId >= meta.pgid {
+            errors.push(format!("page {}: overflow out of bounds: {}", pgid, p.overflow));
+            return 0;
+        }
+        if p.verify_checksum().is_err() {
+            errors.push(format!("page {}: checksum mismatch", pgid));
+        }
+        for id in pgid..=pgid + p.overflow as PgId {
+            if !reachable.insert(id) {
+                errors.push(format!("page {}: multiple references", id));
+            }
+            if freed.contains(&id) {
+                errors.push(format!("page {}: reachable freed", id));
+            }
+        }
+        if !p.flags.contains(PageFlag::LeafPage) && !p.flags.contains(PageFlag::BranchPage) {
+            errors.push(format!("page {}: invalid type: {:#x}", pgid, p.flags.bits()));
+            return 0;
+        }
+        if !p.elements_in_bounds() {
+            errors.push(format!("page {}: elements out of page bounds", pgid));
+            return 0;
+        }
+
+        let keys: Vec<&[u8]> = if p.flags.contains(PageFlag::LeafPage) {
+            p.leaf_page_elements().iter().map(|e| e.key()).collect()
+        } else {
+            p.branch_page_elements().iter().map(|e| e.key()).collect()
+        };
+        for (i, key) in keys.iter().enumerate() {
+            if i > 0 && self.0.order.cmp(keys[i - 1], key).is_ge() {
+                errors.push(format!("page {}: keys out of order at index {}", pgid, i));
+            }
+            if min.is_some_and(|m| self.0.order.cmp(key, m).is_lt()) || max.is_some_and(|m| self.0.order.cmp(key, m).is_ge()) {
+                errors.push(format!("page {}: key at index {} outside of parent range", pgid, i));
+            }
+        }
+
+        if p.flags.contains(PageFlag::LeafPage) {
+            return p.count as u64;
+        }
+        if p.count == 0 {
+            errors.push(format!("page {}: empty branch page", pgid));
+        }
+        let mut total = 0;
+        for (i, elem) in p.branch_page_elements().iter().enumerate() {
+            let upper = keys.get(i + 1).copied().or(max);
+            let count = self.check_page(elem.value, Some(keys[i]), upper, meta, freed, reachable, errors);
+            // 计数 B+ 树中记录的子树 key 数量
+            if meta.counted() && p.branch_count(i) != count {
+                errors.push(format!("page {}: key count {} at index {} does not match {}", pgid, p.branch_count(i), i, count));
+            }
+            total += count;
+        }
+        total
+    }
+
+    /// 将当前事务看到的数据库快照写入 w，写出的内容是一个可以直接打开的数据库文件
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<u64> {
+        let mut meta = self.meta();
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for id in 0..2 {
+            let p = Page::page_in_buffer_mut(&mut buf, 0);
+            meta.write(p);
+            p.id = id;
+            w.write_all(&buf).map_err(|e| ("can't write meta page", e))?;
+        }
+
+        let db = self.db().unwrap();
+        let mmap = db.0.state().mmap.as_ref().unwrap();
+        let data = &mmap[2 * PAGE_SIZE..meta.pgid as usize * PAGE_SIZE];
+        w.write_all(data).map_err(|e| ("can't write data pages", e))?;
+        Ok((2 * PAGE_SIZE + data.len()) as u64)
+    }
+}
+
 unsafe impl Send for Tx {
 }
 unsafe impl Sync for Tx {
@@ -292,4 +827,16 @@ impl From<Node> for PageNode {
     fn from(n: Node) -> Self {
         PageNode::Node(n)
     }
-}
\ No newline at end of file
+}
+fn check_key_value(key: &[u8], value: &[u8]) -> Result<()> {
+    if key.is_empty() {
+        return Err(Error::ErrKeyRequired);
+    } else if key.len() > MAX_KEY_SIZE {
+        return Err(Error::ErrKeyTooLarge);
+    } else if value.len() > MAX_VALUE_SIZE {
+        return Err(Error::ErrValueTooLarge);
+    } else if is_system_key(key) {
+        return Err(Error::ErrReservedKey);
+    }
+    Ok(())
+}